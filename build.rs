@@ -1,7 +1,10 @@
 use anyhow::*;
 use glob::glob;
 use std::fs::{read_to_string, write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+// same lookup as `shader_util::compile_to_module`
+const INCLUDE_DIRS: [&str; 1] = ["./res/shaders"];
 
 struct ShaderData {
     src: String,
@@ -52,6 +55,25 @@ fn main() -> Result<()> {
         .collect::<Result<Vec<_>>>()?;
 
     let mut compiler = shaderc::Compiler::new().context("Unable to create shader compiler")?;
+    let mut compile_options =
+        shaderc::CompileOptions::new().context("Unable to create compile options")?;
+    compile_options.set_include_callback(|requested, include_type, requesting, _depth| {
+        let dirs: Vec<&Path> = match include_type {
+            shaderc::IncludeType::Relative => Path::new(requesting).parent().into_iter().collect(),
+            shaderc::IncludeType::Standard => INCLUDE_DIRS.iter().map(Path::new).collect(),
+        };
+        let resolved = dirs
+            .into_iter()
+            .map(|dir| dir.join(requested))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| format!("Can't find include file '{}'", requested))?;
+        let content = read_to_string(&resolved).map_err(|err| err.to_string())?;
+        Ok(shaderc::ResolvedInclude {
+            resolved_name: resolved.to_str().unwrap().to_string(),
+            content,
+        })
+    });
+    println!("cargo:rerun-if-changed=./res/shaders/engine");
 
     for shader in shaders {
         println!(
@@ -64,7 +86,7 @@ fn main() -> Result<()> {
             shader.kind,
             &shader.src_path.to_str().unwrap(),
             "main",
-            None,
+            Some(&compile_options),
         )?;
         write(shader.spv_path, compiled.as_binary_u8())?;
     }
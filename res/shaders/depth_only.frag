@@ -0,0 +1,29 @@
+#version 450
+
+// Writes no color, alpha tested materials discard as in pbr.frag.
+#ifdef ALPHA_TEST
+layout (location = 1) in vec2 v_texcoords;
+
+layout (set = 0, binding = 0) uniform MaterialUniform {
+    vec4 base_color;
+    vec3 emissive_factor;
+    float metallic_factor;
+    float roughness_factor;
+    float alpha_cutoff;
+};
+
+layout (set = 0, binding = 1) uniform texture2D base_color_tex;
+layout (set = 0, binding = 2) uniform sampler base_color_tex_sampler;
+#ifndef base_color_tex_LOD_BIAS
+#define base_color_tex_LOD_BIAS 0.0
+#endif
+#endif
+
+void main() {
+#ifdef ALPHA_TEST
+    float alpha = base_color.a * texture(sampler2D(base_color_tex, base_color_tex_sampler), v_texcoords, base_color_tex_LOD_BIAS).a;
+    if (alpha < alpha_cutoff) {
+        discard;
+    }
+#endif
+}
@@ -0,0 +1,27 @@
+#version 450
+
+// Only the position, and the texture coordinates alpha tested materials need. gl_Position is
+// computed as in pbr.vert and invariant in both, so the passes after it can test depth for equality.
+layout (location = 0) in vec3 a_position;
+#if defined(ALPHA_TEST) && defined(VERTEX_TEXCOORDS)
+layout (location = 1) in vec2 a_texcoords;
+#elif defined(ALPHA_TEST)
+const vec2 a_texcoords = vec2(0.0);
+#endif
+
+#ifdef ALPHA_TEST
+layout (location = 1) out vec2 v_texcoords;
+#endif
+
+invariant gl_Position;
+
+#include <engine/object.glsl>
+#include <engine/camera.glsl>
+
+void main() {
+#ifdef ALPHA_TEST
+    v_texcoords = a_texcoords;
+#endif
+    vec3 position = (matrix_model * vec4(a_position, 1.0)).xyz;
+    gl_Position = matrix_proj * matrix_view * vec4(position, 1.0);
+}
@@ -0,0 +1,15 @@
+#ifndef ENGINE_COMMON_GLSL
+#define ENGINE_COMMON_GLSL
+
+const float PI = 3.14159265359;
+
+float pow2(float x) {
+    return x * x;
+}
+
+float pow5(float x) {
+    float x2 = x * x;
+    return x * x2 * x2;
+}
+
+#endif
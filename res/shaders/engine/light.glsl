@@ -0,0 +1,42 @@
+#ifndef ENGINE_LIGHT_GLSL
+#define ENGINE_LIGHT_GLSL
+
+#include "light_types.glsl"
+
+// the light of this pass, light_position.w is 0 for directional lights, light_position.xyz is
+// then the direction
+layout (set = 2, binding = 0) uniform LightUniform {
+    vec4 light_position;
+    vec4 light_color;
+    vec4 light_direction;
+    vec4 light_spot;
+};
+
+// Every light at once, for sub shaders lighting in a single pass.
+layout (set = 2, binding = 1) uniform LightArrayUniform {
+    uint light_count;
+    Light lights[MAX_LIGHTS];
+};
+
+// The lights of each cluster, for clustered sub shaders, see FragmentCluster.
+layout (set = 2, binding = 2) uniform ClusterParamsUniform {
+    ClusterParams cluster_params;
+};
+
+layout (std430, set = 2, binding = 3) readonly buffer ClusterLightsBuffer {
+    Light cluster_lights[];
+};
+
+layout (std430, set = 2, binding = 4) readonly buffer ClusterLightCountsBuffer {
+    uint cluster_light_counts[];
+};
+
+layout (std430, set = 2, binding = 5) readonly buffer ClusterLightIndicesBuffer {
+    uint cluster_light_indices[];
+};
+
+Light PassLight() {
+    return Light(light_position, light_color, light_direction, light_spot);
+}
+
+#endif
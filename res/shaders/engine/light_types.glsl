@@ -0,0 +1,68 @@
+#ifndef ENGINE_LIGHT_TYPES_GLSL
+#define ENGINE_LIGHT_TYPES_GLSL
+
+// Light types and clustering, without bindings so that compute shaders can use them too.
+#include "common.glsl"
+
+// MAX_LIGHTS in light.rs
+#define MAX_LIGHTS 16
+// in light_clusters.rs
+#define CLUSTER_GRID_X 16
+#define CLUSTER_GRID_Y 9
+#define CLUSTER_GRID_Z 24
+#define MAX_CLUSTERED_LIGHTS 1024
+#define MAX_LIGHTS_PER_CLUSTER 128
+
+// position.w is 0 for directional lights, position.xyz is then the direction
+// direction.w is the range of point and spot lights, spot.xy scale and offset the spot cone
+struct Light {
+    vec4 position;
+    vec4 color;
+    vec4 direction;
+    vec4 spot;
+};
+
+struct ClusterParams {
+    mat4 view;
+    mat4 proj_inv;
+    vec2 screen_size;
+    float znear;
+    float zfar;
+    uint light_count;
+    uint heatmap;
+};
+
+// How much of the light reaches `position`, `light_dir` is set to the direction towards it.
+float LightAttenuation(Light light, vec3 position, out vec3 light_dir) {
+    if (light.position.w == 0.0) {
+        light_dir = light.position.xyz;
+        return 1.0;
+    }
+    vec3 to_light = light.position.xyz - position;
+    float dist_sqr = max(dot(to_light, to_light), 0.0001);
+    light_dir = to_light * inversesqrt(dist_sqr);
+    float range_sqr = light.direction.w * light.direction.w;
+    float window = clamp(1.0 - pow2(dist_sqr / range_sqr), 0.0, 1.0);
+    float cone = clamp(dot(light.direction.xyz, -light_dir) * light.spot.x + light.spot.y, 0.0, 1.0);
+    return window * window * cone * cone / dist_sqr;
+}
+
+// Exponential depth slices, the view depth where a slice begins.
+float ClusterSliceDepth(ClusterParams params, uint slice) {
+    return params.znear * pow(params.zfar / params.znear, float(slice) / float(CLUSTER_GRID_Z));
+}
+
+uint ClusterIndex(uvec3 cluster) {
+    return cluster.x + (cluster.y + cluster.z * uint(CLUSTER_GRID_Y)) * uint(CLUSTER_GRID_X);
+}
+
+// The cluster of a fragment at `frag_coord` (framebuffer pixels), `view_depth` in front of the camera.
+uint FragmentCluster(ClusterParams params, vec2 frag_coord, float view_depth) {
+    uvec2 tile = uvec2(frag_coord / params.screen_size * vec2(CLUSTER_GRID_X, CLUSTER_GRID_Y));
+    float slice = log(max(view_depth, params.znear) / params.znear) / log(params.zfar / params.znear);
+    uvec3 cluster = min(uvec3(tile, uint(slice * float(CLUSTER_GRID_Z))),
+        uvec3(CLUSTER_GRID_X - 1, CLUSTER_GRID_Y - 1, CLUSTER_GRID_Z - 1));
+    return ClusterIndex(cluster);
+}
+
+#endif
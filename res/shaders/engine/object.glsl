@@ -0,0 +1,9 @@
+#ifndef ENGINE_OBJECT_GLSL
+#define ENGINE_OBJECT_GLSL
+
+layout (set = 1, binding = 0) uniform ObjectUniform {
+    mat4 matrix_model;
+    mat4 matrix_model_iv;
+};
+
+#endif
@@ -0,0 +1,13 @@
+#ifndef ENGINE_PBR_COMMON_GLSL
+#define ENGINE_PBR_COMMON_GLSL
+
+// Everything a forward PBR shader needs besides its material (set 0).
+#include "common.glsl"
+#include "object.glsl"
+#include "light.glsl"
+#include "camera.glsl"
+#include "scene.glsl"
+#include "brdf.glsl"
+#include "pbr_lighting.glsl"
+
+#endif
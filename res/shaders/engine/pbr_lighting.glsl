@@ -0,0 +1,107 @@
+#ifndef ENGINE_PBR_LIGHTING_GLSL
+#define ENGINE_PBR_LIGHTING_GLSL
+
+// The lighting of pbr.frag, shared with the deferred lighting pass. Needs light, camera, scene and
+// brdf.glsl.
+
+struct Surface {
+    vec3 position;
+    vec3 normal_dir;
+    vec3 view_dir;
+    vec3 albedo;
+    float metallic;
+    // perceptual, squared for the BRDF
+    float p_roughness;
+    float ambient_occlusion;
+};
+
+vec3 DirectLighting(Light light, Surface surface) {
+    vec3 fresnel_r0 = mix(DIELECTRIC_R0, surface.albedo, surface.metallic);
+    float roughness = surface.p_roughness * surface.p_roughness;
+    float roughness_sqr = roughness * roughness;
+
+    // light, half
+    vec3 light_dir;
+    float attenuation = LightAttenuation(light, surface.position, light_dir);
+    vec3 half_dir = normalize(surface.view_dir + light_dir);
+
+    // dots
+    float ndoth = max(dot(surface.normal_dir, half_dir), 0.0);
+    float ndotv = max(dot(surface.normal_dir, surface.view_dir), 0.0);
+    float ndotl = max(dot(surface.normal_dir, light_dir), 0.0);
+    float hdotv = max(dot(half_dir, surface.view_dir), 0.0);
+
+    // diffuse
+    vec3 diffuse = surface.albedo;
+//    vec3 diffuse = surface.albedo / PI;
+
+    // NDF
+    float ndf = NdfGgx(ndoth, roughness_sqr);
+
+    // Visible
+    float visible = SeparableVisible(ndotv, ndotl, roughness_sqr);
+
+    // Fresnel
+    vec3 fresnel = SchlickFresnel(fresnel_r0, hdotv);
+    vec3 fresnel_dielectric = SchlickFresnel(DIELECTRIC_R0, hdotv);
+    vec3 k_specualr = fresnel;
+    vec3 k_diffsue = (vec3(1.0) - fresnel_dielectric) * (1.0 - surface.metallic);
+
+    return (diffuse * k_diffsue + ndf * visible * k_specualr) * light.color.xyz * attenuation * ndotl;
+}
+
+// With the first MAX_LIGHTS lights.
+vec3 LightArrayLighting(Surface surface) {
+    vec3 lighting = vec3(0.0);
+    for (uint i = 0u; i < min(light_count, uint(MAX_LIGHTS)); i++) {
+        lighting += DirectLighting(lights[i], surface);
+    }
+    return lighting;
+}
+
+uint SurfaceCluster(Surface surface, vec2 frag_coord) {
+    float view_depth = -(matrix_view * vec4(surface.position, 1.0)).z;
+    return FragmentCluster(cluster_params, frag_coord, view_depth);
+}
+
+uint ClusterLightCount(uint cluster) {
+    return min(cluster_light_counts[cluster], uint(MAX_LIGHTS_PER_CLUSTER));
+}
+
+// With the lights of the cluster.
+vec3 ClusteredLighting(Surface surface, uint cluster) {
+    vec3 lighting = vec3(0.0);
+    for (uint i = 0u; i < ClusterLightCount(cluster); i++) {
+        uint light_index = cluster_light_indices[cluster * uint(MAX_LIGHTS_PER_CLUSTER) + i];
+        lighting += DirectLighting(cluster_lights[light_index], surface);
+    }
+    return lighting;
+}
+
+// From the skybox, by ndotv as there is no single half vector.
+vec3 IndirectLighting(Surface surface) {
+    vec3 fresnel_r0 = mix(DIELECTRIC_R0, surface.albedo, surface.metallic);
+    vec3 reflect_dir = reflect(-surface.view_dir, surface.normal_dir);
+    float ndotv = max(dot(surface.normal_dir, surface.view_dir), 0.0);
+
+    vec3 prefiltered_color = textureLod(samplerCube(skybox_prefiltered_tex, skybox_prefiltered_tex_sampler),
+        reflect_dir, surface.p_roughness * 6).rgb;
+    vec2 brdf = texture(sampler2D(brdf_lut_tex, brdf_lut_tex_sampler), vec2(ndotv, surface.p_roughness)).rg;
+    vec3 indirect_specular = prefiltered_color * (fresnel_r0 * brdf.x + brdf.y);
+    vec3 indirect_diffuse = surface.albedo
+        * texture(samplerCube(skybox_irradiance_tex, skybox_irradiance_tex_sampler), surface.normal_dir).rgb;
+    vec3 k_diffsue = (vec3(1.0) - SchlickFresnel(DIELECTRIC_R0, ndotv)) * (1.0 - surface.metallic);
+    return (indirect_diffuse * k_diffsue + indirect_specular) * surface.ambient_occlusion;
+}
+
+// Blue for no light to red for 32 or more, over `color` when the cluster heatmap is on.
+vec3 ClusterHeatmap(vec3 color, uint cluster) {
+    if (cluster_params.heatmap == 0u) {
+        return color;
+    }
+    float heat = clamp(float(ClusterLightCount(cluster)) / 32.0, 0.0, 1.0);
+    vec3 heat_color = clamp(vec3(heat * 2.0 - 1.0, 1.0 - abs(heat * 2.0 - 1.0), 1.0 - heat * 2.0), 0.0, 1.0);
+    return mix(color, heat_color, 0.75);
+}
+
+#endif
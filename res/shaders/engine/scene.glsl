@@ -0,0 +1,13 @@
+#ifndef ENGINE_SCENE_GLSL
+#define ENGINE_SCENE_GLSL
+
+layout (set = 4, binding = 0) uniform textureCube skybox_tex;
+layout (set = 4, binding = 1) uniform sampler skybox_tex_sampler;
+layout (set = 4, binding = 2) uniform textureCube skybox_irradiance_tex;
+layout (set = 4, binding = 3) uniform sampler skybox_irradiance_tex_sampler;
+layout (set = 4, binding = 4) uniform textureCube skybox_prefiltered_tex;
+layout (set = 4, binding = 5) uniform sampler skybox_prefiltered_tex_sampler;
+layout (set = 4, binding = 6) uniform texture2D brdf_lut_tex;
+layout (set = 4, binding = 7) uniform sampler brdf_lut_tex_sampler;
+
+#endif
@@ -0,0 +1,50 @@
+#version 450
+
+layout (location = 0) in vec2 v_texcoords;
+
+layout (location = 0) out vec4 f_color;
+
+// GBUFFER_TARGETS in graphics.rs, written by pbr.frag
+layout (set = 0, binding = 0) uniform texture2D gbuffer_albedo_ao;
+layout (set = 0, binding = 1) uniform texture2D gbuffer_normal_roughness;
+layout (set = 0, binding = 2) uniform texture2D gbuffer_emissive_metallic;
+layout (set = 0, binding = 3) uniform texture2D gbuffer_view_depth;
+layout (set = 0, binding = 4) uniform sampler gbuffer_sampler;
+
+#include <engine/common.glsl>
+#include <engine/light.glsl>
+#include <engine/camera.glsl>
+#include <engine/scene.glsl>
+#include <engine/brdf.glsl>
+#include <engine/pbr_lighting.glsl>
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    float view_depth = texelFetch(sampler2D(gbuffer_view_depth, gbuffer_sampler), texel, 0).r;
+    // nothing was drawn, left to the skybox
+    if (view_depth <= 0.0) {
+        discard;
+    }
+    vec4 albedo_ao = texelFetch(sampler2D(gbuffer_albedo_ao, gbuffer_sampler), texel, 0);
+    vec4 normal_roughness = texelFetch(sampler2D(gbuffer_normal_roughness, gbuffer_sampler), texel, 0);
+    vec4 emissive_metallic = texelFetch(sampler2D(gbuffer_emissive_metallic, gbuffer_sampler), texel, 0);
+
+    // back to world space through the near plane
+    vec4 near_point = matrix_proj_inv * vec4(v_texcoords.x * 2.0 - 1.0, 1.0 - v_texcoords.y * 2.0, 0.0, 1.0);
+    vec3 view_position = near_point.xyz / near_point.w;
+    view_position *= view_depth / -view_position.z;
+    vec3 position = (matrix_view_inv * vec4(view_position, 1.0)).xyz;
+
+    Surface surface = Surface(
+        position,
+        normalize(normal_roughness.xyz),
+        normalize(camera_position - position),
+        albedo_ao.rgb,
+        emissive_metallic.a,
+        normal_roughness.a,
+        albedo_ao.a
+    );
+    uint cluster = FragmentCluster(cluster_params, gl_FragCoord.xy, view_depth);
+    vec3 final_color = ClusteredLighting(surface, cluster) + emissive_metallic.rgb + IndirectLighting(surface);
+    f_color = vec4(ClusterHeatmap(final_color, cluster), 1.0);
+}
@@ -7,7 +7,7 @@ layout (location = 0) out vec4 f_color;
 layout (set = 1, binding = 0) uniform textureCube skybox_tex;
 layout (set = 1, binding = 1) uniform sampler skybox_tex_sampler;
 
-const float PI = 3.14159265359;
+#include <engine/common.glsl>
 
 void main() {
     vec3 normal_dir = normalize(v_position);
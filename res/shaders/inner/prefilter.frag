@@ -10,43 +10,9 @@ layout (set = 1, binding = 2) uniform Uniform {
     float u_roughness;
 };
 
-const float PI = 3.14159265359;
-const int SAMPLE_COUNT = 1024;
-
-float RadicalInverseVdc(uint bits) {
-    bits = (bits << 16u) | (bits >> 16u);
-    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
-    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
-    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
-    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
-    return float(bits) * 2.3283064365386963e-10; // / 0x100000000
-}
-
-vec2 Hammersley(int i, int n) {
-    return vec2(float(i) / float(n), RadicalInverseVdc(uint(i)));
-}
-
-vec3 ImportanceSampleGgx(vec2 sam, vec3 normal_dir, float a2) {
-    float phi = 2.0 * PI * sam.x;
-    float cos_theta_sqr = (1.0 - sam.y) / (1.0 + (a2 - 1.0) * sam.y);
-    float cos_theta = sqrt(cos_theta_sqr);
-    float sin_theta = sqrt(1.0 - cos_theta_sqr);
+#include <engine/brdf.glsl>
 
-    vec3 half_dir = vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
-    vec3 bitangent_dir = vec3(0.0, 1.0, 0.0);
-    vec3 tangent_dir = cross(bitangent_dir, normal_dir);
-    bitangent_dir = cross(normal_dir, tangent_dir);
-    vec3 sample_dir = tangent_dir * half_dir.x + bitangent_dir * half_dir.y + normal_dir * half_dir.z;
-    return sample_dir;
-}
-
-float pow2(float x) {
-    return x * x;
-}
-
-float NdfGgx(float ndoth, float a2) {
-    return a2 / max(PI * pow2(ndoth * ndoth * (a2 - 1) + 1), 0.0001);
-}
+const int SAMPLE_COUNT = 1024;
 
 void main() {
     vec3 normal_dir = normalize(v_position);
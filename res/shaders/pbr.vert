@@ -1,10 +1,24 @@
 #version 450
 
+// attributes missing from the shader's vertex layout fall back to MeshVertex defaults
 layout (location = 0) in vec3 a_position;
+#ifdef VERTEX_TEXCOORDS
 layout (location = 1) in vec2 a_texcoords;
+#else
+const vec2 a_texcoords = vec2(0.0);
+#endif
+#if defined(VERTEX_NORMAL_OCT)
+layout (location = 2) in vec2 a_normal_oct;
+#elif defined(VERTEX_NORMAL)
 layout (location = 2) in vec3 a_normal;
+#else
+const vec3 a_normal = vec3(0.0, 0.0, 1.0);
+#endif
+#ifdef VERTEX_TANGENT
 layout (location = 3) in vec4 a_tangent;
-layout (location = 4) in vec4 a_color;
+#else
+const vec4 a_tangent = vec4(1.0, 0.0, 0.0, 1.0);
+#endif
 
 layout (location = 0) out vec3 v_position;
 layout (location = 1) out vec2 v_texcoords;
@@ -12,17 +26,21 @@ layout (location = 2) out vec3 v_normal;
 layout (location = 3) out vec3 v_tangent;
 layout (location = 4) out vec3 v_bitangent;
 
+// the same as in depth_only.vert
+invariant gl_Position;
+
 layout (set = 0, binding = 0) uniform MaterialUniform {
     vec4 base_color;
     vec3 emissive_factor;
     float metallic_factor;
     float roughness_factor;
+    float alpha_cutoff;
 };
 
 layout (set = 0, binding = 1) uniform texture2D base_color_tex;
 layout (set = 0, binding = 2) uniform sampler base_color_tex_sampler;
 
-layout (set = 0, binding = 3) uniform texture2D noral_tex;
+layout (set = 0, binding = 3) uniform texture2D normal_tex;
 layout (set = 0, binding = 4) uniform sampler normal_tex_sampler;
 
 layout (set = 0, binding = 5) uniform texture2D metallic_roughness_tex;
@@ -31,28 +49,23 @@ layout (set = 0, binding = 6) uniform sampler metallic_roughness_tex_sampler;
 layout (set = 0, binding = 7) uniform texture2D emissive_tex;
 layout (set = 0, binding = 8) uniform sampler emissive_tex_sampler;
 
-layout (set = 1, binding = 0) uniform ObjectUniform {
-    mat4 matrix_model;
-    mat4 matrix_model_iv;
-};
-
-layout (set = 2, binding = 0) uniform LightUniform {
-    vec4 light_position;
-    vec4 light_color;
-};
+#include <engine/object.glsl>
+#include <engine/camera.glsl>
 
-layout (set = 3, binding = 0) uniform CameraUniform {
-    mat4 matrix_view;
-    mat4 matrix_proj;
-    mat4 matrix_view_inv;
-    mat4 matrix_proj_inv;
-    vec3 camera_position;
-    float _padding0;
-    float camera_znear;
-    float camera_zfar;
-};
+#ifdef VERTEX_NORMAL_OCT
+vec3 oct_decode(vec2 e) {
+    vec3 n = vec3(e, 1.0 - abs(e.x) - abs(e.y));
+    float t = max(-n.z, 0.0);
+    n.x += n.x >= 0.0 ? -t : t;
+    n.y += n.y >= 0.0 ? -t : t;
+    return normalize(n);
+}
+#endif
 
 void main() {
+#ifdef VERTEX_NORMAL_OCT
+    vec3 a_normal = oct_decode(a_normal_oct);
+#endif
     v_position = (matrix_model * vec4(a_position, 1.0)).xyz;
     v_texcoords = a_texcoords;
     v_normal = normalize(mat3(matrix_model_iv) * a_normal);
@@ -133,6 +133,34 @@ impl Camera {
         self.uniform_dirty = true;
     }
 
+    // up to date after `update`
+    pub fn view(&self) -> cgmath::Matrix4<f32> {
+        self.uniform.view.into()
+    }
+
+    pub fn proj_inv(&self) -> cgmath::Matrix4<f32> {
+        self.uniform.proj_inv.into()
+    }
+
+    pub fn depth_range(&self) -> (f32, f32) {
+        (self.znear, self.zfar)
+    }
+
+    // radius in pixels of a sphere seen by this camera, infinite if the eye is inside it
+    pub fn projected_radius(
+        &self,
+        center: cgmath::Point3<f32>,
+        radius: f32,
+        viewport_height: u32,
+    ) -> f32 {
+        let distance = (center - self.eye).magnitude();
+        if distance <= radius {
+            return f32::INFINITY;
+        }
+        let half_fovy = cgmath::Rad::from(cgmath::Deg(self.fovy * 0.5));
+        radius / (distance * half_fovy.0.tan()) * viewport_height as f32 * 0.5
+    }
+
     pub fn build(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout) {
         self.uniform_buffer = Some(
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -0,0 +1,169 @@
+// Compute passes dispatched every frame, and the buffers and textures they bind by name.
+use anyhow::*;
+
+use crate::engine::Engine;
+use crate::render_graph::RenderGraph;
+use crate::shader::compute::{ComputeResourceKind, ComputeSubShader};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ComputeStage {
+    BeforeRender,
+    // can read the render targets of the frame
+    AfterRender,
+}
+
+// A compute sub shader dispatched every frame.
+#[derive(Clone, Debug)]
+pub struct ComputePass {
+    pub shader: String,
+    pub tag: String,
+    pub workgroups: [u32; 3],
+    pub stage: ComputeStage,
+}
+
+impl Engine {
+    // Replaces the buffer of the same name, compute shaders bind it as a uniform or storage buffer.
+    pub fn create_storage_buffer(&mut self, name: &str, size: u64) {
+        let buffer = self
+            .graphics_state
+            .device
+            .create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{} Storage Buffer", name)),
+                size,
+                usage: wgpu::BufferUsage::STORAGE
+                    | wgpu::BufferUsage::UNIFORM
+                    | wgpu::BufferUsage::COPY_DST
+                    | wgpu::BufferUsage::COPY_SRC,
+                mapped_at_creation: false,
+            });
+        self.graphics_state
+            .storage_buffers
+            .insert(name.to_string(), buffer);
+    }
+
+    // Dispatches the pass every frame from now on, after the ones scheduled before it. A pass
+    // of the same name is replaced in place.
+    pub fn schedule_compute_pass(&mut self, name: &str, pass: ComputePass) -> Result<()> {
+        let compute = self
+            .shaders
+            .get(&pass.shader)
+            .and_then(|shader| shader.compute_shaders.get(&pass.tag))
+            .with_context(|| {
+                format!(
+                    "Shader '{}' has no compute shader '{}'",
+                    &pass.shader, &pass.tag
+                )
+            })?;
+        self.compute_bind_group(compute)
+            .with_context(|| format!("Can't schedule compute pass '{}'", name))?;
+        match self
+            .compute_passes
+            .iter_mut()
+            .find(|(scheduled, _)| scheduled == name)
+        {
+            Some((_, scheduled)) => *scheduled = pass,
+            None => self.compute_passes.push((name.to_string(), pass)),
+        }
+        Ok(())
+    }
+
+    // Passes whose shader or resources went away, e.g. by a reload, are skipped until they're
+    // back, missing resources are logged once. A pass reads all of its resources and writes the
+    // writable ones.
+    pub(crate) fn add_compute_passes<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        stage: ComputeStage,
+    ) {
+        for (name, pass) in &self.compute_passes {
+            if pass.stage != stage {
+                continue;
+            }
+            let key = (pass.shader.clone(), pass.tag.clone());
+            let compute = self
+                .shaders
+                .get(&pass.shader)
+                .and_then(|shader| shader.compute_shaders.get(&pass.tag));
+            let pipeline = self.graphics_state.compute_pipelines.get(&key);
+            let (compute, pipeline) = match (compute, pipeline) {
+                (Some(compute), Some(pipeline)) => (compute, pipeline),
+                _ => continue,
+            };
+            let mut reads = vec![];
+            let mut writes = vec![];
+            for resource in &compute.resources {
+                let name = resource.name.as_str();
+                if !graph.contains(name) {
+                    graph.import(name);
+                }
+                reads.push(name);
+                if let ComputeResourceKind::StorageBuffer { read_only: false } = resource.kind {
+                    writes.push(name);
+                }
+            }
+            graph.add_pass(name, &reads, &writes, move |encoder, _| {
+                let bind_group = match self.compute_bind_group(compute) {
+                    Ok(bind_group) => {
+                        self.failed_compute_passes.borrow_mut().remove(name);
+                        bind_group
+                    }
+                    Err(err) => {
+                        if self.failed_compute_passes.borrow_mut().insert(name.clone()) {
+                            eprintln!("Skipping compute pass '{}': {:?}", name, err);
+                        }
+                        return;
+                    }
+                };
+                let mut compute_pass =
+                    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: Some(name) });
+                compute_pass.set_pipeline(pipeline);
+                compute_pass.set_bind_group(0, &bind_group, &[]);
+                let [x, y, z] = pass.workgroups;
+                compute_pass.dispatch(x, y, z);
+            });
+        }
+    }
+
+    // Created each time it's used, render targets are recreated on resize.
+    fn compute_bind_group(&self, compute: &ComputeSubShader) -> Result<wgpu::BindGroup> {
+        let graphics_state = &self.graphics_state;
+        let texture = |name: &str| {
+            self.render_target(name)
+                .with_context(|| format!("No render target '{}'", name))
+        };
+        let mut entries = Vec::with_capacity(compute.resources.len());
+        for resource in &compute.resources {
+            let name = resource.name.as_str();
+            let binding_resource = match resource.kind {
+                ComputeResourceKind::UniformBuffer | ComputeResourceKind::StorageBuffer { .. } => {
+                    let buffer = graphics_state
+                        .storage_buffers
+                        .get(name)
+                        .with_context(|| format!("No storage buffer '{}'", name))?;
+                    wgpu::BindingResource::Buffer {
+                        buffer,
+                        offset: 0,
+                        size: None,
+                    }
+                }
+                ComputeResourceKind::Texture => {
+                    wgpu::BindingResource::TextureView(&texture(name)?.view)
+                }
+                ComputeResourceKind::Sampler => {
+                    wgpu::BindingResource::Sampler(&texture(name)?.sampler)
+                }
+            };
+            entries.push(wgpu::BindGroupEntry {
+                binding: resource.binding,
+                resource: binding_resource,
+            });
+        }
+        Ok(graphics_state
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(&format!("{} Compute Bind Group", &compute.tag)),
+                layout: compute.bind_group_layout().unwrap(),
+                entries: &entries,
+            }))
+    }
+}
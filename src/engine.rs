@@ -1,22 +1,45 @@
 use anyhow::*;
+use cgmath::prelude::*;
 
 use crate::camera::{Camera, CubeCamera};
+use crate::compute::{ComputePass, ComputeStage};
 use crate::env_map::EnvMap;
-use crate::graphics::GraphicsState;
-use crate::light::Light;
+use crate::geometry::{GeometryArena, MeshAllocation};
+use crate::graphics::{GraphicsState, DEPTH_TARGET, GBUFFER_TARGETS};
+use crate::hot_reload::{FileWatcher, ShaderSource};
+use crate::light::{Light, LightArray};
+use crate::light_clusters::{self, LightClusters};
 use crate::material::Material;
-use crate::mesh::Mesh;
-use crate::shader::Shader;
+use crate::mesh::{Mesh, MeshLod};
+use crate::mesh_processing::normals::NormalMode;
+use crate::mesh_processing::simplify::LodOptions;
+use crate::render_graph::{GraphTextures, RenderGraph, TransientTexture};
+use crate::render_passes::{self, FramePass, LightMode, Lighting};
+use crate::shader::schema::{self, MaterialEntry, ShaderEntry, ShaderJson, ShadersFile};
+use crate::shader::{render_queue, Keywords, PipelineKey, Shader, FRAME_TARGET};
 use crate::texture::Texture;
 use image::GenericImageView;
-use std::collections::HashMap;
-use std::convert::TryInto;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use winit::dpi::{PhysicalPosition, PhysicalSize};
 use winit::event::{
     ElementState, Event, KeyboardInput, MouseScrollDelta, VirtualKeyCode, WindowEvent,
 };
 use winit::event_loop::ControlFlow;
 
+// A mesh to draw this frame.
+struct Draw<'a> {
+    mesh: &'a Mesh,
+    allocation: MeshAllocation,
+    lod: &'a MeshLod,
+    material: &'a Material,
+    // from the camera to the center of its bounds
+    distance: f32,
+}
+
 pub struct Engine {
     // TODO - make these fields clean ?
     window: winit::window::Window,
@@ -24,13 +47,39 @@ pub struct Engine {
     last_mouse_position: PhysicalPosition<f64>,
     pub graphics_state: GraphicsState,
     pub meshes: Vec<Mesh>,
+    pub geometry: GeometryArena,
+    pub normal_mode: NormalMode,
+    pub lod_options: Option<LodOptions>,
+    pub lod_pixel_error: f32,
+    pub optimize_meshes: bool,
     camera: Camera,
     pub skybox_camera: CubeCamera,
     lights: Vec<Light>,
+    // all of the lights, bound with each of them
+    light_array: LightArray,
+    pub light_clusters: LightClusters,
     skybox: EnvMap,
     brdf_lut: Texture,
     pub shaders: HashMap<String, Shader>,
     pub materials: HashMap<String, Material>,
+    // pipelines of the shader variants materials use
+    pub pipeline_cache: HashMap<PipelineKey, wgpu::RenderPipeline>,
+    // definitions of the materials created from JSON, by name
+    pub material_definitions: HashMap<String, MaterialEntry>,
+    pub file_watcher: FileWatcher,
+    pub shader_sources: HashMap<String, ShaderSource>,
+    // material name -> its own json file
+    pub material_sources: HashMap<String, PathBuf>,
+    // dispatched every frame in this order, by name, see `schedule_compute_pass`
+    pub compute_passes: Vec<(String, ComputePass)>,
+    // passes that couldn't bind their resources when recorded, the error is only logged once
+    pub failed_compute_passes: RefCell<HashSet<String>>,
+    // drawn in this order every frame
+    pub frame_passes: Vec<FramePass>,
+    // how the default passes light, unless the shaders file has its own passes
+    pub lighting: Lighting,
+    // the default passes write the depth of opaque meshes before shading them
+    pub depth_prepass: bool,
 }
 
 impl Engine {
@@ -63,26 +112,14 @@ impl Engine {
             &graphics_state.bind_group_layouts["_Camera"],
         );
 
-        let mut light0 = Light::directional_light((-3.0, -1.0, -5.0).into(), [1.0, 1.0, 1.0, 1.0]);
-        light0.build(
-            &graphics_state.device,
-            &graphics_state.bind_group_layouts["_Light"],
-        );
-        let mut light1 = Light::directional_light((2.0, -1.0, 5.0).into(), [1.0, 1.0, 1.0, 1.0]);
-        light1.build(
-            &graphics_state.device,
-            &graphics_state.bind_group_layouts["_Light"],
-        );
-        let mut light2 = Light::directional_light((5.0, -1.0, 0.0).into(), [1.0, 1.0, 1.0, 1.0]);
-        light2.build(
-            &graphics_state.device,
-            &graphics_state.bind_group_layouts["_Light"],
-        );
-        let mut light3 = Light::directional_light((-5.0, -1.0, 0.0).into(), [1.0, 1.0, 1.0, 1.0]);
-        light3.build(
-            &graphics_state.device,
-            &graphics_state.bind_group_layouts["_Light"],
-        );
+        let lights = vec![
+            Light::directional_light((-3.0, -1.0, -5.0).into(), [1.0, 1.0, 1.0, 1.0]),
+            Light::directional_light((2.0, -1.0, 5.0).into(), [1.0, 1.0, 1.0, 1.0]),
+            Light::directional_light((5.0, -1.0, 0.0).into(), [1.0, 1.0, 1.0, 1.0]),
+            Light::directional_light((-5.0, -1.0, 0.0).into(), [1.0, 1.0, 1.0, 1.0]),
+        ];
+        // filled by `add_light` once the light cluster buffers exist
+        let light_array = LightArray::new(&graphics_state.device, &[]);
 
         let brdf_lut = image::load_from_memory(include_bytes!("../res/textures/brdf_lut.png"))?;
         let brdf_lut_width = brdf_lut.width();
@@ -117,14 +154,35 @@ impl Engine {
             last_mouse_position: PhysicalPosition { x: 0.0, y: 0.0 },
             graphics_state,
             meshes: vec![],
+            geometry: GeometryArena::new(),
+            normal_mode: NormalMode::default(),
+            lod_options: Some(LodOptions::default()),
+            lod_pixel_error: 1.0,
+            optimize_meshes: false,
             camera,
             skybox_camera,
-            lights: vec![light0, light1, light2, light3],
+            lights: vec![],
+            light_array,
+            light_clusters: LightClusters::default(),
             skybox,
             brdf_lut,
             shaders: HashMap::new(),
             materials: HashMap::new(),
+            pipeline_cache: HashMap::new(),
+            material_definitions: HashMap::new(),
+            file_watcher: FileWatcher::new(),
+            shader_sources: HashMap::new(),
+            material_sources: HashMap::new(),
+            compute_passes: vec![],
+            failed_compute_passes: RefCell::new(HashSet::new()),
+            frame_passes: render_passes::default_frame_passes(Lighting::SinglePass, false),
+            lighting: Lighting::SinglePass,
+            depth_prepass: false,
         };
+        engine.create_light_cluster_buffers();
+        for light in lights {
+            engine.add_light(light);
+        }
         engine.init_inner_pipelines();
 
         Ok((engine, event_loop))
@@ -174,49 +232,298 @@ impl Engine {
     }
 
     pub fn load_shaders<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
-        let json_file = std::fs::File::open(path)?;
-        let json_reader = std::io::BufReader::new(json_file);
-        let json_value: serde_json::Value = serde_json::from_reader(json_reader)?;
-
-        let shaders = json_value["shaders"].as_array().unwrap();
-        for shader in shaders {
-            let mut shader: Shader = shader.try_into()?;
-            shader.build(&self.graphics_state.device)?;
-            for (sub_shader_tag, sub_shader) in &shader.sub_shaders {
-                let render_pipeline = sub_shader.render_pipeline(
-                    &shader,
-                    &self.graphics_state.device,
-                    self.graphics_state.swap_chain_desc.format,
-                    GraphicsState::DEPTH_STENCIL_FORMAT,
-                    &self.graphics_state.bind_group_layouts["_Object"],
-                    &self.graphics_state.bind_group_layouts["_Light"],
-                    &self.graphics_state.bind_group_layouts["_Camera"],
-                    &self.graphics_state.bind_group_layouts["_Scene"],
-                );
-                self.graphics_state.render_pipelines.insert(
-                    format!("{}-{}", &shader.name, sub_shader_tag),
-                    render_pipeline,
-                );
+        let path = path.as_ref();
+        let shaders_file: ShadersFile = schema::from_file(path)?;
+        self.frame_passes = match &shaders_file.passes {
+            Some(passes) => render_passes::frame_passes_from_json(passes)
+                .map_err(|err| err.in_file(&path.display().to_string()))?,
+            None => render_passes::default_frame_passes(self.lighting, self.depth_prepass),
+        };
+
+        for (i, entry) in shaders_file.shaders.iter().enumerate() {
+            let (shader, source) = self.load_shader_entry(entry, i, path)?;
+            let name = shader.name.clone();
+            self.install_shader(shader)?;
+            self.watch_shader(&name, source);
+        }
+
+        self.add_materials(&shaders_file.materials, &path.display().to_string())?;
+        self.build_material_pipelines()?;
+        if let Lighting::Clustered | Lighting::Deferred = self.lighting {
+            self.schedule_light_clusters()?;
+        }
+        Ok(())
+    }
+
+    // Lit by every sub shader from the next frame on.
+    pub fn add_light(&mut self, mut light: Light) {
+        light.build(
+            &self.graphics_state.device,
+            &self.graphics_state.bind_group_layouts["_Light"],
+            &self.light_array,
+            &self.graphics_state.storage_buffers,
+        );
+        self.lights.push(light);
+        self.light_array
+            .update(&self.graphics_state.queue, &self.lights);
+        self.light_clusters.update_lights(
+            &self.graphics_state.queue,
+            &self.graphics_state.storage_buffers,
+            &self.lights,
+        );
+    }
+
+    // Parses and compiles one entry of a shaders file.
+    pub fn load_shader_entry(
+        &self,
+        entry: &ShaderEntry,
+        index: usize,
+        shaders_file: &std::path::Path,
+    ) -> Result<(Shader, ShaderSource)> {
+        let mut files = vec![];
+        let mut shader = match entry {
+            ShaderEntry::File(shader_path) => {
+                files.push(shader_path.into());
+                let shader_json: ShaderJson = schema::from_file(shader_path)?;
+                Shader::try_from(&shader_json).map_err(|err| err.in_file(shader_path))?
+            }
+            ShaderEntry::Inline(shader_json) => Shader::try_from(shader_json).map_err(|err| {
+                err.at(&format!("shaders[{}]", index))
+                    .in_file(&shaders_file.display().to_string())
+            })?,
+        };
+        // the files the shader names, its includes are only known once it's built
+        files.extend(shader.source_files());
+        let mut source = ShaderSource {
+            shaders_file: shaders_file.to_path_buf(),
+            files,
+        };
+        shader
+            .build(&self.graphics_state.device)
+            .with_context(|| format!("Can't build shader '{}'", &shader.name))?;
+        source.files.extend(shader.included_files());
+        Ok((shader, source))
+    }
+
+    // Creates the pipelines of a built shader for the keyword sets its materials use. A shader
+    // replacing one with the same name takes over its materials.
+    pub fn install_shader(&mut self, mut shader: Shader) -> Result<()> {
+        if let Some(old_shader) = self.shaders.get(&shader.name) {
+            // meshes are uploaded with the attributes of their material's shader
+            let used_by_meshes = self.meshes.iter().any(|mesh| {
+                self.materials
+                    .get(&mesh.material)
+                    .is_some_and(|material| material.shader == shader.name)
+            });
+            if used_by_meshes && old_shader.vertex_layout != shader.vertex_layout {
+                bail!("Vertex attributes changed, the scene has to be reloaded");
             }
-            self.shaders.insert(shader.name.clone(), shader);
         }
 
-        let materials = json_value["materials"].as_array().unwrap();
-        for material in materials {
-            let name = material["name"].as_str().unwrap().to_string();
-            let shader = material["shader"].as_str().unwrap().to_string();
-            let material = Material::from_shader(
-                name.clone(),
-                &self.shaders[&shader],
-                &self.graphics_state.device,
-                &self.graphics_state.queue,
+        // materials keep the keywords the new version still declares, see Material::rebind
+        let keyword_sets: HashSet<Keywords> = self
+            .materials
+            .values()
+            .filter(|material| material.shader == shader.name)
+            .map(|material| shader.supported_keywords(&material.keywords))
+            .collect();
+        let target_formats = self.render_target_formats(&shader)?;
+        // `_GBufferDepth` can't be resolved, and resolving normals and depths would average them
+        // across edges
+        let deferred = self
+            .frame_passes
+            .iter()
+            .any(|frame_pass| matches!(frame_pass, FramePass::DeferredLighting));
+        if deferred && shader.sample_count() > 1 {
+            bail!(
+                "Shader '{}' asks for {}x MSAA, deferred shading only works without MSAA",
+                &shader.name,
+                shader.sample_count()
             );
-            self.materials.insert(name, material);
         }
+        // the main pass is multisampled as much as any installed shader asks for
+        let sample_count = self
+            .shaders
+            .values()
+            .filter(|other| other.name != shader.name)
+            .map(Shader::sample_count)
+            .chain(std::iter::once(shader.sample_count()))
+            .max()
+            .unwrap_or(1);
+        let mut pipelines = HashMap::new();
+        for keywords in &keyword_sets {
+            Self::build_variant_pipelines(
+                &self.graphics_state,
+                &mut shader,
+                keywords,
+                sample_count,
+                &mut pipelines,
+            )
+            .with_context(|| format!("Can't build variant {:?}", keywords))?;
+        }
+        if sample_count != self.graphics_state.sample_count {
+            // the other shaders' pipelines are rebuilt by build_material_pipelines
+            self.graphics_state.set_sample_count(sample_count);
+            self.pipeline_cache.clear();
+            self.skybox_pipeline();
+            self.deferred_lighting_pipeline();
+        }
+        self.graphics_state.set_render_targets(target_formats);
+        self.graphics_state.create_compute_pipelines(&shader);
 
+        if let Some(old_shader) = self.shaders.remove(&shader.name) {
+            self.pipeline_cache
+                .retain(|key, _| key.shader != shader.name);
+            for material in self.materials.values_mut() {
+                if material.shader == shader.name {
+                    material.rebind(
+                        &old_shader,
+                        &shader,
+                        &self.graphics_state.device,
+                        &self.graphics_state.queue,
+                    );
+                }
+            }
+        }
+        self.pipeline_cache.extend(pipelines);
+        self.shaders.insert(shader.name.clone(), shader);
         Ok(())
     }
 
+    // Compiles the shader variants of all materials and creates their missing pipelines, call
+    // after adding materials or changing their keywords.
+    pub fn build_material_pipelines(&mut self) -> Result<()> {
+        let variants: HashSet<(String, Keywords)> = self
+            .materials
+            .values()
+            .map(|material| (material.shader.clone(), material.keywords.clone()))
+            .collect();
+        for (shader_name, keywords) in variants {
+            let shader = match self.shaders.get_mut(&shader_name) {
+                Some(shader) => shader,
+                None => continue,
+            };
+            Self::build_variant_pipelines(
+                &self.graphics_state,
+                shader,
+                &keywords,
+                self.graphics_state.sample_count,
+                &mut self.pipeline_cache,
+            )
+            .with_context(|| format!("Can't build variant {:?} of '{}'", keywords, shader_name))?;
+        }
+        Ok(())
+    }
+
+    fn build_variant_pipelines(
+        graphics_state: &GraphicsState,
+        shader: &mut Shader,
+        keywords: &Keywords,
+        sample_count: u32,
+        pipelines: &mut HashMap<PipelineKey, wgpu::RenderPipeline>,
+    ) -> Result<()> {
+        for (tag, sub_shader) in &shader.sub_shaders {
+            let missing = sub_shader.required_features() - graphics_state.device.features();
+            if !missing.is_empty() {
+                bail!("Sub shader '{}' needs unsupported {:?}", tag, missing);
+            }
+        }
+        shader.build_variant(keywords, &graphics_state.device)?;
+        for (tag, sub_shader) in &shader.sub_shaders {
+            let key = PipelineKey {
+                shader: shader.name.clone(),
+                tag: tag.clone(),
+                keywords: keywords.clone(),
+                vertex_layout: shader.vertex_layout.clone(),
+                color_format: graphics_state.swap_chain_desc.format,
+                sample_count,
+            };
+            if pipelines.contains_key(&key) {
+                continue;
+            }
+            let pipeline = sub_shader.render_pipeline(
+                shader,
+                keywords,
+                &graphics_state.device,
+                key.color_format,
+                GraphicsState::DEPTH_STENCIL_FORMAT,
+                sample_count,
+                &graphics_state.bind_group_layouts["_Object"],
+                &graphics_state.bind_group_layouts["_Light"],
+                &graphics_state.bind_group_layouts["_Camera"],
+                &graphics_state.bind_group_layouts["_Scene"],
+            );
+            pipelines.insert(key, pipeline);
+        }
+        Ok(())
+    }
+
+    // The names of the targets the sub shader `tag` of the material's shader writes.
+    fn material_targets(&self, material: &Material, tag: &str) -> Option<Vec<&str>> {
+        let sub_shader = self.shaders.get(&material.shader)?.sub_shaders.get(tag)?;
+        Some(
+            sub_shader
+                .targets()
+                .iter()
+                .map(|target| target.name.as_str())
+                .collect(),
+        )
+    }
+
+    // A texture sub shaders render into, by the name their targets give it.
+    pub fn render_target(&self, name: &str) -> Option<&Texture> {
+        self.graphics_state.render_targets.get(name)
+    }
+
+    // Formats of the render targets of the installed shaders, with `shader` replacing the one
+    // of the same name. A target has one texture, so sub shaders have to agree on its format.
+    fn render_target_formats(
+        &self,
+        shader: &Shader,
+    ) -> Result<HashMap<String, wgpu::TextureFormat>> {
+        let mut formats: HashMap<String, wgpu::TextureFormat> = HashMap::new();
+        let shaders = self
+            .shaders
+            .values()
+            .filter(|other| other.name != shader.name)
+            .chain(std::iter::once(shader));
+        for other in shaders {
+            for (tag, sub_shader) in &other.sub_shaders {
+                for target in sub_shader.targets() {
+                    let format = match target.format {
+                        Some(format) => format,
+                        None => continue,
+                    };
+                    match formats.insert(target.name.clone(), format) {
+                        Some(previous) if previous != format => bail!(
+                            "'{}-{}' writes target '{}' as {:?}, other sub shaders as {:?}",
+                            other.name,
+                            tag,
+                            target.name,
+                            format,
+                            previous
+                        ),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(formats)
+    }
+
+    // The pipeline drawing `material` in the sub shader `tag`, if built.
+    fn material_pipeline(&self, material: &Material, tag: &str) -> Option<&wgpu::RenderPipeline> {
+        let shader = self.shaders.get(&material.shader)?;
+        self.pipeline_cache.get(&PipelineKey {
+            shader: shader.name.clone(),
+            tag: tag.to_string(),
+            keywords: material.keywords.clone(),
+            vertex_layout: shader.vertex_layout.clone(),
+            color_format: self.graphics_state.swap_chain_desc.format,
+            sample_count: self.graphics_state.sample_count,
+        })
+    }
+
     pub fn load_skybox<P: AsRef<std::path::Path>>(
         &mut self,
         path_pos_x: P,
@@ -306,6 +613,9 @@ impl Engine {
                             VirtualKeyCode::E => self
                                 .camera
                                 .translate(cgmath::Vector3::new(0.0, -delta, 0.0)),
+                            VirtualKeyCode::H => self
+                                .light_clusters
+                                .set_heatmap(!self.light_clusters.heatmap()),
                             _ => result = false,
                         }
                     }
@@ -353,78 +663,342 @@ impl Engine {
     }
 
     fn update(&mut self) {
+        self.hot_reload();
         self.camera.update(&self.graphics_state.queue);
+        self.light_clusters.update(
+            &self.graphics_state.queue,
+            &self.graphics_state.storage_buffers,
+            &self.camera,
+            self.window_size.width,
+            self.window_size.height,
+        );
     }
 
     fn render(&self) -> Result<(), wgpu::SwapChainError> {
         let frame = self.graphics_state.swap_chain.get_current_frame()?.output;
-        let mut encoder =
-            self.graphics_state
-                .device
-                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                    label: Some("Render Encoder"),
-                });
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                        store: true,
+        let draws: Vec<_> = self
+            .meshes
+            .iter()
+            .filter_map(|mesh| {
+                let (center, _) = mesh.world_bounding_sphere();
+                Some(Draw {
+                    mesh,
+                    allocation: mesh.allocation?,
+                    lod: self.select_lod(mesh)?,
+                    material: self.materials.get(&mesh.material)?,
+                    distance: (center - self.camera.eye).magnitude(),
+                })
+            })
+            .collect();
+
+        // a pass can't change its attachments, sub shaders writing other targets than the frame
+        // get a pass per target list, in the order the frame passes first write them
+        let mut target_lists: Vec<Vec<&str>> = vec![];
+        for frame_pass in &self.frame_passes {
+            let tag = match frame_pass {
+                FramePass::Draw { tag, .. } => tag,
+                FramePass::Skybox | FramePass::DeferredLighting => {
+                    if !target_lists.contains(&vec![FRAME_TARGET]) {
+                        target_lists.push(vec![FRAME_TARGET]);
+                    }
+                    continue;
+                }
+            };
+            for draw in &draws {
+                if let Some(targets) = self.material_targets(draw.material, tag) {
+                    if !target_lists.contains(&targets) {
+                        target_lists.push(targets);
+                    }
+                }
+            }
+        }
+        // cleared even if nothing is drawn
+        if !target_lists.contains(&vec![FRAME_TARGET]) {
+            target_lists.push(vec![FRAME_TARGET]);
+        }
+
+        let sample_count = self.graphics_state.sample_count;
+        let mut graph = RenderGraph::new("Frame");
+        graph.import_texture(FRAME_TARGET, &frame.view);
+        for (name, texture) in &self.graphics_state.render_targets {
+            graph.import_texture(name, &texture.view);
+        }
+        // every light binds them
+        for name in &light_clusters::CLUSTER_BUFFERS {
+            graph.import(name);
+        }
+        graph.create_texture(
+            DEPTH_TARGET,
+            TransientTexture {
+                format: GraphicsState::DEPTH_STENCIL_FORMAT,
+                sample_count,
+            },
+        );
+        // multisampled targets are rendered into a transient texture and resolved
+        if sample_count > 1 {
+            for &name in target_lists.iter().flatten() {
+                let format = match name {
+                    FRAME_TARGET => self.graphics_state.swap_chain_desc.format,
+                    _ => self.graphics_state.render_targets[name].format,
+                };
+                graph.create_texture(
+                    &msaa_target(name),
+                    TransientTexture {
+                        format,
+                        sample_count,
                     },
-                }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: &self.graphics_state.depth_stencil_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(0),
-                        store: true,
-                    }),
-                }),
-            });
-            render_pass.set_bind_group(4, &self.skybox.bind_group, &[]);
-            render_pass.set_bind_group(3, &self.camera.bind_group.as_ref().unwrap(), &[]);
-            let mut is_first = true;
-            for light in &self.lights {
-                let sub_shader_tag = if is_first {
-                    "ForwardBase"
+                );
+            }
+        }
+
+        self.add_compute_passes(&mut graph, ComputeStage::BeforeRender);
+        let draws = &draws;
+        let deferred = self.graphics_state.gbuffer_bind_group.is_some()
+            && self
+                .frame_passes
+                .iter()
+                .any(|frame_pass| matches!(frame_pass, FramePass::DeferredLighting));
+        let mut cleared = HashSet::new();
+        for (i, target_list) in target_lists.iter().enumerate() {
+            let loads: Vec<_> = target_list
+                .iter()
+                .map(|&name| {
+                    if cleared.insert(name) {
+                        wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+                    } else {
+                        wgpu::LoadOp::Load
+                    }
+                })
+                .collect();
+            let msaa_targets: Vec<_> = target_list.iter().map(|&name| msaa_target(name)).collect();
+
+            let mut reads: Vec<&str> = light_clusters::CLUSTER_BUFFERS.to_vec();
+            let mut writes = vec![DEPTH_TARGET];
+            if i > 0 {
+                reads.push(DEPTH_TARGET);
+            }
+            for (j, &name) in target_list.iter().enumerate() {
+                let loaded = matches!(loads[j], wgpu::LoadOp::Load);
+                if loaded {
+                    reads.push(name);
+                }
+                writes.push(name);
+                if sample_count > 1 {
+                    if loaded {
+                        reads.push(&msaa_targets[j]);
+                    }
+                    writes.push(&msaa_targets[j]);
+                }
+            }
+            if deferred && target_list == &[FRAME_TARGET] {
+                reads.extend(&GBUFFER_TARGETS);
+            }
+
+            let label = format!("Render Pass {:?}", target_list);
+            let attachments = msaa_targets.clone();
+            let record = move |encoder: &mut wgpu::CommandEncoder, textures: &GraphTextures| {
+                let color_attachments: Vec<_> = target_list
+                    .iter()
+                    .zip(&attachments)
+                    .zip(loads)
+                    .map(|((&name, msaa_name), load)| {
+                        let (attachment, resolve_target) = if sample_count > 1 {
+                            (textures.view(msaa_name), Some(textures.view(name)))
+                        } else {
+                            (textures.view(name), None)
+                        };
+                        wgpu::RenderPassColorAttachmentDescriptor {
+                            attachment,
+                            resolve_target,
+                            ops: wgpu::Operations { load, store: true },
+                        }
+                    })
+                    .collect();
+                let (depth_load, stencil_load) = if i == 0 {
+                    (wgpu::LoadOp::Clear(1.0), wgpu::LoadOp::Clear(0))
                 } else {
-                    "ForwardAdd"
+                    (wgpu::LoadOp::Load, wgpu::LoadOp::Load)
                 };
-                is_first = false;
-                render_pass.set_bind_group(2, light.bind_group.as_ref().unwrap(), &[]);
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some(&label),
+                    color_attachments: &color_attachments,
+                    depth_stencil_attachment: Some(
+                        wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                            attachment: textures.view(DEPTH_TARGET),
+                            depth_ops: Some(wgpu::Operations {
+                                load: depth_load,
+                                store: true,
+                            }),
+                            stencil_ops: Some(wgpu::Operations {
+                                load: stencil_load,
+                                store: true,
+                            }),
+                        },
+                    ),
+                });
+                for frame_pass in &self.frame_passes {
+                    match frame_pass {
+                        FramePass::Draw {
+                            tag,
+                            lights,
+                            queues,
+                        } => self.draw_meshes(
+                            &mut render_pass,
+                            draws,
+                            target_list,
+                            tag,
+                            *lights,
+                            queues,
+                        ),
+                        FramePass::Skybox if target_list == &[FRAME_TARGET] => {
+                            self.draw_skybox(&mut render_pass)
+                        }
+                        FramePass::DeferredLighting if target_list == &[FRAME_TARGET] => {
+                            self.draw_deferred_lighting(&mut render_pass)
+                        }
+                        FramePass::Skybox | FramePass::DeferredLighting => {}
+                    }
+                }
+            };
+            graph.add_pass(
+                &format!("Render Pass {:?}", target_list),
+                &reads,
+                &writes,
+                record,
+            );
+        }
+        self.add_compute_passes(&mut graph, ComputeStage::AfterRender);
+        graph.execute(&self.graphics_state);
+        Ok(())
+    }
+
+    // Draws the meshes whose sub shader `tag` writes exactly `target_list` and is in one of the
+    // queues, queue by queue.
+    fn draw_meshes<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        draws: &[Draw<'a>],
+        target_list: &[&str],
+        tag: &str,
+        lights: LightMode,
+        queues: &RangeInclusive<u32>,
+    ) {
+        let object_bind_group = match &self.geometry.object_bind_group {
+            Some(bind_group) => bind_group,
+            None => return,
+        };
+        let mut selected: Vec<_> = draws
+            .iter()
+            .filter_map(|draw| {
+                let sub_shader = self
+                    .shaders
+                    .get(&draw.material.shader)?
+                    .sub_shaders
+                    .get(tag)?;
+                let queue = draw.material.queue.unwrap_or_else(|| sub_shader.queue());
+                if !queues.contains(&queue)
+                    || self.material_targets(draw.material, tag).as_deref() != Some(target_list)
+                {
+                    return None;
+                }
+                Some((draw, queue, self.material_pipeline(draw.material, tag)?))
+            })
+            .collect();
+        if selected.is_empty() {
+            return;
+        }
+        // opaque queues are drawn pipeline by pipeline and front to back so early depth tests
+        // skip the fragments behind, transparent ones back to front
+        selected.sort_by(|(a, queue_a, pipeline_a), (b, queue_b, pipeline_b)| {
+            queue_a.cmp(queue_b).then_with(|| {
+                if *queue_a > render_queue::LAST_OPAQUE {
+                    b.distance
+                        .partial_cmp(&a.distance)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                } else {
+                    (*pipeline_a as *const wgpu::RenderPipeline)
+                        .cmp(&(*pipeline_b as *const _))
+                        .then_with(|| {
+                            a.distance
+                                .partial_cmp(&b.distance)
+                                .unwrap_or(std::cmp::Ordering::Equal)
+                        })
+                }
+            })
+        });
+        let lights = match lights {
+            LightMode::First => &self.lights[..self.lights.len().min(1)],
+            LightMode::Rest => &self.lights[self.lights.len().min(1)..],
+            LightMode::Each => &self.lights[..],
+        };
 
-                for mesh in &self.meshes {
-                    render_pass.set_bind_group(1, mesh.bind_group.as_ref().unwrap(), &[]);
+        // the skybox may have been drawn in between, it binds other groups
+        render_pass.set_bind_group(4, &self.skybox.bind_group, &[]);
+        render_pass.set_bind_group(3, self.camera.bind_group.as_ref().unwrap(), &[]);
+        for light in lights {
+            render_pass.set_bind_group(2, light.bind_group.as_ref().unwrap(), &[]);
+
+            let mut current_pool = None;
+            let mut current_material = None;
+            let mut current_pipeline: Option<&wgpu::RenderPipeline> = None;
+            for (draw, _, pipeline) in &selected {
+                if current_material != Some(&draw.mesh.material) {
+                    current_material = Some(&draw.mesh.material);
+                    render_pass.set_bind_group(0, draw.material.bind_group.as_ref().unwrap(), &[]);
+                }
+                if !current_pipeline.is_some_and(|p| std::ptr::eq(p, *pipeline)) {
+                    render_pass.set_pipeline(pipeline);
+                    current_pipeline = Some(pipeline);
+                }
+                let allocation = &draw.allocation;
+                if current_pool != Some(allocation.pool) {
+                    let pool = &self.geometry.pools[allocation.pool];
                     render_pass
-                        .set_vertex_buffer(0, mesh.vertex_buffer.as_ref().unwrap().slice(..));
+                        .set_vertex_buffer(0, pool.vertex_buffer.as_ref().unwrap().slice(..));
                     render_pass.set_index_buffer(
-                        mesh.index_buffer.as_ref().unwrap().slice(..),
+                        pool.index_buffer.as_ref().unwrap().slice(..),
                         wgpu::IndexFormat::Uint32,
                     );
-                    if let Some(material) = self.materials.get(&mesh.material) {
-                        render_pass.set_bind_group(0, material.bind_group.as_ref().unwrap(), &[]);
-                        let pipeline_name = format!("{}-{}", &material.shader, sub_shader_tag);
-                        if let Some(pipeline) =
-                            self.graphics_state.render_pipelines.get(&pipeline_name)
-                        {
-                            render_pass.set_pipeline(pipeline);
-                            render_pass.draw_indexed(0..mesh.index_count(), 0, 0..1);
-                        }
-                    }
+                    current_pool = Some(allocation.pool);
                 }
+                render_pass.set_bind_group(1, object_bind_group, &[allocation.object_offset]);
+                let first_index = allocation.first_index + draw.lod.first_index;
+                render_pass.draw_indexed(
+                    first_index..first_index + draw.lod.index_count,
+                    allocation.base_vertex,
+                    0..1,
+                );
             }
-            self.draw_skybox(&mut render_pass);
         }
-        self.graphics_state
-            .queue
-            .submit(std::iter::once(encoder.finish()));
-        Ok(())
+    }
+
+    fn select_lod<'a>(&self, mesh: &'a Mesh) -> Option<&'a MeshLod> {
+        let (center, radius) = mesh.world_bounding_sphere();
+        let radius_px = self
+            .camera
+            .projected_radius(center, radius, self.window_size.height);
+        let screen_area = (self.window_size.width * self.window_size.height).max(1) as f32;
+        let screen_coverage = std::f32::consts::PI * radius_px * radius_px / screen_area;
+        mesh.select_lod(radius_px, screen_coverage, self.lod_pixel_error)
+    }
+
+    fn draw_deferred_lighting<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        let gbuffer_bind_group = match &self.graphics_state.gbuffer_bind_group {
+            Some(bind_group) => bind_group,
+            None => return,
+        };
+        let light = match self.lights.first() {
+            Some(light) => light,
+            None => return,
+        };
+        render_pass.set_pipeline(&self.graphics_state.render_pipelines["DeferredLighting"]);
+        render_pass.set_bind_group(0, gbuffer_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.graphics_state.empty_bind_group, &[]);
+        // any light binds the clusters
+        render_pass.set_bind_group(2, light.bind_group.as_ref().unwrap(), &[]);
+        render_pass.set_bind_group(3, self.camera.bind_group.as_ref().unwrap(), &[]);
+        render_pass.set_bind_group(4, &self.skybox.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
     }
 
     fn draw_skybox<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
@@ -435,6 +1009,19 @@ impl Engine {
     }
 
     pub fn generate_mipmap(&self, texture: &Texture) {
+        let mut graph = RenderGraph::new("Mipmap");
+        graph.import("_Texture");
+        self.add_mipmap_pass(&mut graph, "_Texture", texture);
+        graph.execute(&self.graphics_state);
+    }
+
+    // Blits each mip level of the layers of the imported `name` from the one above it.
+    pub(crate) fn add_mipmap_pass<'a>(
+        &'a self,
+        graph: &mut RenderGraph<'a>,
+        name: &str,
+        texture: &'a Texture,
+    ) {
         let mipmap_level_count = {
             let layer_size = wgpu::Extent3d {
                 depth: 1,
@@ -446,80 +1033,91 @@ impl Engine {
 
         let pipeline_name = format!("Blit-{:?}", texture.format);
 
-        let mut encoder =
-            self.graphics_state
-                .device
-                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                    label: Some("Render Encoder - Mipmap"),
-                });
-        for i in 0..layer_count {
-            let mut last_view = texture.texture.create_view(&wgpu::TextureViewDescriptor {
-                dimension: Some(wgpu::TextureViewDimension::D2),
-                base_array_layer: i,
-                array_layer_count: std::num::NonZeroU32::new(1),
-                base_mip_level: 0,
-                level_count: std::num::NonZeroU32::new(1),
-                ..Default::default()
-            });
-            for j in 1..mipmap_level_count {
-                let view = texture.texture.create_view(&wgpu::TextureViewDescriptor {
-                    dimension: Some(wgpu::TextureViewDimension::D2),
-                    base_array_layer: i,
-                    array_layer_count: std::num::NonZeroU32::new(1),
-                    base_mip_level: j,
-                    level_count: std::num::NonZeroU32::new(1),
-                    ..Default::default()
-                });
-
-                let bind_group =
-                    self.graphics_state
-                        .device
-                        .create_bind_group(&wgpu::BindGroupDescriptor {
-                            label: Some("Mipmap Bind Group"),
-                            layout: &self.graphics_state.bind_group_layouts["_Blit"],
-                            entries: &[
-                                wgpu::BindGroupEntry {
-                                    binding: 0,
-                                    resource: wgpu::BindingResource::TextureView(&last_view),
-                                },
-                                wgpu::BindGroupEntry {
-                                    binding: 1,
-                                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
-                                },
-                            ],
-                        });
-                {
-                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                        label: Some("Render Pass - Mipmap"),
-                        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                            attachment: &view,
-                            resolve_target: None,
-                            ops: wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                                store: true,
-                            },
-                        }],
-                        depth_stencil_attachment: None,
+        graph.add_pass(
+            &format!("{} Mipmap", name),
+            &[name],
+            &[name],
+            move |encoder, _| {
+                for i in 0..layer_count {
+                    let mut last_view = texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_array_layer: i,
+                        array_layer_count: std::num::NonZeroU32::new(1),
+                        base_mip_level: 0,
+                        level_count: std::num::NonZeroU32::new(1),
+                        ..Default::default()
                     });
-                    render_pass.set_pipeline(&self.graphics_state.render_pipelines[&pipeline_name]);
-                    render_pass.set_bind_group(0, &bind_group, &[]);
-                    render_pass.draw(0..3, 0..1);
-                }
+                    for j in 1..mipmap_level_count {
+                        let view = texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                            dimension: Some(wgpu::TextureViewDimension::D2),
+                            base_array_layer: i,
+                            array_layer_count: std::num::NonZeroU32::new(1),
+                            base_mip_level: j,
+                            level_count: std::num::NonZeroU32::new(1),
+                            ..Default::default()
+                        });
 
-                last_view = view;
-            }
-        }
+                        let bind_group = self.graphics_state.device.create_bind_group(
+                            &wgpu::BindGroupDescriptor {
+                                label: Some("Mipmap Bind Group"),
+                                layout: &self.graphics_state.bind_group_layouts["_Blit"],
+                                entries: &[
+                                    wgpu::BindGroupEntry {
+                                        binding: 0,
+                                        resource: wgpu::BindingResource::TextureView(&last_view),
+                                    },
+                                    wgpu::BindGroupEntry {
+                                        binding: 1,
+                                        resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                                    },
+                                ],
+                            },
+                        );
+                        {
+                            let mut render_pass =
+                                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                                    label: Some("Render Pass - Mipmap"),
+                                    color_attachments: &[
+                                        wgpu::RenderPassColorAttachmentDescriptor {
+                                            attachment: &view,
+                                            resolve_target: None,
+                                            ops: wgpu::Operations {
+                                                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                                                store: true,
+                                            },
+                                        },
+                                    ],
+                                    depth_stencil_attachment: None,
+                                });
+                            render_pass.set_pipeline(
+                                &self.graphics_state.render_pipelines[&pipeline_name],
+                            );
+                            render_pass.set_bind_group(0, &bind_group, &[]);
+                            render_pass.draw(0..3, 0..1);
+                        }
 
-        self.graphics_state
-            .queue
-            .submit(std::iter::once(encoder.finish()));
+                        last_view = view;
+                    }
+                }
+            },
+        );
     }
 
+    // One submission for all of them.
     pub fn generate_all_mipmaps(&self) {
-        for (_, mat) in &self.materials {
-            for (_, tex) in &mat.textures {
-                self.generate_mipmap(tex);
+        let mut graph = RenderGraph::new("Mipmaps");
+        for (material_name, mat) in &self.materials {
+            for (texture_name, tex) in &mat.textures {
+                let name = format!("{}.{}", material_name, texture_name);
+                graph.import(&name);
+                self.add_mipmap_pass(&mut graph, &name, tex);
             }
         }
+        graph.execute(&self.graphics_state);
     }
 }
+
+// The transient texture a multisampled target is rendered into.
+fn msaa_target(name: &str) -> String {
+    format!("{}_MSAA", name)
+}
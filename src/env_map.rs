@@ -1,4 +1,7 @@
+use wgpu::util::DeviceExt;
+
 use crate::engine::Engine;
+use crate::render_graph::RenderGraph;
 use crate::texture::Texture;
 
 pub struct EnvMap {
@@ -141,75 +144,6 @@ impl Engine {
                 ],
             });
 
-        let pre_calc_uniform_buffer =
-            self.graphics_state
-                .device
-                .create_buffer(&wgpu::BufferDescriptor {
-                    label: Some("EnvMap Pre-Calc Uniform Buffer"),
-                    size: 4,
-                    usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-                    mapped_at_creation: false,
-                });
-        let pre_calc_bind_group =
-            self.graphics_state
-                .device
-                .create_bind_group(&wgpu::BindGroupDescriptor {
-                    label: Some("EnvMap Pre-Calc Bind Group"),
-                    layout: &self.graphics_state.bind_group_layouts["_EnvMap"],
-                    entries: &[
-                        wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: wgpu::BindingResource::TextureView(&cubemap.view),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 1,
-                            resource: wgpu::BindingResource::Sampler(&cubemap.sampler),
-                        },
-                        wgpu::BindGroupEntry {
-                            binding: 2,
-                            resource: pre_calc_uniform_buffer.as_entire_binding(),
-                        },
-                    ],
-                });
-        self.generate_mipmap(&cubemap);
-
-        let mut encoder =
-            self.graphics_state
-                .device
-                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                    label: Some("Render Encoder - EnvMap - Irradiance"),
-                });
-        for i in 0..6 {
-            let view = irradiance
-                .texture
-                .create_view(&wgpu::TextureViewDescriptor {
-                    dimension: Some(wgpu::TextureViewDimension::D2),
-                    base_array_layer: i,
-                    array_layer_count: std::num::NonZeroU32::new(1),
-                    ..Default::default()
-                });
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass - EnvMap - Irradiance"),
-                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: None,
-            });
-            render_pass.set_pipeline(&self.graphics_state.render_pipelines["EnvMap-Irradiance"]);
-            render_pass.set_bind_group(1, &pre_calc_bind_group, &[]);
-            render_pass.set_bind_group(0, self.skybox_camera.get_bind_group(i as usize), &[]);
-            render_pass.draw(0..3, 0..1);
-        }
-        self.graphics_state
-            .queue
-            .submit(std::iter::once(encoder.finish()));
-        self.generate_mipmap(&irradiance);
-
         let mipmap_level_count = {
             let layer_size = wgpu::Extent3d {
                 depth: 1,
@@ -217,53 +151,132 @@ impl Engine {
             };
             layer_size.max_mips() as u32
         };
-        for j in 0..mipmap_level_count {
-            let mut encoder = self.graphics_state.device.create_command_encoder(
-                &wgpu::CommandEncoderDescriptor {
-                    label: Some("Render Encoder - EnvMap - Prefilter"),
-                },
-            );
+        // the roughness each mip level of the prefiltered map is convolved with, all of them are
+        // recorded before the submission
+        let pre_calc_uniforms: Vec<_> = (0..mipmap_level_count)
+            .map(|j| {
+                let roughness = (j as f32 / 6.0).min(1.0);
+                let uniform_buffer = self.graphics_state.device.create_buffer_init(
+                    &wgpu::util::BufferInitDescriptor {
+                        label: Some("EnvMap Pre-Calc Uniform Buffer"),
+                        contents: bytemuck::cast_slice(&[roughness]),
+                        usage: wgpu::BufferUsage::UNIFORM,
+                    },
+                );
+                let bind_group =
+                    self.graphics_state
+                        .device
+                        .create_bind_group(&wgpu::BindGroupDescriptor {
+                            label: Some("EnvMap Pre-Calc Bind Group"),
+                            layout: &self.graphics_state.bind_group_layouts["_EnvMap"],
+                            entries: &[
+                                wgpu::BindGroupEntry {
+                                    binding: 0,
+                                    resource: wgpu::BindingResource::TextureView(&cubemap.view),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 1,
+                                    resource: wgpu::BindingResource::Sampler(&cubemap.sampler),
+                                },
+                                wgpu::BindGroupEntry {
+                                    binding: 2,
+                                    resource: uniform_buffer.as_entire_binding(),
+                                },
+                            ],
+                        });
+                (uniform_buffer, bind_group)
+            })
+            .collect();
 
-            let roughness = (j as f32 / 6.0).min(1.0);
-            self.graphics_state.queue.write_buffer(
-                &pre_calc_uniform_buffer,
-                0,
-                bytemuck::cast_slice(&[roughness]),
-            );
-            for i in 0..6 {
-                let view = prefiltered
-                    .texture
-                    .create_view(&wgpu::TextureViewDescriptor {
-                        dimension: Some(wgpu::TextureViewDimension::D2),
-                        base_array_layer: i,
-                        array_layer_count: std::num::NonZeroU32::new(1),
-                        base_mip_level: j,
-                        level_count: std::num::NonZeroU32::new(1),
-                        ..Default::default()
+        let mut graph = RenderGraph::new("EnvMap");
+        graph.import("_Cubemap");
+        graph.import("_Irradiance");
+        graph.import("_Prefiltered");
+        self.add_mipmap_pass(&mut graph, "_Cubemap", &cubemap);
+        graph.add_pass(
+            "EnvMap Irradiance",
+            &["_Cubemap"],
+            &["_Irradiance"],
+            |encoder, _| {
+                for i in 0..6 {
+                    let view = irradiance
+                        .texture
+                        .create_view(&wgpu::TextureViewDescriptor {
+                            dimension: Some(wgpu::TextureViewDimension::D2),
+                            base_array_layer: i,
+                            array_layer_count: std::num::NonZeroU32::new(1),
+                            ..Default::default()
+                        });
+                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        label: Some("Render Pass - EnvMap - Irradiance"),
+                        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                            attachment: &view,
+                            resolve_target: None,
+                            ops: wgpu::Operations {
+                                load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                                store: true,
+                            },
+                        }],
+                        depth_stencil_attachment: None,
                     });
-                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("Render Pass - EnvMap - Prefilter"),
-                    color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                        attachment: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                            store: true,
-                        },
-                    }],
-                    depth_stencil_attachment: None,
-                });
-                render_pass.set_pipeline(&self.graphics_state.render_pipelines["EnvMap-Prefilter"]);
-                render_pass.set_bind_group(1, &pre_calc_bind_group, &[]);
-                render_pass.set_bind_group(0, self.skybox_camera.get_bind_group(i as usize), &[]);
-                render_pass.draw(0..3, 0..1);
-            }
-
-            // I have to submit per mipmap level since 'roughness' is changed
-            self.graphics_state
-                .queue
-                .submit(std::iter::once(encoder.finish()));
-        }
+                    render_pass
+                        .set_pipeline(&self.graphics_state.render_pipelines["EnvMap-Irradiance"]);
+                    render_pass.set_bind_group(1, &pre_calc_uniforms[0].1, &[]);
+                    render_pass.set_bind_group(
+                        0,
+                        self.skybox_camera.get_bind_group(i as usize),
+                        &[],
+                    );
+                    render_pass.draw(0..3, 0..1);
+                }
+            },
+        );
+        self.add_mipmap_pass(&mut graph, "_Irradiance", &irradiance);
+        graph.add_pass(
+            "EnvMap Prefilter",
+            &["_Cubemap"],
+            &["_Prefiltered"],
+            |encoder, _| {
+                for (j, (_, pre_calc_bind_group)) in pre_calc_uniforms.iter().enumerate() {
+                    for i in 0..6 {
+                        let view = prefiltered
+                            .texture
+                            .create_view(&wgpu::TextureViewDescriptor {
+                                dimension: Some(wgpu::TextureViewDimension::D2),
+                                base_array_layer: i,
+                                array_layer_count: std::num::NonZeroU32::new(1),
+                                base_mip_level: j as u32,
+                                level_count: std::num::NonZeroU32::new(1),
+                                ..Default::default()
+                            });
+                        let mut render_pass =
+                            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                                label: Some("Render Pass - EnvMap - Prefilter"),
+                                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                                    attachment: &view,
+                                    resolve_target: None,
+                                    ops: wgpu::Operations {
+                                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                                        store: true,
+                                    },
+                                }],
+                                depth_stencil_attachment: None,
+                            });
+                        render_pass.set_pipeline(
+                            &self.graphics_state.render_pipelines["EnvMap-Prefilter"],
+                        );
+                        render_pass.set_bind_group(1, pre_calc_bind_group, &[]);
+                        render_pass.set_bind_group(
+                            0,
+                            self.skybox_camera.get_bind_group(i as usize),
+                            &[],
+                        );
+                        render_pass.draw(0..3, 0..1);
+                    }
+                }
+            },
+        );
+        graph.execute(&self.graphics_state);
 
         EnvMap {
             cubemap,
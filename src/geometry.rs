@@ -0,0 +1,142 @@
+use wgpu::util::DeviceExt;
+
+use crate::mesh::MeshUniform;
+use crate::vertex::{MeshVertex, VertexLayout};
+
+// Sub-allocates every mesh from a few shared buffers: one vertex and one index buffer per vertex
+// layout, plus a single uniform buffer holding all object data, addressed with dynamic offsets.
+// Draws only rebind buffers when the layout changes.
+pub struct GeometryArena {
+    pub pools: Vec<GeometryPool>,
+    object_data: Vec<u8>,
+    object_buffer: Option<wgpu::Buffer>,
+    pub object_bind_group: Option<wgpu::BindGroup>,
+}
+
+pub struct GeometryPool {
+    vertex_layout: VertexLayout,
+    vertex_data: Vec<u8>,
+    vertex_count: u32,
+    indices: Vec<u32>,
+    pub vertex_buffer: Option<wgpu::Buffer>,
+    pub index_buffer: Option<wgpu::Buffer>,
+}
+
+// Where a mesh lives in the arena. Mesh indices stay relative to the mesh's own vertices, so
+// draws add `first_index` to the LOD range and pass `base_vertex`.
+#[derive(Copy, Clone, Debug)]
+pub struct MeshAllocation {
+    pub pool: usize,
+    pub base_vertex: i32,
+    pub first_index: u32,
+    pub object_offset: wgpu::DynamicOffset,
+}
+
+impl GeometryArena {
+    // every object slot has to start at a multiple of the dynamic offset alignment
+    const OBJECT_SLOT_SIZE: usize = wgpu::BIND_BUFFER_ALIGNMENT as usize;
+
+    pub fn new() -> Self {
+        Self {
+            pools: vec![],
+            object_data: vec![],
+            object_buffer: None,
+            object_bind_group: None,
+        }
+    }
+
+    pub fn object_binding_size() -> wgpu::BufferSize {
+        wgpu::BufferSize::new(std::mem::size_of::<MeshUniform>() as wgpu::BufferAddress).unwrap()
+    }
+
+    pub fn allocate(
+        &mut self,
+        vertices: &[MeshVertex],
+        indices: &[u32],
+        uniform: &MeshUniform,
+        vertex_layout: &VertexLayout,
+    ) -> MeshAllocation {
+        let pool_index = match self
+            .pools
+            .iter()
+            .position(|pool| &pool.vertex_layout == vertex_layout)
+        {
+            Some(pool_index) => pool_index,
+            None => {
+                self.pools.push(GeometryPool {
+                    vertex_layout: vertex_layout.clone(),
+                    vertex_data: vec![],
+                    vertex_count: 0,
+                    indices: vec![],
+                    vertex_buffer: None,
+                    index_buffer: None,
+                });
+                self.pools.len() - 1
+            }
+        };
+        let pool = &mut self.pools[pool_index];
+
+        let base_vertex = pool.vertex_count as i32;
+        let first_index = pool.indices.len() as u32;
+        pool.vertex_data.extend(pool.vertex_layout.pack(vertices));
+        pool.vertex_count += vertices.len() as u32;
+        pool.indices.extend_from_slice(indices);
+
+        let object_offset = self.object_data.len();
+        self.object_data
+            .extend_from_slice(bytemuck::bytes_of(uniform));
+        self.object_data
+            .resize(object_offset + Self::OBJECT_SLOT_SIZE, 0);
+
+        MeshAllocation {
+            pool: pool_index,
+            base_vertex,
+            first_index,
+            object_offset: object_offset as wgpu::DynamicOffset,
+        }
+    }
+
+    // (Re)creates the GPU buffers from everything allocated so far, call after loading a batch
+    // of meshes.
+    pub fn build(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout) {
+        for pool in &mut self.pools {
+            pool.vertex_buffer = Some(device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Geometry Vertex Buffer"),
+                    contents: &pool.vertex_data,
+                    usage: wgpu::BufferUsage::VERTEX,
+                },
+            ));
+            pool.index_buffer = Some(device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Geometry Index Buffer"),
+                    contents: bytemuck::cast_slice(&pool.indices),
+                    usage: wgpu::BufferUsage::INDEX,
+                },
+            ));
+        }
+
+        if self.object_data.is_empty() {
+            return;
+        }
+        self.object_buffer = Some(
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Geometry Object Buffer"),
+                contents: &self.object_data,
+                usage: wgpu::BufferUsage::UNIFORM,
+            }),
+        );
+        self.object_bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Geometry Object Bind Group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer {
+                    buffer: self.object_buffer.as_ref().unwrap(),
+                    offset: 0,
+                    size: Some(Self::object_binding_size()),
+                },
+            }],
+        }));
+    }
+}
@@ -4,25 +4,59 @@ use cgmath::SquareMatrix;
 
 use crate::engine::Engine;
 use crate::material::Material;
-use crate::mesh::Mesh;
-use crate::vertex::MeshVertex;
+use crate::mesh::{LodThreshold, Mesh};
+use crate::mesh_processing::normals;
+use crate::shader::render_queue;
+use crate::vertex::{MeshVertex, VertexLayout};
 
 pub(crate) struct GltfScene {
     gltf_document: gltf::Document,
     buffers: Vec<gltf::buffer::Data>,
     images: Vec<gltf::image::Data>,
+    // the gltf crate drops extensions it doesn't know (e.g. MSFT_lod), so keep the raw json around
+    json: serde_json::Value,
 }
 
 impl GltfScene {
     fn import<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let json = if bytes.starts_with(b"glTF") {
+            serde_json::from_slice(&gltf::Glb::from_slice(&bytes)?.json)?
+        } else {
+            serde_json::from_slice(&bytes)?
+        };
         let (gltf_document, buffers, images) = gltf::import(path)?;
         Ok(Self {
             gltf_document,
             buffers,
             images,
+            json,
         })
     }
 
+    // LOD nodes (coarser first) and screen coverages (including the node itself) from MSFT_lod
+    fn msft_lod<'a>(&'a self, node: &gltf::Node) -> Option<(Vec<gltf::Node<'a>>, Vec<f32>)> {
+        let node_json = &self.json["nodes"][node.index()];
+        let ids = node_json["extensions"]["MSFT_lod"]["ids"].as_array()?;
+        let lod_nodes = ids
+            .iter()
+            .filter_map(|id| {
+                let id = id.as_u64()? as usize;
+                self.gltf_document.nodes().nth(id)
+            })
+            .collect();
+        let coverages = node_json["extras"]["MSFT_screencoverage"]
+            .as_array()
+            .map(|coverages| {
+                coverages
+                    .iter()
+                    .filter_map(|c| c.as_f64().map(|c| c as f32))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some((lod_nodes, coverages))
+    }
+
     fn data_of_accessor<'a>(&'a self, accessor: &gltf::Accessor<'a>) -> Result<&'a [u8]> {
         let buffer_view = accessor.view().context("Accessor has no buffer view")?;
         let buffer = buffer_view.buffer();
@@ -39,19 +73,65 @@ impl Engine {
     pub fn load_gltf<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
         let gltf_scene = GltfScene::import(path)?;
 
-        self.parse_gltf_materials(&gltf_scene);
+        self.parse_gltf_materials(&gltf_scene)?;
+        self.build_material_pipelines()?;
 
         self.meshes.reserve(gltf_scene.gltf_document.meshes().len());
+        let first_new_mesh = self.meshes.len();
         for s in gltf_scene.gltf_document.scenes() {
             for node in s.nodes() {
                 self.parse_gltf_node(&node, &gltf_scene, cgmath::Matrix4::identity())?;
             }
         }
 
+        if self.optimize_meshes {
+            self.optimize_gltf_meshes(first_new_mesh);
+        }
+        let default_layout = VertexLayout::default();
+        let shaders = &self.shaders;
+        for mesh in &mut self.meshes[first_new_mesh..] {
+            // only upload the attributes the material's shader consumes
+            let vertex_layout = self
+                .materials
+                .get(&mesh.material)
+                .and_then(|material| shaders.get(&material.shader))
+                .map_or(&default_layout, |shader| &shader.vertex_layout);
+            mesh.build(&mut self.geometry, vertex_layout);
+        }
+        self.geometry.build(
+            &self.graphics_state.device,
+            &self.graphics_state.bind_group_layouts["_Object"],
+        );
+
         Ok(())
     }
 
-    fn parse_gltf_materials(&mut self, gltf_scene: &GltfScene) {
+    fn optimize_gltf_meshes(&mut self, first_mesh: usize) {
+        let mut triangles = 0.0;
+        let mut acmr = (0.0, 0.0);
+        let mut atvr = (0.0, 0.0);
+        for mesh in &mut self.meshes[first_mesh..] {
+            let (before, after) = mesh.optimize();
+            let weight = mesh.triangle_count() as f32;
+            triangles += weight;
+            acmr.0 += before.acmr * weight;
+            acmr.1 += after.acmr * weight;
+            atvr.0 += before.atvr * weight;
+            atvr.1 += after.atvr * weight;
+        }
+        if triangles > 0.0 {
+            println!(
+                "Optimized {} triangles: ACMR {:.3} -> {:.3}, ATVR {:.3} -> {:.3}",
+                triangles,
+                acmr.0 / triangles,
+                acmr.1 / triangles,
+                atvr.0 / triangles,
+                atvr.1 / triangles,
+            );
+        }
+    }
+
+    fn parse_gltf_materials(&mut self, gltf_scene: &GltfScene) -> Result<()> {
         for mat in gltf_scene.gltf_document.materials() {
             let gltf_material_name = mat.name().unwrap();
             if self.materials.get(gltf_material_name).is_none() {
@@ -81,6 +161,7 @@ impl Engine {
                                 &info.texture(),
                                 true,
                                 gltf_scene,
+                                shader.gltf_sampler_override("base_color_tex"),
                             ),
                         );
                     }
@@ -96,6 +177,7 @@ impl Engine {
                                 &info.texture(),
                                 false,
                                 gltf_scene,
+                                shader.gltf_sampler_override("metallic_roughness_tex"),
                             ),
                         );
                     }
@@ -108,10 +190,24 @@ impl Engine {
                                 &info.texture(),
                                 true,
                                 gltf_scene,
+                                shader.gltf_sampler_override("emissive_tex"),
                             ),
                         );
                     }
+                    match mat.alpha_mode() {
+                        gltf::material::AlphaMode::Opaque => {}
+                        gltf::material::AlphaMode::Mask => {
+                            material.enable_keyword("ALPHA_TEST");
+                            material.set_float("alpha_cutoff", mat.alpha_cutoff());
+                        }
+                        // kept out of the G-buffer and the depth pre-pass, drawn after the skybox
+                        gltf::material::AlphaMode::Blend => {
+                            material.enable_keyword("ALPHA_BLEND");
+                            material.queue = Some(render_queue::TRANSPARENT);
+                        }
+                    }
                     if let Some(info) = mat.normal_texture() {
+                        material.enable_keyword("NORMAL_MAP");
                         material.set_texture(
                             "normal_tex",
                             util::gltf_texture_to_wgpu_texture(
@@ -120,6 +216,7 @@ impl Engine {
                                 &info.texture(),
                                 false,
                                 gltf_scene,
+                                shader.gltf_sampler_override("normal_tex"),
                             ),
                         );
                     }
@@ -130,7 +227,13 @@ impl Engine {
                     );
                 }
             }
+            // values of material files win over the ones of the glTF file
+            if self.material_definitions.contains_key(gltf_material_name) {
+                self.reapply_material_definition(gltf_material_name)
+                    .with_context(|| format!("Can't apply material '{}'", gltf_material_name))?;
+            }
         }
+        Ok(())
     }
 
     fn parse_gltf_node(
@@ -143,21 +246,51 @@ impl Engine {
         let transform = transform * curr_trans;
 
         if let Some(mesh) = node.mesh() {
-            for prim in mesh.primitives() {
-                let (vertices, calc_tangents) = self.parse_gltf_vertices(gltf_scene, &prim)?;
-                let indices = self.parse_gltf_indices(gltf_scene, &prim)?;
+            let msft_lod = gltf_scene.msft_lod(node);
+            for (prim_index, prim) in mesh.primitives().enumerate() {
+                let (mut vertices, indices, calc_tangents) =
+                    self.parse_gltf_primitive(gltf_scene, &prim)?;
+                if calc_tangents {
+                    Mesh::calc_tangents(&mut vertices, &indices);
+                }
 
                 // material
                 let material = prim.material().name();
                 if material.is_some() && self.materials.get(material.unwrap()).is_some() {
                     let material = material.unwrap();
                     let mut mesh = Mesh::new(vertices, indices, transform, material.to_string());
-                    mesh.build(
-                        &self.graphics_state.device,
-                        &self.graphics_state.bind_group_layouts["_Object"],
-                    );
-                    if calc_tangents {
-                        mesh.calc_tangents();
+                    if let Some((lod_nodes, coverages)) = &msft_lod {
+                        let coverage = |level: usize| coverages.get(level).cloned().unwrap_or(0.0);
+                        mesh.lods[0].threshold = LodThreshold::ScreenCoverage(coverage(0));
+                        for (level, lod_node) in lod_nodes.iter().enumerate() {
+                            let lod_prim = lod_node
+                                .mesh()
+                                .and_then(|lod_mesh| lod_mesh.primitives().nth(prim_index));
+                            if let Some(lod_prim) = lod_prim {
+                                let (mut lod_vertices, lod_indices, lod_calc_tangents) =
+                                    self.parse_gltf_primitive(gltf_scene, &lod_prim)?;
+                                if lod_calc_tangents {
+                                    Mesh::calc_tangents(&mut lod_vertices, &lod_indices);
+                                }
+                                // LOD nodes replace this node, so bring them into its space
+                                let lod_trans: cgmath::Matrix4<f32> =
+                                    lod_node.transform().matrix().into();
+                                if let Some(curr_trans_inv) = curr_trans.invert() {
+                                    util::transform_vertices(
+                                        &mut lod_vertices,
+                                        curr_trans_inv * lod_trans,
+                                    );
+                                }
+                                mesh.add_lod_with_vertices(
+                                    lod_vertices,
+                                    lod_indices,
+                                    LodThreshold::ScreenCoverage(coverage(level + 1)),
+                                );
+                            }
+                        }
+                    }
+                    if let (None, Some(lod_options)) = (&msft_lod, &self.lod_options) {
+                        mesh.generate_lods(lod_options);
                     }
                     self.meshes.push(mesh);
                 } else {
@@ -166,6 +299,10 @@ impl Engine {
             }
         }
 
+        if let Some(light) = node.light() {
+            self.add_light(util::gltf_light_to_light(&light, transform));
+        }
+
         for ch in node.children() {
             self.parse_gltf_node(&ch, gltf_scene, transform)?;
         }
@@ -173,11 +310,26 @@ impl Engine {
         Ok(())
     }
 
+    fn parse_gltf_primitive(
+        &mut self,
+        gltf_scene: &GltfScene,
+        prim: &gltf::Primitive,
+    ) -> Result<(Vec<MeshVertex>, Vec<u32>, bool)> {
+        let (vertices, calc_normals, calc_tangents) = self.parse_gltf_vertices(gltf_scene, prim)?;
+        let indices = self.parse_gltf_indices(gltf_scene, prim)?;
+        let (vertices, indices) = if calc_normals {
+            normals::generate_normals(&vertices, &indices, self.normal_mode)
+        } else {
+            (vertices, indices)
+        };
+        Ok((vertices, indices, calc_tangents))
+    }
+
     fn parse_gltf_vertices(
         &mut self,
         gltf_scene: &GltfScene,
         prim: &gltf::Primitive,
-    ) -> Result<(Vec<MeshVertex>, bool)> {
+    ) -> Result<(Vec<MeshVertex>, bool, bool)> {
         // vertices
         let position_accessor = prim
             .get(&gltf::mesh::Semantic::Positions)
@@ -218,6 +370,7 @@ impl Engine {
                 }
             }
         });
+        let need_to_calc_normals = prim.get(&gltf::mesh::Semantic::Normals).is_none();
         // tangent
         prim.get(&gltf::mesh::Semantic::Tangents).map(|accessor| {
             if let Ok(data) = gltf_scene.data_of_accessor(&accessor) {
@@ -254,7 +407,7 @@ impl Engine {
                 }
             }
         });
-        Ok((vertices, need_to_calc_tangents))
+        Ok((vertices, need_to_calc_normals, need_to_calc_tangents))
     }
 
     fn parse_gltf_indices(
@@ -283,8 +436,12 @@ impl Engine {
 
 mod util {
     use crate::gltf_scene::GltfScene;
+    use crate::light::Light;
     use crate::texture::Texture;
+    use crate::vertex::MeshVertex;
+    use cgmath::prelude::*;
     use gltf::image::Format;
+    use gltf::khr_lights_punctual::Kind;
     use gltf::texture::{MagFilter, MinFilter, WrappingMode};
 
     pub(crate) fn gltf_texture_to_wgpu_texture(
@@ -293,8 +450,12 @@ mod util {
         tex: &gltf::texture::Texture,
         is_srgb: bool,
         gltf_scene: &GltfScene,
+        sampler_override: Option<&wgpu::SamplerDescriptor>,
     ) -> Texture {
         let image_data = &gltf_scene.images[tex.index()];
+        let sampler = sampler_override
+            .cloned()
+            .unwrap_or_else(|| gltf_sampler_to_wgpu_sampler(&tex.sampler()));
         let image_size = image_data.width as usize * image_data.height as usize;
         match image_data.format {
             gltf::image::Format::R8G8B8 | gltf::image::Format::B8G8R8 => {
@@ -307,7 +468,7 @@ mod util {
                     image_data.height,
                     gltf_format_to_wgpu_format(image_data.format, is_srgb),
                     true,
-                    &gltf_sampler_to_wgpu_sampler(&tex.sampler()),
+                    &sampler,
                     Some("glTF Texture 2D"),
                 )
             }
@@ -321,7 +482,7 @@ mod util {
                     image_data.height,
                     gltf_format_to_wgpu_format(image_data.format, is_srgb),
                     true,
-                    &gltf_sampler_to_wgpu_sampler(&tex.sampler()),
+                    &sampler,
                     Some("glTF Texture 2D"),
                 )
             }
@@ -333,12 +494,36 @@ mod util {
                 image_data.height,
                 gltf_format_to_wgpu_format(image_data.format, is_srgb),
                 true,
-                &gltf_sampler_to_wgpu_sampler(&tex.sampler()),
+                &sampler,
                 Some("glTF Texture 2D"),
             ),
         }
     }
 
+    pub(crate) fn transform_vertices(vertices: &mut [MeshVertex], transform: cgmath::Matrix4<f32>) {
+        let normal_transform = transform
+            .invert()
+            .map(|inv| inv.transpose())
+            .unwrap_or(transform);
+        for v in vertices {
+            v.position = transform
+                .transform_point(cgmath::Point3::from(v.position))
+                .into();
+            v.normal = normal_transform
+                .transform_vector(cgmath::Vector3::from(v.normal))
+                .normalize()
+                .into();
+            let tangent = transform
+                .transform_vector(cgmath::Vector3::new(
+                    v.tangent[0],
+                    v.tangent[1],
+                    v.tangent[2],
+                ))
+                .normalize();
+            v.tangent = [tangent.x, tangent.y, tangent.z, v.tangent[3]];
+        }
+    }
+
     pub(crate) fn rgb8_to_rgba8(orig_data: &[u8], size: usize) -> Vec<u8> {
         let mut data = vec![0; 4 * size];
         for i in 0..size {
@@ -392,6 +577,33 @@ mod util {
         }
     }
 
+    // KHR_lights_punctual lights shine down the node's -z
+    pub(crate) fn gltf_light_to_light(
+        light: &gltf::khr_lights_punctual::Light,
+        transform: cgmath::Matrix4<f32>,
+    ) -> Light {
+        let [r, g, b] = light.color();
+        let intensity = light.intensity();
+        let color = [r * intensity, g * intensity, b * intensity, 1.0];
+        let position = transform.transform_point(cgmath::Point3::origin());
+        let direction = transform.transform_vector(-cgmath::Vector3::unit_z());
+        match light.kind() {
+            Kind::Directional => Light::directional_light(direction, color),
+            Kind::Point => Light::point_light(position, color, light.range()),
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => Light::spot_light(
+                position,
+                direction,
+                color,
+                light.range(),
+                inner_cone_angle,
+                outer_cone_angle,
+            ),
+        }
+    }
+
     pub(crate) fn gltf_sampler_to_wgpu_sampler<'a>(
         gltf_sampler: &gltf::texture::Sampler,
     ) -> wgpu::SamplerDescriptor<'a> {
@@ -1,16 +1,47 @@
+use crate::geometry::GeometryArena;
+use crate::render_graph::TransientTextures;
+use crate::shader::Shader;
 use crate::texture::Texture;
 use anyhow::*;
+use std::cell::RefCell;
 use std::collections::HashMap;
 
+// What "GBuffer" sub shaders write for the deferred lighting pass: albedo and ambient occlusion,
+// world normal and perceptual roughness, emissive and metallic, view depth (0 where nothing is).
+pub const GBUFFER_TARGETS: [&str; 4] = [
+    "_GBufferAlbedo",
+    "_GBufferNormal",
+    "_GBufferEmissive",
+    "_GBufferDepth",
+];
+
+// The depth stencil texture in the render graph of a frame.
+pub const DEPTH_TARGET: &str = "_Depth";
+
 pub struct GraphicsState {
     pub surface: wgpu::Surface,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub swap_chain: wgpu::SwapChain,
     pub swap_chain_desc: wgpu::SwapChainDescriptor,
-    pub depth_stencil_texture: Texture,
+    // samples per pixel of the main pass, see `set_sample_count`
+    pub sample_count: u32,
+    // the targets sub shaders write besides the frame, by name, see `set_render_targets`. When
+    // multisampling, they're resolved to from transient textures like the frame.
+    pub render_targets: HashMap<String, Texture>,
+    // the depth stencil and multisampled textures of frames, see RenderGraph
+    pub transient_textures: RefCell<TransientTextures>,
     pub render_pipelines: HashMap<String, wgpu::RenderPipeline>,
+    // by (shader, tag), see `create_compute_pipelines`
+    pub compute_pipelines: HashMap<(String, String), wgpu::ComputePipeline>,
+    // buffers compute shaders bind by name, see `Engine::create_storage_buffer`
+    pub storage_buffers: HashMap<String, wgpu::Buffer>,
     pub bind_group_layouts: HashMap<String, wgpu::BindGroupLayout>,
+    // for the sets a pipeline leaves unused
+    pub empty_bind_group: wgpu::BindGroup,
+    // the G-buffer targets, None until shaders write all of them
+    pub gbuffer_bind_group: Option<wgpu::BindGroup>,
+    gbuffer_sampler: wgpu::Sampler,
 }
 
 impl GraphicsState {
@@ -32,7 +63,9 @@ impl GraphicsState {
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: Some("Device"),
-                    features: wgpu::Features::empty(),
+                    // optional, only sub shaders using them fail to build without
+                    features: adapter.features()
+                        & (wgpu::Features::NON_FILL_POLYGON_MODE | wgpu::Features::DEPTH_CLAMPING),
                     limits: wgpu::Limits {
                         max_bind_groups: 5,
                         ..Default::default()
@@ -49,26 +82,38 @@ impl GraphicsState {
             present_mode: wgpu::PresentMode::Fifo,
         };
         let swap_chain = device.create_swap_chain(&surface, &swap_chain_desc);
-        let depth_stencil_texture = Texture::depth_stencil_texture(
-            &device,
-            &swap_chain_desc,
-            Some("Default Depth Stencil Texture"),
-        );
 
         let object_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Object Bind Group Layout"),
-                entries: &[util::uniform_bind_group_entry(0)],
+                // all objects share one buffer, see GeometryArena
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: Some(GeometryArena::object_binding_size()),
+                    },
+                    count: None,
+                }],
             });
         let light_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Light Bind Group Layout"),
                 entries: &[
                     util::uniform_bind_group_entry(0),
+                    // every light, see LightArray
+                    util::uniform_bind_group_entry(1),
+                    // params, lights, light counts and light indices, see LightClusters
+                    util::uniform_bind_group_entry(2),
+                    util::storage_bind_group_entry(3, true),
+                    util::storage_bind_group_entry(4, true),
+                    util::storage_bind_group_entry(5, true),
                     // for future use (shadow map)
-                    // util::texture_bind_group_entry(1, wgpu::TextureViewDimension::D2),
-                    // util::texture_bind_group_entry(2, wgpu::TextureViewDimension::Cube),
-                    // util::sampler_bind_group_entry(3),
+                    // util::texture_bind_group_entry(6, wgpu::TextureViewDimension::D2),
+                    // util::texture_bind_group_entry(7, wgpu::TextureViewDimension::Cube),
+                    // util::sampler_bind_group_entry(8),
                 ],
             });
         let camera_bind_group_layout =
@@ -90,11 +135,54 @@ impl GraphicsState {
                     util::sampler_bind_group_entry(7),
                 ],
             });
+        let empty_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Empty Bind Group Layout"),
+                entries: &[],
+            });
+        let empty_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Empty Bind Group"),
+            layout: &empty_bind_group_layout,
+            entries: &[],
+        });
+        // fetched, the depth can't be filtered
+        let gbuffer_entries: Vec<_> = (0..GBUFFER_TARGETS.len() as u32)
+            .map(|binding| wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            })
+            .chain(std::iter::once(wgpu::BindGroupLayoutEntry {
+                binding: GBUFFER_TARGETS.len() as u32,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    filtering: false,
+                    comparison: false,
+                },
+                count: None,
+            }))
+            .collect();
+        let gbuffer_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("GBuffer Bind Group Layout"),
+                entries: &gbuffer_entries,
+            });
+        let gbuffer_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("GBuffer Sampler"),
+            ..Default::default()
+        });
         let mut bind_group_layouts = HashMap::new();
         bind_group_layouts.insert("_Object".to_string(), object_bind_group_layout);
         bind_group_layouts.insert("_Light".to_string(), light_bind_group_layout);
         bind_group_layouts.insert("_Camera".to_string(), camera_bind_group_layout);
         bind_group_layouts.insert("_Scene".to_string(), scene_bind_group_layout);
+        bind_group_layouts.insert("_Empty".to_string(), empty_bind_group_layout);
+        bind_group_layouts.insert("_GBuffer".to_string(), gbuffer_bind_group_layout);
 
         Ok(Self {
             surface,
@@ -102,9 +190,16 @@ impl GraphicsState {
             queue,
             swap_chain,
             swap_chain_desc,
-            depth_stencil_texture,
+            sample_count: 1,
+            render_targets: HashMap::new(),
+            transient_textures: RefCell::new(TransientTextures::new()),
             render_pipelines: HashMap::new(),
+            compute_pipelines: HashMap::new(),
+            storage_buffers: HashMap::new(),
             bind_group_layouts,
+            empty_bind_group,
+            gbuffer_bind_group: None,
+            gbuffer_sampler,
         })
     }
 
@@ -114,11 +209,102 @@ impl GraphicsState {
         self.swap_chain = self
             .device
             .create_swap_chain(&self.surface, &self.swap_chain_desc);
-        self.depth_stencil_texture = Texture::depth_stencil_texture(
+        self.create_render_targets();
+    }
+
+    // Pipelines drawing into the main pass have to be recreated with the new count.
+    pub fn set_sample_count(&mut self, sample_count: u32) {
+        self.sample_count = sample_count;
+        self.transient_textures.get_mut().clear();
+    }
+
+    fn create_render_targets(&mut self) {
+        self.transient_textures.get_mut().clear();
+        let formats: Vec<_> = self
+            .render_targets
+            .iter()
+            .map(|(name, target)| (name.clone(), target.format))
+            .collect();
+        for (name, format) in formats {
+            let target = self.create_render_target(&name, format);
+            self.render_targets.insert(name, target);
+        }
+        self.create_gbuffer_bind_group();
+    }
+
+    // Keeps the targets whose format didn't change, their contents are undefined anyway.
+    pub fn set_render_targets(&mut self, formats: HashMap<String, wgpu::TextureFormat>) {
+        self.render_targets
+            .retain(|name, target| formats.get(name) == Some(&target.format));
+        for (name, format) in formats {
+            if !self.render_targets.contains_key(&name) {
+                let target = self.create_render_target(&name, format);
+                self.render_targets.insert(name, target);
+            }
+        }
+        self.create_gbuffer_bind_group();
+    }
+
+    fn create_gbuffer_bind_group(&mut self) {
+        let targets: Option<Vec<_>> = GBUFFER_TARGETS
+            .iter()
+            .map(|&name| self.render_targets.get(name))
+            .collect();
+        self.gbuffer_bind_group = targets.map(|targets| {
+            let entries: Vec<_> = targets
+                .iter()
+                .enumerate()
+                .map(|(binding, target)| wgpu::BindGroupEntry {
+                    binding: binding as u32,
+                    resource: wgpu::BindingResource::TextureView(&target.view),
+                })
+                .chain(std::iter::once(wgpu::BindGroupEntry {
+                    binding: targets.len() as u32,
+                    resource: wgpu::BindingResource::Sampler(&self.gbuffer_sampler),
+                }))
+                .collect();
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("GBuffer Bind Group"),
+                layout: &self.bind_group_layouts["_GBuffer"],
+                entries: &entries,
+            })
+        });
+    }
+
+    // Replaces the pipelines of the shader's previous version, the shader has to be built.
+    pub fn create_compute_pipelines(&mut self, shader: &Shader) {
+        self.compute_pipelines
+            .retain(|(name, _), _| name != &shader.name);
+        for (tag, compute) in &shader.compute_shaders {
+            let pipeline_layout =
+                self.device
+                    .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        label: Some(&format!("{}-{} Pipeline Layout", &shader.name, tag)),
+                        bind_group_layouts: &[compute.bind_group_layout().unwrap()],
+                        push_constant_ranges: &[],
+                    });
+            let pipeline = self
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some(&format!("{}-{} Compute Pipeline", &shader.name, tag)),
+                    layout: Some(&pipeline_layout),
+                    module: compute.module().unwrap(),
+                    entry_point: compute.entry_point(),
+                });
+            self.compute_pipelines
+                .insert((shader.name.clone(), tag.clone()), pipeline);
+        }
+    }
+
+    fn create_render_target(&self, name: &str, format: wgpu::TextureFormat) -> Texture {
+        Texture::render_target_2d(
             &self.device,
-            &self.swap_chain_desc,
-            Some("Default Depth Stencil Texture"),
-        );
+            self.swap_chain_desc.width,
+            self.swap_chain_desc.height,
+            format,
+            1,
+            Some(&format!("{} Render Target", name)),
+        )
     }
 }
 
@@ -136,6 +322,19 @@ pub mod util {
         }
     }
 
+    pub fn storage_bind_group_entry(binding: u32, read_only: bool) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }
+    }
+
     pub fn texture_bind_group_entry(
         binding: u32,
         view_dimension: wgpu::TextureViewDimension,
@@ -0,0 +1,205 @@
+use anyhow::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+use crate::engine::Engine;
+use crate::material_file;
+use crate::render_passes;
+use crate::shader::schema::{self, MaterialFileEntry, ShaderEntry, ShadersFile};
+
+// Where a loaded shader came from, so it can be reloaded when any of these files change.
+pub struct ShaderSource {
+    // the file given to `Engine::load_shaders`
+    pub shaders_file: PathBuf,
+    // the shader's own json file (if not inlined), its GLSL files and their includes
+    pub files: Vec<PathBuf>,
+}
+
+// Polls the modification time of a set of files. Cheap enough to call every frame, the files are
+// only looked at every `POLL_INTERVAL`.
+pub struct FileWatcher {
+    files: HashMap<PathBuf, Option<SystemTime>>,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn watch<P: AsRef<Path>>(&mut self, path: P) {
+        self.files
+            .entry(path.as_ref().to_path_buf())
+            .or_insert_with_key(|path| Self::modified(path));
+    }
+
+    // files modified (or removed / recreated) since the last poll
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < Self::POLL_INTERVAL {
+            return vec![];
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = vec![];
+        for (path, last_modified) in &mut self.files {
+            let modified = Self::modified(path);
+            if modified != *last_modified {
+                *last_modified = modified;
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+}
+
+impl Engine {
+    pub fn watch_shader(&mut self, name: &str, source: ShaderSource) {
+        self.file_watcher.watch(&source.shaders_file);
+        for file in &source.files {
+            self.file_watcher.watch(file);
+        }
+        self.shader_sources.insert(name.to_string(), source);
+    }
+
+    pub fn watch_material(&mut self, name: &str, file: PathBuf) {
+        self.file_watcher.watch(&file);
+        self.material_sources.insert(name.to_string(), file);
+    }
+
+    // Reloads the shaders and material files that changed. Failing shaders keep their old
+    // pipelines, failing material files their old definition.
+    pub fn hot_reload(&mut self) {
+        let changed: HashSet<PathBuf> = self.file_watcher.poll().into_iter().collect();
+        if changed.is_empty() {
+            return;
+        }
+        self.reload_shader_files(&changed);
+        self.reload_material_files(&changed);
+    }
+
+    fn reload_shader_files(&mut self, changed: &HashSet<PathBuf>) {
+        // shaders file -> shaders to reload, None for all of them (the file itself changed)
+        let mut reloads: HashMap<PathBuf, Option<HashSet<String>>> = HashMap::new();
+        for (name, source) in &self.shader_sources {
+            if changed.contains(&source.shaders_file) {
+                reloads.insert(source.shaders_file.clone(), None);
+            } else if source.files.iter().any(|file| changed.contains(file)) {
+                if let Some(names) = reloads
+                    .entry(source.shaders_file.clone())
+                    .or_insert_with(|| Some(HashSet::new()))
+                {
+                    names.insert(name.clone());
+                }
+            }
+        }
+
+        for (shaders_file, names) in reloads {
+            if let Err(err) = self.reload_shaders(&shaders_file, names.as_ref()) {
+                eprintln!("Failed to reload '{}': {:?}", shaders_file.display(), err);
+            }
+        }
+    }
+
+    fn reload_shaders(&mut self, path: &Path, names: Option<&HashSet<String>>) -> Result<()> {
+        let file_name = path.display().to_string();
+        let shaders_file: ShadersFile = schema::from_file(path)?;
+        if names.is_none() {
+            // only the shaders file itself has them
+            self.frame_passes = match &shaders_file.passes {
+                Some(passes) => render_passes::frame_passes_from_json(passes)
+                    .map_err(|err| err.in_file(&file_name))?,
+                None => render_passes::default_frame_passes(self.lighting, self.depth_prepass),
+            };
+        }
+
+        for (i, entry) in shaders_file.shaders.iter().enumerate() {
+            if let Some(names) = names {
+                let affected = match entry {
+                    ShaderEntry::Inline(shader_json) => names.contains(&shader_json.name),
+                    ShaderEntry::File(shader_path) => names.iter().any(|name| {
+                        self.shader_sources[name]
+                            .files
+                            .contains(&PathBuf::from(shader_path))
+                    }),
+                };
+                if !affected {
+                    continue;
+                }
+            }
+            let (shader, source) = match self.load_shader_entry(entry, i, path) {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    eprintln!("Failed to reload shader: {:?}", err);
+                    continue;
+                }
+            };
+            let name = shader.name.clone();
+            match self.install_shader(shader) {
+                Ok(()) => {
+                    println!("Reloaded shader '{}'", &name);
+                    self.watch_shader(&name, source);
+                }
+                Err(err) => eprintln!("Failed to reload shader '{}': {:?}", &name, err),
+            }
+        }
+
+        // only pick up new materials, existing ones keep their values
+        let new_materials: Vec<_> = shaders_file
+            .materials
+            .into_iter()
+            .filter(|entry| match entry {
+                MaterialFileEntry::Inline(material) => !self.materials.contains_key(&material.name),
+                // material files are reloaded on their own
+                MaterialFileEntry::File(file) => !self
+                    .material_sources
+                    .values()
+                    .any(|source| source == Path::new(file)),
+            })
+            .collect();
+        self.add_materials(&new_materials, &file_name)?;
+        self.build_material_pipelines()
+    }
+
+    fn reload_material_files(&mut self, changed: &HashSet<PathBuf>) {
+        let files: HashSet<PathBuf> = self
+            .material_sources
+            .values()
+            .filter(|file| changed.contains(*file))
+            .cloned()
+            .collect();
+        for file in &files {
+            let result = material_file::material_from_file(file).and_then(|material| {
+                let name = material.name.clone();
+                self.define_material(material)
+                    .map_err(|err| err.in_file(&file.display().to_string()))?;
+                Ok(name)
+            });
+            match result {
+                Ok(name) => {
+                    println!("Reloaded material '{}'", &name);
+                    // the material may have been renamed
+                    self.material_sources.retain(|_, source| source != file);
+                    self.watch_material(&name, file.clone());
+                }
+                Err(err) => eprintln!("Failed to reload '{}': {}", file.display(), err),
+            }
+        }
+        if !files.is_empty() {
+            if let Err(err) = self.build_material_pipelines() {
+                eprintln!("Failed to build material pipelines: {:?}", err);
+            }
+        }
+    }
+}
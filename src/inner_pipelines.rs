@@ -8,6 +8,7 @@ impl Engine {
             wgpu::TextureFormat::Rgba16Float,
         ]);
         self.skybox_pipeline();
+        self.deferred_lighting_pipeline();
         self.envmap_pipeline();
     }
 
@@ -87,7 +88,8 @@ impl Engine {
             .insert("_Blit".to_string(), bind_group_layout);
     }
 
-    fn skybox_pipeline(&mut self) {
+    // drawn in the main pass, so recreated when its sample count changes
+    pub(crate) fn skybox_pipeline(&mut self) {
         let pipeline_layout =
             self.graphics_state
                 .device
@@ -149,7 +151,82 @@ impl Engine {
                         clamp_depth: false,
                     }),
                     multisample: wgpu::MultisampleState {
-                        count: 1,
+                        count: self.graphics_state.sample_count,
+                        mask: !0,
+                        alpha_to_coverage_enabled: false,
+                    },
+                }),
+        );
+    }
+
+    // like the skybox, drawn in the main pass. Sets 2 to 4 are the ones of forward sub shaders.
+    pub(crate) fn deferred_lighting_pipeline(&mut self) {
+        let pipeline_layout =
+            self.graphics_state
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Deferred Lighting Pipeline Layout"),
+                    bind_group_layouts: &[
+                        &self.graphics_state.bind_group_layouts["_GBuffer"],
+                        &self.graphics_state.bind_group_layouts["_Empty"],
+                        &self.graphics_state.bind_group_layouts["_Light"],
+                        &self.graphics_state.bind_group_layouts["_Camera"],
+                        &self.graphics_state.bind_group_layouts["_Scene"],
+                    ],
+                    push_constant_ranges: &[],
+                });
+        let vs_module = self
+            .graphics_state
+            .device
+            .create_shader_module(&wgpu::include_spirv!(
+                "../res/shaders/inner/screen.vert.spv"
+            ));
+        let fs_module = self
+            .graphics_state
+            .device
+            .create_shader_module(&wgpu::include_spirv!(
+                "../res/shaders/inner/deferred_lighting.frag.spv"
+            ));
+        self.graphics_state.render_pipelines.insert(
+            "DeferredLighting".to_string(),
+            self.graphics_state
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("Deferred Lighting Render Pipeline"),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &vs_module,
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &fs_module,
+                        entry_point: "main",
+                        targets: &[wgpu::ColorTargetState {
+                            format: self.graphics_state.swap_chain_desc.format,
+                            alpha_blend: wgpu::BlendState::REPLACE,
+                            color_blend: wgpu::BlendState::REPLACE,
+                            write_mask: wgpu::ColorWrite::ALL,
+                        }],
+                    }),
+                    primitive: wgpu::PrimitiveState {
+                        topology: wgpu::PrimitiveTopology::TriangleList,
+                        strip_index_format: None,
+                        front_face: wgpu::FrontFace::Ccw,
+                        cull_mode: wgpu::CullMode::None,
+                        polygon_mode: wgpu::PolygonMode::Fill,
+                    },
+                    // the depth of the G-buffer pass is kept for the passes after
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: crate::graphics::GraphicsState::DEPTH_STENCIL_FORMAT,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Always,
+                        stencil: wgpu::StencilState::default(),
+                        bias: wgpu::DepthBiasState::default(),
+                        clamp_depth: false,
+                    }),
+                    multisample: wgpu::MultisampleState {
+                        count: self.graphics_state.sample_count,
                         mask: !0,
                         alpha_to_coverage_enabled: false,
                     },
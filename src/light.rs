@@ -1,44 +1,122 @@
+use bytemuck::Zeroable;
 use cgmath::InnerSpace;
+use std::collections::HashMap;
 use wgpu::util::DeviceExt;
 
+use crate::light_clusters::{
+    CLUSTER_LIGHTS, CLUSTER_LIGHT_COUNTS, CLUSTER_LIGHT_INDICES, CLUSTER_PARAMS,
+};
+
+// MAX_LIGHTS in engine/light_types.glsl, lights past it are left out of the array
+pub const MAX_LIGHTS: usize = 16;
+
 pub struct Light {
     uniform: LightUniform,
     uniform_buffer: Option<wgpu::Buffer>,
     pub bind_group: Option<wgpu::BindGroup>,
 }
 
+// The range of point and spot lights without one, where their light falls below this.
+const LIGHT_CUTOFF: f32 = 0.01;
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct LightUniform {
     position: [f32; 4],
     color: [f32; 4],
+    // spot direction, range in w
+    direction: [f32; 4],
+    // cone scale and offset, 0 and 1 for no cone
+    spot: [f32; 4],
+}
+
+// Every light at once, for shaders lighting in a single pass. The bind group of each light binds
+// it besides the light's own uniform.
+pub struct LightArray {
+    uniform_buffer: wgpu::Buffer,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightArrayUniform {
+    count: u32,
+    _padding: [u32; 3],
+    lights: [LightUniform; MAX_LIGHTS],
 }
 
 impl Light {
-    pub fn point_light(position: cgmath::Point3<f32>, color: [f32; 4]) -> Self {
-        Self {
-            uniform: LightUniform {
-                position: [position.x, position.y, position.z, 1.0],
-                color,
-            },
-            uniform_buffer: None,
-            bind_group: None,
-        }
+    // Lights nothing past `range`, None derives it from the color.
+    pub fn point_light(position: cgmath::Point3<f32>, color: [f32; 4], range: Option<f32>) -> Self {
+        Self::new(LightUniform {
+            position: [position.x, position.y, position.z, 1.0],
+            color,
+            direction: [0.0, 0.0, 0.0, Self::range(range, color)],
+            spot: [0.0, 1.0, 0.0, 0.0],
+        })
+    }
+
+    // Full inside `inner_cone_angle` and fading to nothing at `outer_cone_angle`, in radians.
+    pub fn spot_light(
+        position: cgmath::Point3<f32>,
+        direction: cgmath::Vector3<f32>,
+        color: [f32; 4],
+        range: Option<f32>,
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    ) -> Self {
+        let direction = direction.normalize();
+        let cos_outer = outer_cone_angle.cos();
+        let cone_scale = 1.0 / (inner_cone_angle.cos() - cos_outer).max(0.001);
+        Self::new(LightUniform {
+            position: [position.x, position.y, position.z, 1.0],
+            color,
+            direction: [
+                direction.x,
+                direction.y,
+                direction.z,
+                Self::range(range, color),
+            ],
+            spot: [cone_scale, -cos_outer * cone_scale, 0.0, 0.0],
+        })
     }
 
     pub fn directional_light(direction: cgmath::Vector3<f32>, color: [f32; 4]) -> Self {
         let direction = (-direction).normalize();
+        Self::new(LightUniform {
+            position: [direction.x, direction.y, direction.z, 0.0],
+            color,
+            direction: [0.0; 4],
+            spot: [0.0, 1.0, 0.0, 0.0],
+        })
+    }
+
+    fn new(uniform: LightUniform) -> Self {
         Self {
-            uniform: LightUniform {
-                position: [direction.x, direction.y, direction.z, 0.0],
-                color,
-            },
+            uniform,
             uniform_buffer: None,
             bind_group: None,
         }
     }
 
-    pub fn build(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout) {
+    fn range(range: Option<f32>, color: [f32; 4]) -> f32 {
+        range.unwrap_or_else(|| {
+            let brightest = color[0].max(color[1]).max(color[2]);
+            (brightest / LIGHT_CUTOFF).sqrt()
+        })
+    }
+
+    pub fn uniform(&self) -> LightUniform {
+        self.uniform
+    }
+
+    pub fn build(
+        &mut self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        light_array: &LightArray,
+        // the engine's, with the light cluster buffers
+        storage_buffers: &HashMap<String, wgpu::Buffer>,
+    ) {
         self.uniform_buffer = Some(
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Light Uniform Buffer"),
@@ -50,10 +128,32 @@ impl Light {
         self.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Light Bing Group"),
             layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: self.uniform_buffer.as_ref().unwrap().as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.uniform_buffer.as_ref().unwrap().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: light_array.uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: storage_buffers[CLUSTER_PARAMS].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: storage_buffers[CLUSTER_LIGHTS].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: storage_buffers[CLUSTER_LIGHT_COUNTS].as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: storage_buffers[CLUSTER_LIGHT_INDICES].as_entire_binding(),
+                },
+            ],
         }))
     }
 
@@ -65,3 +165,34 @@ impl Light {
         );
     }
 }
+
+impl LightArray {
+    pub fn new(device: &wgpu::Device, lights: &[Light]) -> Self {
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Array Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[Self::uniform(lights)]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        Self { uniform_buffer }
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, lights: &[Light]) {
+        queue.write_buffer(
+            &self.uniform_buffer,
+            0,
+            bytemuck::cast_slice(&[Self::uniform(lights)]),
+        );
+    }
+
+    fn uniform(lights: &[Light]) -> LightArrayUniform {
+        let mut uniform = LightArrayUniform {
+            count: lights.len().min(MAX_LIGHTS) as u32,
+            _padding: [0; 3],
+            lights: [LightUniform::zeroed(); MAX_LIGHTS],
+        };
+        for (slot, light) in uniform.lights.iter_mut().zip(lights) {
+            *slot = light.uniform;
+        }
+        uniform
+    }
+}
@@ -0,0 +1,152 @@
+// Clustered forward lighting. The view frustum is split into a grid of clusters, tiles on screen
+// sliced exponentially in depth, and a compute pass lists the lights reaching each cluster, so a
+// fragment only loops over the lights of its own cluster. The buffers are bound with every light
+// (set 2) and to compute shaders by name.
+use anyhow::*;
+use std::collections::HashMap;
+
+use crate::camera::Camera;
+use crate::compute::{ComputePass, ComputeStage};
+use crate::engine::Engine;
+use crate::light::{Light, LightUniform};
+
+// CLUSTER_GRID_*, MAX_CLUSTERED_LIGHTS and MAX_LIGHTS_PER_CLUSTER in engine/light_types.glsl
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+// lights past it are left out of the clusters
+pub const MAX_CLUSTERED_LIGHTS: usize = 1024;
+pub const MAX_LIGHTS_PER_CLUSTER: usize = 128;
+
+// The compute sub shader building the clusters, any installed shader may have it.
+pub const BUILD_CLUSTERS_TAG: &str = "BuildClusters";
+const BUILD_CLUSTERS_PASS: &str = "_BuildClusters";
+// local size of the compute shader, one invocation per cluster
+const WORKGROUP_SIZE: [u32; 3] = [16, 9, 1];
+
+// Engine storage buffers, see `Engine::create_storage_buffer`, so compute shaders bind them by
+// name.
+pub const CLUSTER_PARAMS: &str = "_ClusterParams";
+pub const CLUSTER_LIGHTS: &str = "_ClusterLights";
+// lights per cluster
+pub const CLUSTER_LIGHT_COUNTS: &str = "_ClusterLightCounts";
+// MAX_LIGHTS_PER_CLUSTER slots per cluster
+pub const CLUSTER_LIGHT_INDICES: &str = "_ClusterLightIndices";
+pub const CLUSTER_BUFFERS: [&str; 4] = [
+    CLUSTER_PARAMS,
+    CLUSTER_LIGHTS,
+    CLUSTER_LIGHT_COUNTS,
+    CLUSTER_LIGHT_INDICES,
+];
+
+#[derive(Default)]
+pub struct LightClusters {
+    params: ClusterParamsUniform,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct ClusterParamsUniform {
+    view: [[f32; 4]; 4],
+    proj_inv: [[f32; 4]; 4],
+    screen_size: [f32; 2],
+    znear: f32,
+    zfar: f32,
+    light_count: u32,
+    // colors fragments by the lights of their cluster instead of lighting them
+    heatmap: u32,
+    _padding: [u32; 2],
+}
+
+impl LightClusters {
+    pub fn heatmap(&self) -> bool {
+        self.params.heatmap != 0
+    }
+
+    pub fn set_heatmap(&mut self, heatmap: bool) {
+        self.params.heatmap = heatmap as u32;
+    }
+
+    pub fn update_lights(
+        &mut self,
+        queue: &wgpu::Queue,
+        storage_buffers: &HashMap<String, wgpu::Buffer>,
+        lights: &[Light],
+    ) {
+        let uniforms: Vec<_> = lights
+            .iter()
+            .take(MAX_CLUSTERED_LIGHTS)
+            .map(Light::uniform)
+            .collect();
+        self.params.light_count = uniforms.len() as u32;
+        queue.write_buffer(
+            &storage_buffers[CLUSTER_LIGHTS],
+            0,
+            bytemuck::cast_slice(&uniforms),
+        );
+    }
+
+    // Every frame, after the camera is updated.
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        storage_buffers: &HashMap<String, wgpu::Buffer>,
+        camera: &Camera,
+        width: u32,
+        height: u32,
+    ) {
+        let (znear, zfar) = camera.depth_range();
+        self.params.view = camera.view().into();
+        self.params.proj_inv = camera.proj_inv().into();
+        self.params.screen_size = [width as f32, height as f32];
+        self.params.znear = znear;
+        self.params.zfar = zfar;
+        queue.write_buffer(
+            &storage_buffers[CLUSTER_PARAMS],
+            0,
+            bytemuck::cast_slice(&[self.params]),
+        );
+    }
+}
+
+impl Engine {
+    // Before any light is built, every light binds them.
+    pub(crate) fn create_light_cluster_buffers(&mut self) {
+        let cluster_count = (CLUSTER_GRID[0] * CLUSTER_GRID[1] * CLUSTER_GRID[2]) as u64;
+        let light_size = std::mem::size_of::<LightUniform>() as u64;
+        let index_size = std::mem::size_of::<u32>() as u64;
+        let sizes = [
+            std::mem::size_of::<ClusterParamsUniform>() as u64,
+            MAX_CLUSTERED_LIGHTS as u64 * light_size,
+            cluster_count * index_size,
+            cluster_count * MAX_LIGHTS_PER_CLUSTER as u64 * index_size,
+        ];
+        for (name, &size) in CLUSTER_BUFFERS.iter().zip(&sizes) {
+            self.create_storage_buffer(name, size);
+        }
+    }
+
+    // Dispatches the compute sub shader building the clusters before every frame.
+    pub(crate) fn schedule_light_clusters(&mut self) -> Result<()> {
+        let shader = self
+            .shaders
+            .values()
+            .find(|shader| shader.compute_shaders.contains_key(BUILD_CLUSTERS_TAG))
+            .with_context(|| {
+                format!(
+                    "Clustered lighting needs a shader with a '{}' compute shader",
+                    BUILD_CLUSTERS_TAG
+                )
+            })?;
+        let workgroups = [
+            CLUSTER_GRID[0] / WORKGROUP_SIZE[0],
+            CLUSTER_GRID[1] / WORKGROUP_SIZE[1],
+            CLUSTER_GRID[2] / WORKGROUP_SIZE[2],
+        ];
+        let pass = ComputePass {
+            shader: shader.name.clone(),
+            tag: BUILD_CLUSTERS_TAG.to_string(),
+            workgroups,
+            stage: ComputeStage::BeforeRender,
+        };
+        self.schedule_compute_pass(BUILD_CLUSTERS_PASS, pass)
+    }
+}
@@ -1,28 +1,94 @@
 use anyhow::*;
 
 mod camera;
+mod compute;
 mod engine;
 mod env_map;
+mod geometry;
 mod gltf_scene;
 mod graphics;
+mod hot_reload;
 mod inner_pipelines;
 mod light;
+mod light_clusters;
 mod material;
+mod material_file;
 mod mesh;
+mod mesh_processing;
+mod render_graph;
+mod render_passes;
 mod shader;
 mod texture;
 mod vertex;
 
 fn main() -> Result<()> {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 {
-        println!("Usage cargo run <path-to-gltf> <path-to-shader-json>");
+    if args.len() < 3 {
+        println!("Usage cargo run <path-to-gltf> <path-to-shader-json> [options]");
+        println!("Options:");
+        println!(
+            "    --smooth-normals[=<crease-angle>]    generate smooth normals (default 60 deg)"
+        );
+        println!("    --no-lod                             don't generate LODs for meshes");
+        println!(
+            "    --optimize-meshes                    reorder indices and vertices for the GPU"
+        );
+        println!("    --clear-shader-cache                 recompile all shaders");
+        println!(
+            "    --multi-pass-lights                  draw one pass per light instead of one for all"
+        );
+        println!("    --clustered-lights                   light with the lights of each cluster");
+        println!("    --deferred                           light opaque meshes from a G-buffer");
+        println!("    --depth-prepass                      write the depth of opaque meshes first");
+        println!(
+            "    --cluster-heatmap                    show the lights per cluster (toggled by H)"
+        );
         return Ok(());
     }
 
+    // before the engine compiles its inner shaders, so that they don't come from the cache either
+    if args[3..].iter().any(|arg| arg == "--clear-shader-cache") {
+        shader::spirv_cache::clear()?;
+    } else {
+        let evicted = shader::spirv_cache::evict_stale();
+        if evicted > 0 {
+            println!("Evicted {} stale shader cache entries", evicted);
+        }
+    }
+
     println!("Creating engine...");
     let (mut engine, event_loop) = engine::Engine::new()?;
 
+    for arg in &args[3..] {
+        if arg.starts_with("--smooth-normals") {
+            let crease_angle = match arg.strip_prefix("--smooth-normals=") {
+                Some(angle) => angle.parse()?,
+                None => 60.0,
+            };
+            engine.normal_mode = mesh_processing::normals::NormalMode::Smooth {
+                crease_angle: cgmath::Deg(crease_angle),
+            };
+        } else if arg == "--no-lod" {
+            engine.lod_options = None;
+        } else if arg == "--optimize-meshes" {
+            engine.optimize_meshes = true;
+        } else if arg == "--multi-pass-lights" {
+            engine.lighting = render_passes::Lighting::MultiPass;
+        } else if arg == "--clustered-lights" {
+            engine.lighting = render_passes::Lighting::Clustered;
+        } else if arg == "--deferred" {
+            engine.lighting = render_passes::Lighting::Deferred;
+        } else if arg == "--depth-prepass" {
+            engine.depth_prepass = true;
+        } else if arg == "--cluster-heatmap" {
+            engine.light_clusters.set_heatmap(true);
+        } else if arg == "--clear-shader-cache" {
+            // handled before creating the engine
+        } else {
+            println!("Unknown option '{}'", arg);
+        }
+    }
+
     println!("Engine is created successfully. Loading skybox...");
     engine.load_skybox(
         "res/textures/skybox-sea/right.jpg",
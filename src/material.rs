@@ -1,7 +1,7 @@
 use byte_slice_cast::AsByteSlice;
 use wgpu::util::DeviceExt;
 
-use crate::shader::{Shader, TextureProperty};
+use crate::shader::{Keywords, Shader, TextureProperty};
 use crate::texture::Texture;
 use std::collections::HashMap;
 
@@ -13,6 +13,11 @@ pub struct Material {
     uniform_buffer: Option<wgpu::Buffer>,
     pub textures: HashMap<String, Texture>,
     textures_index: HashMap<String, u32>,
+    // selects the shader variant, see `Engine::build_material_pipelines`
+    pub keywords: Keywords,
+    keyword_groups: Vec<Vec<String>>,
+    // drawn in this render queue instead of the one of its sub shaders
+    pub queue: Option<u32>,
     pub bind_group: Option<wgpu::BindGroup>,
 }
 
@@ -26,7 +31,7 @@ impl Material {
         let uniform_bytes = vec![0; shader.uniform_size];
         let mut textures = HashMap::new();
         for (tex_name, tex_ty) in &shader.texture_properties {
-            let default_tex = match tex_ty {
+            let mut default_tex = match tex_ty {
                 TextureProperty::Texture2D(default) => match default.as_str() {
                     "white" => Texture::white1x1(device, queue),
                     "black" => Texture::black1x1(device, queue),
@@ -37,6 +42,9 @@ impl Material {
                 TextureProperty::TextureCube => Texture::default_cube(device, queue),
                 TextureProperty::Texture3D => Texture::black1x1x1(device, queue),
             };
+            if let Some(sampler) = shader.texture_samplers.get(tex_name) {
+                default_tex.sampler = device.create_sampler(&sampler.desc);
+            }
             textures.insert(tex_name.clone(), default_tex);
         }
         // TODO - maybe these 'clone()'s can be removed by using Rc/Arc
@@ -48,10 +56,34 @@ impl Material {
             uniform_buffer: None,
             textures,
             textures_index: shader.textures_index.clone(),
+            keywords: Keywords::new(),
+            keyword_groups: shader.keyword_groups.clone(),
+            queue: None,
             bind_group: None,
         }
     }
 
+    // Enables a keyword of the shader and disables the others of its group. Unknown keywords are
+    // ignored, like unknown uniforms.
+    pub fn enable_keyword(&mut self, keyword: &str) -> &mut Self {
+        if let Some(group) = self
+            .keyword_groups
+            .iter()
+            .find(|group| group.iter().any(|k| k == keyword))
+        {
+            for other in group {
+                self.keywords.remove(other);
+            }
+            self.keywords.insert(keyword.to_string());
+        }
+        self
+    }
+
+    pub fn disable_keyword(&mut self, keyword: &str) -> &mut Self {
+        self.keywords.remove(keyword);
+        self
+    }
+
     pub fn set_float(&mut self, name: &str, value: f32) -> &mut Self {
         if let Some(offset) = self.uniform_offsets.get(name).cloned() {
             let value_bytes = value.to_le_bytes();
@@ -84,6 +116,61 @@ impl Material {
         self
     }
 
+    pub fn set_int(&mut self, name: &str, value: i32) -> &mut Self {
+        self.set_bytes(name, &value.to_le_bytes())
+    }
+
+    pub fn set_ivec2(&mut self, name: &str, value: [i32; 2]) -> &mut Self {
+        self.set_bytes(name, value.as_byte_slice())
+    }
+
+    pub fn set_ivec3(&mut self, name: &str, value: [i32; 3]) -> &mut Self {
+        self.set_bytes(name, value.as_byte_slice())
+    }
+
+    pub fn set_ivec4(&mut self, name: &str, value: [i32; 4]) -> &mut Self {
+        self.set_bytes(name, value.as_byte_slice())
+    }
+
+    pub fn set_uint(&mut self, name: &str, value: u32) -> &mut Self {
+        self.set_bytes(name, &value.to_le_bytes())
+    }
+
+    pub fn set_uvec2(&mut self, name: &str, value: [u32; 2]) -> &mut Self {
+        self.set_bytes(name, value.as_byte_slice())
+    }
+
+    pub fn set_uvec3(&mut self, name: &str, value: [u32; 3]) -> &mut Self {
+        self.set_bytes(name, value.as_byte_slice())
+    }
+
+    pub fn set_uvec4(&mut self, name: &str, value: [u32; 4]) -> &mut Self {
+        self.set_bytes(name, value.as_byte_slice())
+    }
+
+    // booleans take 4 bytes in uniform blocks
+    pub fn set_bool(&mut self, name: &str, value: bool) -> &mut Self {
+        self.set_uint(name, value as u32)
+    }
+
+    pub fn set_bvec2(&mut self, name: &str, value: [bool; 2]) -> &mut Self {
+        self.set_uvec2(name, [value[0] as u32, value[1] as u32])
+    }
+
+    pub fn set_bvec3(&mut self, name: &str, value: [bool; 3]) -> &mut Self {
+        self.set_uvec3(name, [value[0] as u32, value[1] as u32, value[2] as u32])
+    }
+
+    pub fn set_bvec4(&mut self, name: &str, value: [bool; 4]) -> &mut Self {
+        let value = [
+            value[0] as u32,
+            value[1] as u32,
+            value[2] as u32,
+            value[3] as u32,
+        ];
+        self.set_uvec4(name, value)
+    }
+
     pub fn set_mat3(&mut self, name: &str, value: cgmath::Matrix3<f32>) -> &mut Self {
         if let Some(offset) = self.uniform_offsets.get(name).cloned() {
             let value_arr = [
@@ -109,6 +196,14 @@ impl Material {
         self
     }
 
+    // Array elements and struct members are set through their path, e.g. "lights[2].color".
+    fn set_bytes(&mut self, name: &str, bytes: &[u8]) -> &mut Self {
+        if let Some(offset) = self.uniform_offsets.get(name).cloned() {
+            self.uniform_bytes[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        self
+    }
+
     pub fn set_texture(&mut self, name: &str, value: Texture) -> &mut Self {
         self.textures.get_mut(name).map(|data| *data = value);
         self
@@ -144,6 +239,60 @@ impl Material {
         }));
     }
 
+    // Moves the material onto a rebuilt version of its shader. Uniforms and textures that still
+    // exist with the same type keep their values, new ones get the shader defaults.
+    pub fn rebind(
+        &mut self,
+        old_shader: &Shader,
+        shader: &Shader,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) {
+        self.keywords = shader.supported_keywords(&self.keywords);
+        self.keyword_groups = shader.keyword_groups.clone();
+        let layout_changed = self.uniform_offsets != shader.uniform_offsets
+            || self.textures_index != shader.textures_index
+            || old_shader.uniform_properties != shader.uniform_properties;
+        if layout_changed {
+            let mut rebound = Material::from_shader(self.name.clone(), shader, device, queue);
+            for (name, ty) in &shader.uniform_properties {
+                let old_ty = old_shader
+                    .uniform_properties
+                    .iter()
+                    .find(|(old_name, _)| old_name == name)
+                    .map(|(_, ty)| ty);
+                if old_ty == Some(ty) {
+                    let old_offset = self.uniform_offsets[name];
+                    let offset = rebound.uniform_offsets[name];
+                    rebound.uniform_bytes[offset..offset + ty.size()]
+                        .copy_from_slice(&self.uniform_bytes[old_offset..old_offset + ty.size()]);
+                }
+            }
+            for (name, ty) in &shader.texture_properties {
+                let same_type = match (old_shader.texture_properties.get(name), ty) {
+                    (Some(TextureProperty::Texture2D(_)), TextureProperty::Texture2D(_)) => true,
+                    (Some(old_ty), ty) => old_ty == ty,
+                    (None, _) => false,
+                };
+                if same_type {
+                    if let Some(texture) = self.textures.remove(name) {
+                        rebound.textures.insert(name.clone(), texture);
+                    }
+                }
+            }
+            rebound.keywords = std::mem::take(&mut self.keywords);
+            rebound.queue = self.queue;
+            rebound.bind_group = self.bind_group.take();
+            *self = rebound;
+        }
+
+        // the bind group has to be recreated against the new layout even if nothing moved,
+        // materials never built aren't used by any mesh yet
+        if self.bind_group.is_some() {
+            self.build(device, shader.bind_group_layout.as_ref().unwrap());
+        }
+    }
+
     pub fn update(&mut self, queue: &wgpu::Queue) {
         queue.write_buffer(
             &self.uniform_buffer.as_ref().unwrap(),
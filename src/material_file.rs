@@ -0,0 +1,475 @@
+// Materials defined in JSON: uniform values, textures loaded from image files and keywords, on
+// top of the values of an optional parent material.
+use anyhow::*;
+use serde_json::Value;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use crate::engine::Engine;
+use crate::material::Material;
+use crate::shader::schema::{self, MaterialEntry, MaterialFileEntry, MaterialTextureJson};
+use crate::shader::shader_option_util;
+use crate::shader::{ShaderParseError, TextureProperty, UniformProperty};
+use crate::texture::Texture;
+
+impl Engine {
+    pub fn add_materials(&mut self, entries: &[MaterialFileEntry], file_name: &str) -> Result<()> {
+        for (i, entry) in entries.iter().enumerate() {
+            match entry {
+                MaterialFileEntry::Inline(material) => {
+                    self.define_material(material.clone())
+                        .map_err(|err| err.at(&format!("materials[{}]", i)).in_file(file_name))?;
+                }
+                MaterialFileEntry::File(path) => {
+                    let material = material_from_file(path)?;
+                    let name = material.name.clone();
+                    self.define_material(material)
+                        .map_err(|err| err.in_file(path))?;
+                    self.watch_material(&name, PathBuf::from(path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Creates the material, or updates it if it exists with the same shader. Materials
+    // inheriting from it are updated too.
+    pub fn define_material(&mut self, entry: MaterialEntry) -> Result<(), ShaderParseError> {
+        let name = entry.name.clone();
+        let previous = self.material_definitions.insert(name.clone(), entry);
+        if let Err(err) = self.build_defined_material(&name) {
+            match previous {
+                Some(previous) => self.material_definitions.insert(name, previous),
+                None => self.material_definitions.remove(&name),
+            };
+            return Err(err);
+        }
+
+        let children: Vec<String> = self
+            .material_definitions
+            .keys()
+            .filter(|child| {
+                *child != &name
+                    && self
+                        .material_chain(child)
+                        .is_ok_and(|chain| chain.iter().any(|entry| entry.name == name))
+            })
+            .cloned()
+            .collect();
+        // parents come first in the chain, so it doesn't matter in which order they are updated
+        for child in children {
+            if let Err(err) = self.reapply_material_definition(&child) {
+                eprintln!("Failed to update material '{}': {}", child, err);
+            }
+        }
+        Ok(())
+    }
+
+    fn build_defined_material(&mut self, name: &str) -> Result<(), ShaderParseError> {
+        let shader_name = self.material_shader(name)?;
+        let shader = self.shaders.get(&shader_name).ok_or_else(|| {
+            ShaderParseError::new(format!("Unknown shader '{}'", &shader_name)).at("shader")
+        })?;
+        let material = match self.materials.remove(name) {
+            Some(material) if material.shader == shader_name => material,
+            _ => Material::from_shader(
+                name.to_string(),
+                shader,
+                &self.graphics_state.device,
+                &self.graphics_state.queue,
+            ),
+        };
+        self.apply_material_definition(material)
+    }
+
+    // Applies the definition (and its parents) again, after glTF or code changed the values.
+    pub fn reapply_material_definition(&mut self, name: &str) -> Result<(), ShaderParseError> {
+        match self.materials.remove(name) {
+            Some(material) => self.apply_material_definition(material),
+            None => Ok(()),
+        }
+    }
+
+    // The parents of a material come first, the material itself last.
+    fn material_chain(&self, name: &str) -> Result<Vec<&MaterialEntry>, ShaderParseError> {
+        let mut chain = vec![];
+        let mut next = Some(name);
+        while let Some(name) = next {
+            let entry = self.material_definitions.get(name).ok_or_else(|| {
+                ShaderParseError::new(format!("Unknown parent material '{}'", name)).at("parent")
+            })?;
+            if chain.len() > self.material_definitions.len() {
+                return Err(ShaderParseError::new(format!(
+                    "Material '{}' inherits from itself",
+                    name
+                ))
+                .at("parent"));
+            }
+            chain.push(entry);
+            next = entry.parent.as_deref();
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+
+    fn material_shader(&self, name: &str) -> Result<String, ShaderParseError> {
+        self.material_chain(name)?
+            .iter()
+            .rev()
+            .find_map(|entry| entry.shader.clone())
+            .ok_or_else(|| ShaderParseError::new("Material needs a shader or a parent".to_string()))
+    }
+
+    // Puts the material (back) into `self.materials` with its bind group rebuilt, also on error.
+    fn apply_material_definition(
+        &mut self,
+        mut material: Material,
+    ) -> Result<(), ShaderParseError> {
+        let result = self.material_chain(&material.name).and_then(|chain| {
+            let last = chain.len() - 1;
+            for (i, entry) in chain.iter().enumerate() {
+                // values of parents the shader of a child doesn't have are skipped
+                self.apply_material_entry(&mut material, entry, i == last)?;
+            }
+            Ok(())
+        });
+        if let Some(shader) = self.shaders.get(&material.shader) {
+            material.build(
+                &self.graphics_state.device,
+                shader.bind_group_layout.as_ref().unwrap(),
+            );
+        }
+        self.materials.insert(material.name.clone(), material);
+        result
+    }
+
+    fn apply_material_entry(
+        &self,
+        material: &mut Material,
+        entry: &MaterialEntry,
+        strict: bool,
+    ) -> Result<(), ShaderParseError> {
+        let shader = &self.shaders[&material.shader];
+
+        for (name, value) in &entry.uniforms {
+            let ty = match shader.uniform_type(name) {
+                Some(ty) => ty,
+                None if strict => {
+                    return Err(ShaderParseError::new(format!(
+                        "Shader '{}' has no uniform '{}'",
+                        &shader.name, name
+                    ))
+                    .at(name)
+                    .at("uniforms"))
+                }
+                None => continue,
+            };
+            set_uniform_from_json(material, name, ty, value)
+                .map_err(|err| err.at(name).at("uniforms"))?;
+        }
+
+        for (name, texture) in &entry.textures {
+            match shader.texture_properties.get(name) {
+                Some(TextureProperty::Texture2D(_)) => {}
+                Some(_) => {
+                    return Err(ShaderParseError::new(
+                        "Only 2D textures can be loaded from images".to_string(),
+                    )
+                    .at(name)
+                    .at("textures"))
+                }
+                None if strict => {
+                    return Err(ShaderParseError::new(format!(
+                        "Shader '{}' has no texture '{}'",
+                        &shader.name, name
+                    ))
+                    .at(name)
+                    .at("textures"))
+                }
+                None => continue,
+            }
+            let base_sampler = shader
+                .texture_samplers
+                .get(name)
+                .map(|sampler| sampler.desc.clone());
+            let texture = self
+                .load_material_texture(texture, base_sampler)
+                .map_err(|err| err.at(name).at("textures"))?;
+            material.set_texture(name, texture);
+        }
+
+        for (i, keyword) in entry.keywords.iter().enumerate() {
+            if !shader.keyword_groups.iter().flatten().any(|k| k == keyword) {
+                if !strict {
+                    continue;
+                }
+                return Err(ShaderParseError::new(format!(
+                    "Shader '{}' has no keyword '{}'",
+                    &shader.name, keyword
+                ))
+                .at(&format!("keywords[{}]", i)));
+            }
+            material.enable_keyword(keyword);
+        }
+        for keyword in &entry.disabled_keywords {
+            material.disable_keyword(keyword);
+        }
+        Ok(())
+    }
+
+    fn load_material_texture(
+        &self,
+        texture: &MaterialTextureJson,
+        base_sampler: Option<wgpu::SamplerDescriptor<'static>>,
+    ) -> Result<Texture, ShaderParseError> {
+        let (path, color_space, sampler) = match texture {
+            MaterialTextureJson::Path(path) => (path, None, None),
+            MaterialTextureJson::Detailed(details) => (
+                &details.path,
+                details.color_space.as_deref(),
+                details.sampler.as_ref(),
+            ),
+        };
+        let format = match color_space.unwrap_or("srgb") {
+            "srgb" => wgpu::TextureFormat::Rgba8UnormSrgb,
+            "linear" => wgpu::TextureFormat::Rgba8Unorm,
+            other => {
+                return Err(
+                    ShaderParseError::new(format!("Unknown color space '{}'", other))
+                        .at("color_space"),
+                )
+            }
+        };
+        // the settings of the shader are the base, if it has any
+        let base_sampler = base_sampler.unwrap_or_else(shader_option_util::base_sampler);
+        let sampler = match sampler {
+            Some(sampler) if sampler.lod_bias.is_some() || sampler.override_gltf => {
+                return Err(ShaderParseError::new(
+                    "'lod_bias' and 'override_gltf' can only be given in the shader".to_string(),
+                )
+                .at("sampler"))
+            }
+            Some(sampler) => shader_option_util::sampler_from_json(sampler, base_sampler)
+                .map_err(|err| err.at("sampler"))?,
+            None => base_sampler,
+        };
+
+        let image = image::open(path)
+            .map_err(|err| ShaderParseError::new(format!("Can't load '{}': {}", path, err)))?
+            .into_rgba8();
+        let texture = Texture::from_bytes_2d(
+            &self.graphics_state.device,
+            &self.graphics_state.queue,
+            &image,
+            image.width(),
+            image.height(),
+            format,
+            true,
+            &sampler,
+            Some(path),
+        );
+        self.generate_mipmap(&texture);
+        Ok(texture)
+    }
+}
+
+// Reads a material file. Its texture paths are relative to the file, they're made relative to
+// the working directory like the ones of materials in a shaders file.
+pub fn material_from_file<P: AsRef<Path>>(path: P) -> Result<MaterialEntry, ShaderParseError> {
+    let mut material: MaterialEntry = schema::from_file(&path)?;
+    let dir = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
+    for texture in material.textures.values_mut() {
+        let texture_path = match texture {
+            MaterialTextureJson::Path(path) => path,
+            MaterialTextureJson::Detailed(details) => &mut details.path,
+        };
+        *texture_path = dir.join(texture_path.as_str()).display().to_string();
+    }
+    Ok(material)
+}
+
+// Writes a JSON value into the uniform at `path`, arrays and structs member by member.
+fn set_uniform_from_json(
+    material: &mut Material,
+    path: &str,
+    ty: &UniformProperty,
+    value: &Value,
+) -> Result<(), ShaderParseError> {
+    let error = || ShaderParseError::new(format!("Expected a {} value", ty));
+    match ty {
+        UniformProperty::Float => {
+            material.set_float(path, value.as_f64().ok_or_else(error)? as f32);
+        }
+        UniformProperty::Vec2 => {
+            let v = floats(value, 2).ok_or_else(error)?;
+            material.set_vec2(path, [v[0], v[1]]);
+        }
+        UniformProperty::Vec3 => {
+            let v = floats(value, 3).ok_or_else(error)?;
+            material.set_vec3(path, [v[0], v[1], v[2]]);
+        }
+        UniformProperty::Vec4 => {
+            let v = floats(value, 4).ok_or_else(error)?;
+            material.set_vec4(path, [v[0], v[1], v[2], v[3]]);
+        }
+        UniformProperty::Int => {
+            let v = value.as_i64().and_then(|v| v.try_into().ok());
+            material.set_int(path, v.ok_or_else(error)?);
+        }
+        UniformProperty::IVec2 => {
+            let v = ints(value, 2).ok_or_else(error)?;
+            material.set_ivec2(path, [v[0], v[1]]);
+        }
+        UniformProperty::IVec3 => {
+            let v = ints(value, 3).ok_or_else(error)?;
+            material.set_ivec3(path, [v[0], v[1], v[2]]);
+        }
+        UniformProperty::IVec4 => {
+            let v = ints(value, 4).ok_or_else(error)?;
+            material.set_ivec4(path, [v[0], v[1], v[2], v[3]]);
+        }
+        UniformProperty::UInt => {
+            let v = value.as_u64().and_then(|v| v.try_into().ok());
+            material.set_uint(path, v.ok_or_else(error)?);
+        }
+        UniformProperty::UVec2 => {
+            let v = uints(value, 2).ok_or_else(error)?;
+            material.set_uvec2(path, [v[0], v[1]]);
+        }
+        UniformProperty::UVec3 => {
+            let v = uints(value, 3).ok_or_else(error)?;
+            material.set_uvec3(path, [v[0], v[1], v[2]]);
+        }
+        UniformProperty::UVec4 => {
+            let v = uints(value, 4).ok_or_else(error)?;
+            material.set_uvec4(path, [v[0], v[1], v[2], v[3]]);
+        }
+        UniformProperty::Bool => {
+            material.set_bool(path, value.as_bool().ok_or_else(error)?);
+        }
+        UniformProperty::BVec2 => {
+            let v = bools(value, 2).ok_or_else(error)?;
+            material.set_bvec2(path, [v[0], v[1]]);
+        }
+        UniformProperty::BVec3 => {
+            let v = bools(value, 3).ok_or_else(error)?;
+            material.set_bvec3(path, [v[0], v[1], v[2]]);
+        }
+        UniformProperty::BVec4 => {
+            let v = bools(value, 4).ok_or_else(error)?;
+            material.set_bvec4(path, [v[0], v[1], v[2], v[3]]);
+        }
+        UniformProperty::Mat3 => {
+            let c = columns(value, 3).ok_or_else(error)?;
+            material.set_mat3(
+                path,
+                cgmath::Matrix3::new(
+                    c[0][0], c[0][1], c[0][2], c[1][0], c[1][1], c[1][2], c[2][0], c[2][1], c[2][2],
+                ),
+            );
+        }
+        UniformProperty::Mat4 => {
+            let c = columns(value, 4).ok_or_else(error)?;
+            material.set_mat4(
+                path,
+                cgmath::Matrix4::new(
+                    c[0][0], c[0][1], c[0][2], c[0][3], c[1][0], c[1][1], c[1][2], c[1][3],
+                    c[2][0], c[2][1], c[2][2], c[2][3], c[3][0], c[3][1], c[3][2], c[3][3],
+                ),
+            );
+        }
+        UniformProperty::Array(element, length) => {
+            let values = value
+                .as_array()
+                .filter(|values| values.len() == *length)
+                .ok_or_else(error)?;
+            for (i, value) in values.iter().enumerate() {
+                let path = format!("{}[{}]", path, i);
+                set_uniform_from_json(material, &path, element, value)
+                    .map_err(|err| err.at(&format!("[{}]", i)))?;
+            }
+        }
+        UniformProperty::Struct { members, .. } => {
+            let values = value.as_object().ok_or_else(error)?;
+            for (name, value) in values {
+                let member = members
+                    .iter()
+                    .find(|(member, _)| member == name)
+                    .map(|(_, member)| member)
+                    .ok_or_else(|| {
+                        ShaderParseError::new(format!("'{}' has no member '{}'", ty, name))
+                    })?;
+                let path = format!("{}.{}", path, name);
+                set_uniform_from_json(material, &path, member, value)
+                    .map_err(|err| err.at(name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn floats(value: &Value, count: usize) -> Option<Vec<f32>> {
+    let values = value.as_array().filter(|values| values.len() == count)?;
+    values.iter().map(|v| Some(v.as_f64()? as f32)).collect()
+}
+
+fn ints(value: &Value, count: usize) -> Option<Vec<i32>> {
+    let values = value.as_array().filter(|values| values.len() == count)?;
+    values
+        .iter()
+        .map(|v| v.as_i64().and_then(|v| v.try_into().ok()))
+        .collect()
+}
+
+fn uints(value: &Value, count: usize) -> Option<Vec<u32>> {
+    let values = value.as_array().filter(|values| values.len() == count)?;
+    values
+        .iter()
+        .map(|v| v.as_u64().and_then(|v| v.try_into().ok()))
+        .collect()
+}
+
+fn bools(value: &Value, count: usize) -> Option<Vec<bool>> {
+    let values = value.as_array().filter(|values| values.len() == count)?;
+    values.iter().map(Value::as_bool).collect()
+}
+
+// a square matrix as `count` columns
+fn columns(value: &Value, count: usize) -> Option<Vec<Vec<f32>>> {
+    let values = value.as_array().filter(|values| values.len() == count)?;
+    values.iter().map(|column| floats(column, count)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture_path(material: &MaterialEntry, name: &str) -> PathBuf {
+        match &material.textures[name] {
+            MaterialTextureJson::Path(path) => path.into(),
+            MaterialTextureJson::Detailed(details) => (&details.path).into(),
+        }
+    }
+
+    #[test]
+    fn texture_paths_are_relative_to_the_file() {
+        let dir = std::env::temp_dir().join("material_file_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let absolute = std::env::temp_dir().join("c.png");
+        let json = format!(
+            r#"{{ "name": "m", "shader": "s", "textures": {{
+                "a": "a.png",
+                "b": {{ "path": "textures/b.png", "color_space": "linear" }},
+                "c": {}
+            }} }}"#,
+            serde_json::to_string(&absolute.display().to_string()).unwrap()
+        );
+        let path = dir.join("m.json");
+        std::fs::write(&path, json).unwrap();
+
+        let material = material_from_file(&path).unwrap();
+        assert_eq!(texture_path(&material, "a"), dir.join("a.png"));
+        assert_eq!(texture_path(&material, "b"), dir.join("textures/b.png"));
+        assert_eq!(texture_path(&material, "c"), absolute);
+    }
+}
@@ -1,6 +1,7 @@
-use wgpu::util::DeviceExt;
-
-use crate::vertex::MeshVertex;
+use crate::geometry::{GeometryArena, MeshAllocation};
+use crate::mesh_processing::optimize::{self, CacheStats};
+use crate::mesh_processing::simplify::{self, LodOptions};
+use crate::vertex::{MeshVertex, VertexLayout};
 use cgmath::prelude::*;
 use cgmath::{Matrix, SquareMatrix};
 
@@ -9,11 +10,25 @@ pub struct Mesh {
     indices: Vec<u32>,
     transform: cgmath::Matrix4<f32>,
     pub material: String,
-    pub vertex_buffer: Option<wgpu::Buffer>,
-    pub index_buffer: Option<wgpu::Buffer>,
+    pub lods: Vec<MeshLod>,
+    bounding_center: cgmath::Point3<f32>,
+    bounding_radius: f32,
     uniform: MeshUniform,
-    uniform_buffer: Option<wgpu::Buffer>,
-    pub bind_group: Option<wgpu::BindGroup>,
+    pub allocation: Option<MeshAllocation>,
+}
+
+pub struct MeshLod {
+    pub first_index: u32,
+    pub index_count: u32,
+    pub threshold: LodThreshold,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LodThreshold {
+    // generated LODs: geometric error relative to the bounding radius
+    Error(f32),
+    // MSFT_lod: smallest fraction of the screen the bounding sphere may cover
+    ScreenCoverage(f32),
 }
 
 #[repr(C)]
@@ -30,45 +45,152 @@ impl Mesh {
         transform: cgmath::Matrix4<f32>,
         material: String,
     ) -> Self {
+        let (bounding_center, bounding_radius) =
+            simplify::bounding_sphere(vertices.iter().map(|v| v.position));
+        let lods = vec![MeshLod {
+            first_index: 0,
+            index_count: indices.len() as u32,
+            threshold: LodThreshold::Error(0.0),
+        }];
         Self {
             vertices,
             indices,
             transform,
             material,
+            lods,
+            bounding_center,
+            bounding_radius,
             uniform: MeshUniform {
                 transform: transform.into(),
                 transform_iv: transform.transpose().invert().unwrap().into(),
             },
-            vertex_buffer: None,
-            index_buffer: None,
-            uniform_buffer: None,
-            bind_group: None,
+            allocation: None,
         }
     }
 
-    pub fn index_count(&self) -> u32 {
-        self.indices.len() as u32
+    pub fn add_lod(&mut self, indices: Vec<u32>, threshold: LodThreshold) {
+        self.lods.push(MeshLod {
+            first_index: self.indices.len() as u32,
+            index_count: indices.len() as u32,
+            threshold,
+        });
+        self.indices.extend(indices);
     }
 
-    pub fn calc_tangents(&mut self) {
+    // for LODs that come with their own vertices, e.g. from MSFT_lod
+    pub fn add_lod_with_vertices(
+        &mut self,
+        vertices: Vec<MeshVertex>,
+        indices: Vec<u32>,
+        threshold: LodThreshold,
+    ) {
+        let base_vertex = self.vertices.len() as u32;
+        self.vertices.extend(vertices);
+        self.add_lod(
+            indices.into_iter().map(|i| i + base_vertex).collect(),
+            threshold,
+        );
+    }
+
+    pub fn generate_lods(&mut self, options: &LodOptions) {
+        let lod0 = &self.lods[0];
+        let lod0_indices = &self.indices
+            [lod0.first_index as usize..(lod0.first_index + lod0.index_count) as usize];
+        for (indices, error) in simplify::generate_lods(&self.vertices, lod0_indices, options) {
+            self.add_lod(indices, LodThreshold::Error(error));
+        }
+    }
+
+    // Reorders the indices of every LOD for the vertex cache and overdraw, then the vertices for
+    // fetch locality. Returns the cache statistics of LOD 0 before and after.
+    pub fn optimize(&mut self) -> (CacheStats, CacheStats) {
         let vertex_count = self.vertices.len();
+        let mut stats = (CacheStats::default(), CacheStats::default());
+        for (level, lod) in self.lods.iter().enumerate() {
+            let range = lod.first_index as usize..(lod.first_index + lod.index_count) as usize;
+            let lod_indices = &self.indices[range.clone()];
+            if level == 0 {
+                stats.0 =
+                    optimize::analyze_vertex_cache(lod_indices, vertex_count, optimize::CACHE_SIZE);
+            }
+            let lod_indices = optimize::optimize_vertex_cache(lod_indices, vertex_count);
+            let lod_indices = optimize::optimize_overdraw(&self.vertices, &lod_indices, 1.05);
+            if level == 0 {
+                stats.1 = optimize::analyze_vertex_cache(
+                    &lod_indices,
+                    vertex_count,
+                    optimize::CACHE_SIZE,
+                );
+            }
+            self.indices[range].copy_from_slice(&lod_indices);
+        }
+        let (vertices, indices) = optimize::optimize_vertex_fetch(&self.vertices, &self.indices);
+        self.vertices = vertices;
+        self.indices = indices;
+        stats
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.lods[0].index_count as usize / 3
+    }
+
+    pub fn world_bounding_sphere(&self) -> (cgmath::Point3<f32>, f32) {
+        let center = self.transform.transform_point(self.bounding_center);
+        let scale = self
+            .transform
+            .x
+            .truncate()
+            .magnitude()
+            .max(self.transform.y.truncate().magnitude())
+            .max(self.transform.z.truncate().magnitude());
+        (center, self.bounding_radius * scale)
+    }
+
+    // None means the mesh is too small to be drawn at all (only happens with MSFT_lod)
+    pub fn select_lod(
+        &self,
+        radius_px: f32,
+        screen_coverage: f32,
+        max_pixel_error: f32,
+    ) -> Option<&MeshLod> {
+        match self.lods.last()?.threshold {
+            LodThreshold::ScreenCoverage(_) => self.lods.iter().find(|lod| match lod.threshold {
+                LodThreshold::ScreenCoverage(min_coverage) => screen_coverage >= min_coverage,
+                LodThreshold::Error(_) => true,
+            }),
+            LodThreshold::Error(_) => self
+                .lods
+                .iter()
+                .rev()
+                .find(|lod| match lod.threshold {
+                    LodThreshold::Error(error) => error * radius_px <= max_pixel_error,
+                    LodThreshold::ScreenCoverage(_) => true,
+                })
+                .or_else(|| self.lods.first()),
+        }
+    }
+
+    // Before the vertices and indices of a primitive go into a mesh, so that LODs with their own
+    // vertices get their own tangents.
+    pub fn calc_tangents(vertices: &mut [MeshVertex], indices: &[u32]) {
+        let vertex_count = vertices.len();
         let mut tangents_sum = vec![cgmath::Vector3::zero(); vertex_count];
 
-        let triangle_count = self.indices.len() / 3;
+        let triangle_count = indices.len() / 3;
         for i in 0..triangle_count {
-            let i0 = self.indices[3 * i] as usize;
-            let i1 = self.indices[3 * i + 1] as usize;
-            let i2 = self.indices[3 * i + 2] as usize;
+            let i0 = indices[3 * i] as usize;
+            let i1 = indices[3 * i + 1] as usize;
+            let i2 = indices[3 * i + 2] as usize;
 
-            let p0: cgmath::Point3<f32> = self.vertices[i0].position.into();
-            let p1: cgmath::Point3<f32> = self.vertices[i1].position.into();
-            let p2: cgmath::Point3<f32> = self.vertices[i2].position.into();
+            let p0: cgmath::Point3<f32> = vertices[i0].position.into();
+            let p1: cgmath::Point3<f32> = vertices[i1].position.into();
+            let p2: cgmath::Point3<f32> = vertices[i2].position.into();
             let e1 = p1 - p0;
             let e2 = p2 - p0;
 
-            let uv0: cgmath::Point2<f32> = self.vertices[i0].texcoords.into();
-            let uv1: cgmath::Point2<f32> = self.vertices[i1].texcoords.into();
-            let uv2: cgmath::Point2<f32> = self.vertices[i2].texcoords.into();
+            let uv0: cgmath::Point2<f32> = vertices[i0].texcoords.into();
+            let uv1: cgmath::Point2<f32> = vertices[i1].texcoords.into();
+            let uv2: cgmath::Point2<f32> = vertices[i2].texcoords.into();
             let u1 = uv1 - uv0;
             let u2 = uv2 - uv0;
 
@@ -84,43 +206,15 @@ impl Mesh {
             tangents_sum[i2] += t;
         }
 
-        for i in 0..vertex_count {
-            let tangent = tangents_sum[i].normalize();
-            let tangent = cgmath::Vector4::new(tangent.x, tangent.y, tangent.z, 1.0);
-            self.vertices[i].tangent = tangent.into();
+        for (vertex, tangent) in vertices.iter_mut().zip(&tangents_sum) {
+            let tangent = tangent.normalize();
+            vertex.tangent = [tangent.x, tangent.y, tangent.z, 1.0];
         }
     }
 
-    pub fn build(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout) {
-        self.vertex_buffer = Some(
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Object Vertex Buffer"),
-                contents: bytemuck::cast_slice(&self.vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            }),
-        );
-        self.index_buffer = Some(
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Object Index Buffer"),
-                contents: bytemuck::cast_slice(&self.indices),
-                usage: wgpu::BufferUsage::INDEX,
-            }),
-        );
-        self.uniform_buffer = Some(
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Object Uniform Buffer"),
-                contents: bytemuck::cast_slice(&[self.uniform]),
-                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-            }),
-        );
-        self.bind_group = Some(device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Object Bing Group"),
-            layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: self.uniform_buffer.as_ref().unwrap().as_entire_binding(),
-            }],
-        }))
+    pub fn build(&mut self, geometry: &mut GeometryArena, vertex_layout: &VertexLayout) {
+        self.allocation =
+            Some(geometry.allocate(&self.vertices, &self.indices, &self.uniform, vertex_layout));
     }
 
     #[rustfmt::skip]
@@ -0,0 +1,5 @@
+// CPU-side processing of mesh data before it is uploaded to the GPU.
+
+pub mod normals;
+pub mod optimize;
+pub mod simplify;
@@ -0,0 +1,300 @@
+use cgmath::prelude::*;
+use std::collections::HashMap;
+
+use crate::vertex::MeshVertex;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum NormalMode {
+    // one normal per face, every triangle gets its own vertices (what glTF asks for)
+    #[default]
+    Flat,
+    // angle-weighted average of the faces around a position, split at edges sharper than the angle
+    Smooth {
+        crease_angle: cgmath::Deg<f32>,
+    },
+}
+
+pub fn generate_normals(
+    vertices: &[MeshVertex],
+    indices: &[u32],
+    mode: NormalMode,
+) -> (Vec<MeshVertex>, Vec<u32>) {
+    match mode {
+        NormalMode::Flat => flat_normals(vertices, indices),
+        NormalMode::Smooth { crease_angle } => smooth_normals(vertices, indices, crease_angle),
+    }
+}
+
+pub fn flat_normals(vertices: &[MeshVertex], indices: &[u32]) -> (Vec<MeshVertex>, Vec<u32>) {
+    let triangle_count = indices.len() / 3;
+    let mut new_vertices = Vec::with_capacity(triangle_count * 3);
+    let mut new_indices = Vec::with_capacity(triangle_count * 3);
+
+    for tri in indices.chunks_exact(3) {
+        let normal = face_normal(vertices, tri).unwrap_or_else(default_normal);
+        for &i in tri {
+            let mut vertex = vertices[i as usize];
+            vertex.normal = normal.into();
+            new_indices.push(new_vertices.len() as u32);
+            new_vertices.push(vertex);
+        }
+    }
+
+    (new_vertices, new_indices)
+}
+
+pub fn smooth_normals(
+    vertices: &[MeshVertex],
+    indices: &[u32],
+    crease_angle: cgmath::Deg<f32>,
+) -> (Vec<MeshVertex>, Vec<u32>) {
+    let cos_crease = cgmath::Rad::from(crease_angle).0.cos();
+    let triangles: Vec<&[u32]> = indices.chunks_exact(3).collect();
+    let face_normals: Vec<Option<cgmath::Vector3<f32>>> = triangles
+        .iter()
+        .map(|tri| face_normal(vertices, tri))
+        .collect();
+
+    // vertices that share a position are treated as one point, so UV seams don't become hard edges
+    let mut position_ids = HashMap::new();
+    let vertex_position_id: Vec<usize> = vertices
+        .iter()
+        .map(|v| {
+            let next_id = position_ids.len();
+            *position_ids
+                .entry(position_key(v.position))
+                .or_insert(next_id)
+        })
+        .collect();
+
+    // (face, corner angle) of every triangle touching a position
+    let mut position_faces = vec![vec![]; position_ids.len()];
+    for (face, tri) in triangles.iter().enumerate() {
+        if face_normals[face].is_none() {
+            continue;
+        }
+        for corner in 0..3 {
+            let angle = corner_angle(vertices, tri, corner);
+            position_faces[vertex_position_id[tri[corner] as usize]].push((face, angle));
+        }
+    }
+
+    let mut new_vertices: Vec<MeshVertex> = Vec::with_capacity(vertices.len());
+    let mut new_indices = Vec::with_capacity(indices.len());
+    let mut emitted = HashMap::new();
+
+    for (face, tri) in triangles.iter().enumerate() {
+        for &i in tri.iter() {
+            let normal = match face_normals[face] {
+                Some(this_normal) => {
+                    let sum = position_faces[vertex_position_id[i as usize]]
+                        .iter()
+                        .filter_map(|&(other, angle)| {
+                            let other_normal = face_normals[other].unwrap();
+                            if other_normal.dot(this_normal) >= cos_crease {
+                                Some(other_normal * angle)
+                            } else {
+                                None
+                            }
+                        })
+                        .fold(cgmath::Vector3::zero(), |acc, n| acc + n);
+                    if sum.magnitude2() > 0.0 {
+                        sum.normalize()
+                    } else {
+                        this_normal
+                    }
+                }
+                None => default_normal(),
+            };
+
+            // a vertex is only split when the crease gives it a different normal on this face
+            let key = (i, position_key(normal.into()));
+            let index = *emitted.entry(key).or_insert_with(|| {
+                let mut vertex = vertices[i as usize];
+                vertex.normal = normal.into();
+                new_vertices.push(vertex);
+                new_vertices.len() as u32 - 1
+            });
+            new_indices.push(index);
+        }
+    }
+
+    (new_vertices, new_indices)
+}
+
+fn face_normal(vertices: &[MeshVertex], tri: &[u32]) -> Option<cgmath::Vector3<f32>> {
+    let p0: cgmath::Point3<f32> = vertices[tri[0] as usize].position.into();
+    let p1: cgmath::Point3<f32> = vertices[tri[1] as usize].position.into();
+    let p2: cgmath::Point3<f32> = vertices[tri[2] as usize].position.into();
+    let normal = (p1 - p0).cross(p2 - p0);
+    if normal.magnitude2() > f32::EPSILON * f32::EPSILON {
+        Some(normal.normalize())
+    } else {
+        None
+    }
+}
+
+fn corner_angle(vertices: &[MeshVertex], tri: &[u32], corner: usize) -> f32 {
+    let p: cgmath::Point3<f32> = vertices[tri[corner] as usize].position.into();
+    let a: cgmath::Point3<f32> = vertices[tri[(corner + 1) % 3] as usize].position.into();
+    let b: cgmath::Point3<f32> = vertices[tri[(corner + 2) % 3] as usize].position.into();
+    let (ea, eb) = (a - p, b - p);
+    if ea.magnitude2() == 0.0 || eb.magnitude2() == 0.0 {
+        return 0.0;
+    }
+    ea.angle(eb).0
+}
+
+fn position_key(position: [f32; 3]) -> [u32; 3] {
+    [
+        position[0].to_bits(),
+        position[1].to_bits(),
+        position[2].to_bits(),
+    ]
+}
+
+fn default_normal() -> cgmath::Vector3<f32> {
+    MeshVertex::default().normal.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AXES: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+        ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]),
+    ];
+
+    fn vertex(position: [f32; 3], texcoords: [f32; 2]) -> MeshVertex {
+        MeshVertex {
+            position,
+            texcoords,
+            ..Default::default()
+        }
+    }
+
+    // 4 vertices per face, like a cube with a UV seam on every edge
+    fn split_cube() -> (Vec<MeshVertex>, Vec<u32>) {
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        for &(n, u, v) in AXES.iter() {
+            let base = vertices.len() as u32;
+            for &(su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter() {
+                let position = [
+                    n[0] + su * u[0] + sv * v[0],
+                    n[1] + su * u[1] + sv * v[1],
+                    n[2] + su * u[2] + sv * v[2],
+                ];
+                vertices.push(vertex(position, [su, sv]));
+            }
+            indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+        }
+        (vertices, indices)
+    }
+
+    // the 8 corners shared by all faces
+    fn welded_cube() -> (Vec<MeshVertex>, Vec<u32>) {
+        let (split_vertices, split_indices) = split_cube();
+        let mut vertices = vec![];
+        let mut ids = HashMap::new();
+        let indices = split_indices
+            .iter()
+            .map(|&i| {
+                let v = split_vertices[i as usize];
+                *ids.entry(position_key(v.position)).or_insert_with(|| {
+                    vertices.push(vertex(v.position, [0.0, 0.0]));
+                    vertices.len() as u32 - 1
+                })
+            })
+            .collect();
+        (vertices, indices)
+    }
+
+    fn assert_near(a: [f32; 3], b: [f32; 3]) {
+        let d = cgmath::Vector3::from(a) - cgmath::Vector3::from(b);
+        assert!(d.magnitude() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    // every triangle's vertices carry the normal of the cube face it lies on
+    fn assert_face_normals(vertices: &[MeshVertex], indices: &[u32]) {
+        for (face, tri) in indices.chunks_exact(6).enumerate() {
+            for &i in tri {
+                assert_near(vertices[i as usize].normal, AXES[face].0);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_cube() {
+        let (vertices, indices) = welded_cube();
+        let (vertices, indices) = generate_normals(&vertices, &indices, NormalMode::Flat);
+        assert_eq!(vertices.len(), 36);
+        assert_eq!(indices.len(), 36);
+        assert_face_normals(&vertices, &indices);
+    }
+
+    #[test]
+    fn smooth_welds_split_vertices() {
+        // two quads folded by 30 degrees along x = 1, the fold vertices are duplicated with their
+        // own texcoords
+        let (s, c) = cgmath::Deg(30.0f32).sin_cos();
+        let vertices = vec![
+            vertex([0.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([1.0, 0.0, 0.0], [1.0, 0.0]),
+            vertex([1.0, 1.0, 0.0], [1.0, 1.0]),
+            vertex([0.0, 1.0, 0.0], [0.0, 1.0]),
+            vertex([1.0, 0.0, 0.0], [0.0, 0.0]),
+            vertex([1.0 + c, 0.0, s], [1.0, 0.0]),
+            vertex([1.0 + c, 1.0, s], [1.0, 1.0]),
+            vertex([1.0, 1.0, 0.0], [0.0, 1.0]),
+        ];
+        let indices = vec![0, 1, 2, 0, 2, 3, 4, 5, 6, 4, 6, 7];
+        let (new_vertices, new_indices) = generate_normals(
+            &vertices,
+            &indices,
+            NormalMode::Smooth {
+                crease_angle: cgmath::Deg(60.0),
+            },
+        );
+        // no vertex is split, the texcoords still tell the copies apart
+        assert_eq!(new_vertices.len(), vertices.len());
+        assert_eq!(new_indices.len(), indices.len());
+
+        let normal_of = |position: [f32; 3], texcoords: [f32; 2]| {
+            new_vertices
+                .iter()
+                .find(|v| v.position == position && v.texcoords == texcoords)
+                .unwrap()
+                .normal
+        };
+        let fold: [f32; 3] = (cgmath::vec3(0.0, 0.0, 1.0) + cgmath::vec3(-s, 0.0, c))
+            .normalize()
+            .into();
+        for &y in [0.0, 1.0].iter() {
+            assert_near(normal_of([1.0, y, 0.0], [1.0, y]), fold);
+            assert_near(normal_of([1.0, y, 0.0], [0.0, y]), fold);
+        }
+        assert_near(normal_of([0.0, 0.0, 0.0], [0.0, 0.0]), [0.0, 0.0, 1.0]);
+        assert_near(normal_of([1.0 + c, 0.0, s], [1.0, 0.0]), [-s, 0.0, c]);
+    }
+
+    #[test]
+    fn crease_keeps_cube_edges_hard() {
+        let (vertices, indices) = welded_cube();
+        let (vertices, indices) = generate_normals(
+            &vertices,
+            &indices,
+            NormalMode::Smooth {
+                crease_angle: cgmath::Deg(60.0),
+            },
+        );
+        // each corner is split once per face meeting there
+        assert_eq!(vertices.len(), 24);
+        assert_face_normals(&vertices, &indices);
+    }
+}
@@ -0,0 +1,390 @@
+use cgmath::prelude::*;
+
+use crate::vertex::MeshVertex;
+
+// size of the simulated post-transform cache used to measure and reorder indices
+pub const CACHE_SIZE: usize = 16;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    // average cache miss ratio, transformed vertices per triangle
+    pub acmr: f32,
+    // average transform to vertex ratio, transformed vertices per referenced vertex
+    pub atvr: f32,
+}
+
+// FIFO cache simulation, close to how most GPUs reuse transformed vertices
+pub fn analyze_vertex_cache(indices: &[u32], vertex_count: usize, cache_size: usize) -> CacheStats {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return CacheStats::default();
+    }
+
+    let mut cache_timestamps = vec![0usize; vertex_count];
+    let mut referenced = vec![false; vertex_count];
+    let mut timestamp = cache_size + 1;
+    let mut transformed = 0;
+    for &i in indices {
+        let i = i as usize;
+        if timestamp - cache_timestamps[i] > cache_size {
+            cache_timestamps[i] = timestamp;
+            timestamp += 1;
+            transformed += 1;
+        }
+        referenced[i] = true;
+    }
+    let unique = referenced.iter().filter(|&&r| r).count().max(1);
+
+    CacheStats {
+        acmr: transformed as f32 / triangle_count as f32,
+        atvr: transformed as f32 / unique as f32,
+    }
+}
+
+// Tom Forsyth's linear-speed vertex cache optimisation: greedily emit the triangle whose vertices
+// score best, favouring recently used vertices and vertices with few triangles left.
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    const LRU_SIZE: usize = 32;
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return indices.to_vec();
+    }
+
+    let mut vertex_triangles = vec![vec![]; vertex_count];
+    for (t, tri) in indices.chunks_exact(3).enumerate() {
+        for &i in tri {
+            vertex_triangles[i as usize].push(t);
+        }
+    }
+    let mut remaining_valence: Vec<usize> = vertex_triangles.iter().map(|t| t.len()).collect();
+    let mut cache_position: Vec<Option<usize>> = vec![None; vertex_count];
+    let mut vertex_score: Vec<f32> = (0..vertex_count)
+        .map(|i| forsyth_score(None, remaining_valence[i], LRU_SIZE))
+        .collect();
+    let mut triangle_score: Vec<f32> = indices
+        .chunks_exact(3)
+        .map(|tri| tri.iter().map(|&i| vertex_score[i as usize]).sum())
+        .collect();
+    let mut emitted = vec![false; triangle_count];
+
+    let mut lru: Vec<u32> = Vec::with_capacity(LRU_SIZE + 3);
+    let mut result = Vec::with_capacity(indices.len());
+    let mut next_unemitted = 0;
+    let mut best_triangle =
+        (0..triangle_count).max_by(|&a, &b| triangle_score[a].total_cmp(&triangle_score[b]));
+
+    while let Some(t) = best_triangle {
+        emitted[t] = true;
+        let tri = &indices[3 * t..3 * t + 3];
+        result.extend_from_slice(tri);
+
+        for &i in tri {
+            let i = i as usize;
+            remaining_valence[i] -= 1;
+            vertex_triangles[i].retain(|&other| other != t);
+        }
+
+        // move the triangle's vertices to the front of the LRU
+        for &i in tri.iter().rev() {
+            if let Some(pos) = lru.iter().position(|&v| v == i) {
+                lru.remove(pos);
+            }
+            lru.insert(0, i);
+        }
+        let evicted: Vec<u32> = if lru.len() > LRU_SIZE {
+            lru.split_off(LRU_SIZE)
+        } else {
+            vec![]
+        };
+
+        for &i in evicted.iter() {
+            cache_position[i as usize] = None;
+        }
+        for (pos, &i) in lru.iter().enumerate() {
+            cache_position[i as usize] = Some(pos);
+        }
+
+        // rescore everything touched, then pick the best triangle around the cache
+        best_triangle = None;
+        let mut best_score = -1.0;
+        for &i in lru.iter().chain(evicted.iter()) {
+            let i = i as usize;
+            let new_score = forsyth_score(cache_position[i], remaining_valence[i], LRU_SIZE);
+            let diff = new_score - vertex_score[i];
+            vertex_score[i] = new_score;
+            for &other in &vertex_triangles[i] {
+                triangle_score[other] += diff;
+            }
+        }
+        for &i in lru.iter() {
+            for &other in &vertex_triangles[i as usize] {
+                if triangle_score[other] > best_score {
+                    best_score = triangle_score[other];
+                    best_triangle = Some(other);
+                }
+            }
+        }
+
+        if best_triangle.is_none() {
+            // nothing left around the cache, restart from the first triangle not emitted yet
+            while next_unemitted < triangle_count && emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+            if next_unemitted < triangle_count {
+                best_triangle = Some(next_unemitted);
+            }
+        }
+    }
+
+    result
+}
+
+fn forsyth_score(cache_position: Option<usize>, remaining_valence: usize, lru_size: usize) -> f32 {
+    if remaining_valence == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        // the last triangle's vertices get a fixed score so that strips don't get favoured
+        Some(pos) if pos < 3 => 0.75,
+        Some(pos) => (1.0 - (pos - 3) as f32 / (lru_size - 3) as f32).powf(1.5),
+        None => 0.0,
+    };
+    cache_score + 2.0 * (remaining_valence as f32).powf(-0.5)
+}
+
+// Splits the (already cache optimized) triangles into clusters wherever the cache gets flushed,
+// then sorts the clusters so that outward facing ones come first. Those are the ones most likely
+// to occlude the others, so less pixels get shaded twice. Clusters whose cache efficiency would
+// get worse than `threshold` times the original are merged with their predecessor.
+pub fn optimize_overdraw(vertices: &[MeshVertex], indices: &[u32], threshold: f32) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return indices.to_vec();
+    }
+
+    let clusters = hard_cluster_boundaries(indices, vertices.len(), threshold);
+    let mesh_center = centroid(vertices, indices);
+
+    let mut sorted: Vec<(f32, std::ops::Range<usize>)> = clusters
+        .windows(2)
+        .map(|bounds| {
+            let range = bounds[0]..bounds[1];
+            let cluster_indices = &indices[3 * range.start..3 * range.end];
+            let center = centroid(vertices, cluster_indices);
+            let mut normal = cgmath::Vector3::zero();
+            for tri in cluster_indices.chunks_exact(3) {
+                let p0 = cgmath::Point3::from(vertices[tri[0] as usize].position);
+                let p1 = cgmath::Point3::from(vertices[tri[1] as usize].position);
+                let p2 = cgmath::Point3::from(vertices[tri[2] as usize].position);
+                normal += (p1 - p0).cross(p2 - p0);
+            }
+            let normal = if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else {
+                normal
+            };
+            ((center - mesh_center).dot(normal), range)
+        })
+        .collect();
+    // NaN or infinite positions make the keys NaN, total_cmp still gives them an order
+    sorted.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    sorted
+        .into_iter()
+        .flat_map(|(_, range)| indices[3 * range.start..3 * range.end].iter().cloned())
+        .collect()
+}
+
+// Triangle offsets where clusters start, plus the triangle count at the end.
+fn hard_cluster_boundaries(indices: &[u32], vertex_count: usize, threshold: f32) -> Vec<usize> {
+    let triangle_count = indices.len() / 3;
+    let mut cache_timestamps = vec![0usize; vertex_count];
+    let mut timestamp = CACHE_SIZE + 1;
+    let mut boundaries = vec![0];
+    let mut cluster_misses = 0;
+    let mut cluster_start = 0;
+    let total_acmr = analyze_vertex_cache(indices, vertex_count, CACHE_SIZE).acmr;
+
+    for t in 0..triangle_count {
+        let mut misses = 0;
+        for &i in &indices[3 * t..3 * t + 3] {
+            let i = i as usize;
+            if timestamp - cache_timestamps[i] > CACHE_SIZE {
+                cache_timestamps[i] = timestamp;
+                timestamp += 1;
+                misses += 1;
+            }
+        }
+
+        // a triangle missing all three vertices means the cache has been flushed, so this is a
+        // place where the order can change without losing anything
+        if misses == 3 && t > cluster_start {
+            let cluster_acmr = cluster_misses as f32 / (t - cluster_start) as f32;
+            if cluster_acmr <= total_acmr * threshold {
+                boundaries.push(t);
+                cluster_start = t;
+                cluster_misses = 0;
+            }
+        }
+        cluster_misses += misses;
+    }
+
+    boundaries.push(triangle_count);
+    boundaries
+}
+
+fn centroid(vertices: &[MeshVertex], indices: &[u32]) -> cgmath::Point3<f32> {
+    let mut sum = cgmath::Vector3::zero();
+    for &i in indices {
+        sum += cgmath::Vector3::from(vertices[i as usize].position);
+    }
+    cgmath::Point3::from_vec(sum / indices.len().max(1) as f32)
+}
+
+// Reorders vertices in the order they are first referenced, so vertex fetches walk the buffer
+// linearly. Unreferenced vertices are dropped.
+pub fn optimize_vertex_fetch(
+    vertices: &[MeshVertex],
+    indices: &[u32],
+) -> (Vec<MeshVertex>, Vec<u32>) {
+    let mut remap: Vec<Option<u32>> = vec![None; vertices.len()];
+    let mut new_vertices = Vec::with_capacity(vertices.len());
+    let new_indices = indices
+        .iter()
+        .map(|&i| {
+            *remap[i as usize].get_or_insert_with(|| {
+                new_vertices.push(vertices[i as usize]);
+                new_vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+    (new_vertices, new_indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an n x n quad grid on a bumpy surface, triangles in row order
+    fn grid(n: u32) -> (Vec<MeshVertex>, Vec<u32>) {
+        let mut vertices = vec![];
+        for y in 0..=n {
+            for x in 0..=n {
+                vertices.push(MeshVertex {
+                    position: [x as f32, y as f32, ((x * 7 + y * 3) % 5) as f32 * 0.1],
+                    ..Default::default()
+                });
+            }
+        }
+        let mut indices = vec![];
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                indices.extend_from_slice(&[i, i + 1, i + n + 2, i, i + n + 2, i + n + 1]);
+            }
+        }
+        (vertices, indices)
+    }
+
+    // the same triangles in a scattered order
+    fn scrambled(indices: &[u32]) -> Vec<u32> {
+        let triangle_count = indices.len() / 3;
+        (0..triangle_count)
+            .flat_map(|t| {
+                let t = t * 7919 % triangle_count;
+                indices[3 * t..3 * t + 3].to_vec()
+            })
+            .collect()
+    }
+
+    // triangles rotated to start at their smallest index, keeping the winding, then sorted
+    fn triangle_set(indices: &[u32]) -> Vec<[u32; 3]> {
+        let mut triangles: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|tri| {
+                let first = (0..3).min_by_key(|&k| tri[k]).unwrap();
+                [tri[first], tri[(first + 1) % 3], tri[(first + 2) % 3]]
+            })
+            .collect();
+        triangles.sort_unstable();
+        triangles
+    }
+
+    fn acmr(indices: &[u32], vertex_count: usize) -> f32 {
+        analyze_vertex_cache(indices, vertex_count, CACHE_SIZE).acmr
+    }
+
+    #[test]
+    fn vertex_cache_keeps_triangles() {
+        let (vertices, indices) = grid(16);
+        let indices = scrambled(&indices);
+        let optimized = optimize_vertex_cache(&indices, vertices.len());
+        assert_eq!(triangle_set(&optimized), triangle_set(&indices));
+    }
+
+    #[test]
+    fn vertex_cache_doesnt_raise_acmr() {
+        let (vertices, indices) = grid(16);
+        for indices in [indices.clone(), scrambled(&indices)].iter() {
+            let before = acmr(indices, vertices.len());
+            let after = acmr(
+                &optimize_vertex_cache(indices, vertices.len()),
+                vertices.len(),
+            );
+            assert!(after <= before, "ACMR went from {} to {}", before, after);
+        }
+    }
+
+    #[test]
+    fn overdraw_keeps_triangles_and_cache_threshold() {
+        let (vertices, indices) = grid(16);
+        let indices = optimize_vertex_cache(&scrambled(&indices), vertices.len());
+        let threshold = 1.05;
+        let optimized = optimize_overdraw(&vertices, &indices, threshold);
+        assert_eq!(triangle_set(&optimized), triangle_set(&indices));
+        let (before, after) = (
+            acmr(&indices, vertices.len()),
+            acmr(&optimized, vertices.len()),
+        );
+        assert!(
+            after <= before * threshold + 1e-4,
+            "ACMR went from {} to {}",
+            before,
+            after
+        );
+    }
+
+    #[test]
+    fn vertex_fetch_remaps_indices() {
+        let (vertices, indices) = grid(8);
+        let indices = scrambled(&indices);
+        let (new_vertices, new_indices) = optimize_vertex_fetch(&vertices, &indices);
+        assert_eq!(new_vertices.len(), vertices.len());
+        assert_eq!(new_indices.len(), indices.len());
+        // every index still points at the same vertex
+        for (&old, &new) in indices.iter().zip(&new_indices) {
+            assert_eq!(
+                new_vertices[new as usize].position,
+                vertices[old as usize].position
+            );
+        }
+        // and vertices are first used in buffer order
+        let mut next = 0;
+        for &i in &new_indices {
+            assert!(i <= next);
+            if i == next {
+                next += 1;
+            }
+        }
+        assert_eq!(next as usize, new_vertices.len());
+    }
+
+    #[test]
+    fn nan_positions_keep_triangles() {
+        let (mut vertices, indices) = grid(16);
+        vertices[10].position = [f32::NAN; 3];
+        let indices = optimize_vertex_cache(&scrambled(&indices), vertices.len());
+        let optimized = optimize_overdraw(&vertices, &indices, 1.05);
+        assert_eq!(triangle_set(&optimized), triangle_set(&indices));
+    }
+}
@@ -0,0 +1,537 @@
+use cgmath::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use crate::vertex::MeshVertex;
+
+#[derive(Copy, Clone, Debug)]
+pub struct LodOptions {
+    // number of LODs generated besides the original mesh
+    pub levels: usize,
+    // each LOD aims for this fraction of the previous LOD's triangles
+    pub ratio: f32,
+    // largest error allowed for a LOD, relative to the mesh radius
+    pub max_error: f32,
+}
+
+impl Default for LodOptions {
+    fn default() -> Self {
+        Self {
+            levels: 3,
+            ratio: 0.5,
+            max_error: 0.05,
+        }
+    }
+}
+
+// Returns the index buffer and relative error of every generated LOD, coarsest last.
+// LODs that can't remove at least a tenth of the previous triangles are dropped.
+pub fn generate_lods(
+    vertices: &[MeshVertex],
+    indices: &[u32],
+    options: &LodOptions,
+) -> Vec<(Vec<u32>, f32)> {
+    let mut lods: Vec<(Vec<u32>, f32)> = vec![];
+    for _ in 0..options.levels {
+        let prev_indices = lods.last().map_or(indices, |(lod, _)| lod.as_slice());
+        let prev_error = lods.last().map_or(0.0, |(_, error)| *error);
+        let target_index_count = (prev_indices.len() as f32 * options.ratio) as usize / 3 * 3;
+        let (lod_indices, error) = simplify(
+            vertices,
+            prev_indices,
+            target_index_count,
+            options.max_error,
+        );
+        if lod_indices.is_empty() || lod_indices.len() * 10 > prev_indices.len() * 9 {
+            break;
+        }
+        // errors are measured against the previous LOD, so they add up
+        lods.push((lod_indices, prev_error + error));
+    }
+    lods
+}
+
+// Largest difference of any attribute component for two vertices at one position to be merged.
+const ATTRIBUTE_TOLERANCE: f32 = 1e-3;
+
+// Quadric error metric edge collapse (Garland & Heckbert). Vertices are only ever collapsed onto
+// other existing vertices, so the vertex buffer is shared by all LODs and only the indices change.
+// Vertices at one position whose attributes match are merged first, the ones left at a shared
+// position sit on an attribute seam. Those only move along the seam, every copy onto the copy at
+// the other end of its own edge, so both sides keep their attributes. Border vertices stay in
+// place. Returns the new indices and the error of the worst collapse, relative to the mesh radius.
+pub fn simplify(
+    vertices: &[MeshVertex],
+    indices: &[u32],
+    target_index_count: usize,
+    target_error: f32,
+) -> (Vec<u32>, f32) {
+    let scale = mesh_radius(vertices, indices);
+    if scale == 0.0 || indices.len() <= target_index_count {
+        return (indices.to_vec(), 0.0);
+    }
+    let max_cost = (target_error as f64 * scale as f64).powi(2);
+
+    let (position_of, mut remap) = weld_vertices(vertices);
+    let mut indices: Vec<u32> = indices.iter().map(|&i| remap[i as usize]).collect();
+    let border = border_positions(&indices, &position_of);
+
+    // indexed by position, the geometric error doesn't care which copy is moved
+    let mut quadrics = vec![Quadric::default(); vertices.len()];
+    for tri in indices.chunks_exact(3) {
+        let quadric = Quadric::from_triangle(vertices, tri);
+        for &i in tri {
+            quadrics[position_of[i as usize] as usize].add(&quadric);
+        }
+    }
+
+    let mut worst_cost = 0.0f64;
+
+    while indices.len() > target_index_count {
+        let mut vertex_triangles = vec![vec![]; vertices.len()];
+        // the vertices still used at each position
+        let mut copies = vec![vec![]; vertices.len()];
+        for (t, tri) in indices.chunks_exact(3).enumerate() {
+            for &i in tri {
+                if vertex_triangles[i as usize].is_empty() {
+                    copies[position_of[i as usize] as usize].push(i);
+                }
+                vertex_triangles[i as usize].push(t);
+            }
+        }
+
+        let mut collapses = vec![];
+        let mut visited_edges = HashSet::new();
+        for tri in indices.chunks_exact(3) {
+            for e in 0..3 {
+                let (a, b) = (tri[e], tri[(e + 1) % 3]);
+                if !visited_edges.insert((a.min(b), a.max(b))) {
+                    continue;
+                }
+                let mut best: Option<(f64, u32, u32)> = None;
+                for &(from, to) in &[(a, b), (b, a)] {
+                    let (from_position, to_position) = (
+                        position_of[from as usize] as usize,
+                        position_of[to as usize] as usize,
+                    );
+                    if border[from_position] {
+                        continue;
+                    }
+                    let mut quadric = quadrics[from_position];
+                    quadric.add(&quadrics[to_position]);
+                    let cost = quadric.error(vertices[to as usize].position);
+                    // degenerate input can give NaN or infinite errors, those edges are left alone
+                    if !cost.is_finite() {
+                        continue;
+                    }
+                    let cost = cost.max(0.0);
+                    if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                        best = Some((cost, from, to));
+                    }
+                }
+                if let Some(collapse) = best {
+                    collapses.push(collapse);
+                }
+            }
+        }
+        collapses.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+        let triangle_count = indices.len() / 3;
+        let target_triangle_count = target_index_count / 3;
+        let mut removed_triangles = 0;
+        // by position, so the copies of a position all move in the same pass
+        let mut touched = vec![false; vertices.len()];
+        for (cost, from, to) in collapses {
+            if cost > max_cost || triangle_count - removed_triangles <= target_triangle_count {
+                break;
+            }
+            let (from_position, to_position) = (
+                position_of[from as usize] as usize,
+                position_of[to as usize] as usize,
+            );
+            if touched[from_position] || touched[to_position] {
+                continue;
+            }
+            let moves = match collapse_moves(
+                &indices,
+                &remap,
+                &vertex_triangles,
+                &position_of,
+                &copies[from_position],
+                to_position,
+            ) {
+                Some(moves) => moves,
+                None => continue,
+            };
+            if moves.iter().any(|&(from, to)| {
+                flips_triangle(
+                    vertices,
+                    &indices,
+                    &remap,
+                    &vertex_triangles[from as usize],
+                    from,
+                    to,
+                )
+            }) {
+                continue;
+            }
+
+            for &(from, to) in &moves {
+                removed_triangles += vertex_triangles[from as usize]
+                    .iter()
+                    .filter(|&&t| {
+                        indices[3 * t..3 * t + 3]
+                            .iter()
+                            .any(|&i| resolve(&remap, i) == to)
+                    })
+                    .count();
+            }
+            for &(from, to) in &moves {
+                remap[from as usize] = to;
+            }
+            let quadric = quadrics[from_position];
+            quadrics[to_position].add(&quadric);
+            touched[from_position] = true;
+            touched[to_position] = true;
+            worst_cost = worst_cost.max(cost);
+        }
+
+        if removed_triangles == 0 {
+            break;
+        }
+        indices = indices
+            .chunks_exact(3)
+            .map(|tri| {
+                [
+                    resolve(&remap, tri[0]),
+                    resolve(&remap, tri[1]),
+                    resolve(&remap, tri[2]),
+                ]
+            })
+            .filter(|tri| tri[0] != tri[1] && tri[1] != tri[2] && tri[2] != tri[0])
+            .flat_map(|tri| tri.to_vec())
+            .collect();
+    }
+
+    (indices, (worst_cost.sqrt() / scale as f64) as f32)
+}
+
+pub fn mesh_radius(vertices: &[MeshVertex], indices: &[u32]) -> f32 {
+    bounding_sphere(indices.iter().map(|&i| vertices[i as usize].position)).1
+}
+
+// Center of the AABB and the distance to the farthest point, good enough for LOD decisions.
+pub fn bounding_sphere<I: Iterator<Item = [f32; 3]> + Clone>(
+    positions: I,
+) -> (cgmath::Point3<f32>, f32) {
+    let mut min = cgmath::Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = cgmath::Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+    let mut count = 0;
+    for p in positions.clone() {
+        for k in 0..3 {
+            min[k] = min[k].min(p[k]);
+            max[k] = max[k].max(p[k]);
+        }
+        count += 1;
+    }
+    if count == 0 {
+        return (cgmath::Point3::origin(), 0.0);
+    }
+    let center = cgmath::Point3::from_vec((min + max) * 0.5);
+    let radius = positions
+        .map(|p| (cgmath::Point3::from(p) - center).magnitude())
+        .fold(0.0, f32::max);
+    (center, radius)
+}
+
+fn resolve(remap: &[u32], mut i: u32) -> u32 {
+    while remap[i as usize] != i {
+        i = remap[i as usize];
+    }
+    i
+}
+
+// Maps every vertex to the first vertex with exactly the same position, and to the first vertex
+// there whose attributes also match.
+fn weld_vertices(vertices: &[MeshVertex]) -> (Vec<u32>, Vec<u32>) {
+    let mut at_position: HashMap<[u32; 3], Vec<u32>> = HashMap::new();
+    let mut position_of = Vec::with_capacity(vertices.len());
+    let mut remap = Vec::with_capacity(vertices.len());
+    for (i, v) in vertices.iter().enumerate() {
+        let key = [
+            v.position[0].to_bits(),
+            v.position[1].to_bits(),
+            v.position[2].to_bits(),
+        ];
+        let welded = at_position.entry(key).or_default();
+        position_of.push(*welded.first().unwrap_or(&(i as u32)));
+        match welded
+            .iter()
+            .find(|&&j| attributes_match(v, &vertices[j as usize]))
+        {
+            Some(&j) => remap.push(j),
+            None => {
+                welded.push(i as u32);
+                remap.push(i as u32);
+            }
+        }
+    }
+    (position_of, remap)
+}
+
+fn attributes_match(a: &MeshVertex, b: &MeshVertex) -> bool {
+    let near = |x: &[f32], y: &[f32]| {
+        x.iter()
+            .zip(y)
+            .all(|(x, y)| (x - y).abs() <= ATTRIBUTE_TOLERANCE)
+    };
+    near(&a.texcoords, &b.texcoords)
+        && near(&a.normal, &b.normal)
+        && near(&a.tangent, &b.tangent)
+        && near(&a.color, &b.color)
+}
+
+// Returns whether each position sits on a border (an edge only used by one triangle).
+fn border_positions(indices: &[u32], position_of: &[u32]) -> Vec<bool> {
+    let mut edge_uses: HashMap<(u32, u32), u32> = HashMap::new();
+    for tri in indices.chunks_exact(3) {
+        for e in 0..3 {
+            let a = position_of[tri[e] as usize];
+            let b = position_of[tri[(e + 1) % 3] as usize];
+            *edge_uses.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+    let mut border = vec![false; position_of.len()];
+    for (&(a, b), &uses) in &edge_uses {
+        if uses == 1 {
+            border[a as usize] = true;
+            border[b as usize] = true;
+        }
+    }
+    border
+}
+
+// The (from, to) vertex pairs moving a position onto another one: every copy at the position goes
+// to the one vertex at the other position its triangles use. None when a copy has no edge to the
+// other position or edges to several copies there, it would have to take attributes it doesn't
+// have.
+fn collapse_moves(
+    indices: &[u32],
+    remap: &[u32],
+    vertex_triangles: &[Vec<usize>],
+    position_of: &[u32],
+    from_copies: &[u32],
+    to_position: usize,
+) -> Option<Vec<(u32, u32)>> {
+    let mut moves = Vec::with_capacity(from_copies.len());
+    for &from in from_copies {
+        let mut to = None;
+        for &t in &vertex_triangles[from as usize] {
+            for &i in &indices[3 * t..3 * t + 3] {
+                let i = resolve(remap, i);
+                if position_of[i as usize] as usize == to_position {
+                    if to.is_some_and(|to| to != i) {
+                        return None;
+                    }
+                    to = Some(i);
+                }
+            }
+        }
+        moves.push((from, to?));
+    }
+    Some(moves)
+}
+
+fn flips_triangle(
+    vertices: &[MeshVertex],
+    indices: &[u32],
+    remap: &[u32],
+    triangles: &[usize],
+    from: u32,
+    to: u32,
+) -> bool {
+    let position = |i: u32| cgmath::Point3::from(vertices[i as usize].position);
+    for &t in triangles {
+        let tri = [
+            resolve(remap, indices[3 * t]),
+            resolve(remap, indices[3 * t + 1]),
+            resolve(remap, indices[3 * t + 2]),
+        ];
+        if tri.contains(&to) {
+            // this one becomes degenerate and is removed
+            continue;
+        }
+        let moved = [
+            if tri[0] == from { to } else { tri[0] },
+            if tri[1] == from { to } else { tri[1] },
+            if tri[2] == from { to } else { tri[2] },
+        ];
+        let before =
+            (position(tri[1]) - position(tri[0])).cross(position(tri[2]) - position(tri[0]));
+        let after = (position(moved[1]) - position(moved[0]))
+            .cross(position(moved[2]) - position(moved[0]));
+        if before.dot(after) <= 0.0 {
+            return true;
+        }
+    }
+    false
+}
+
+#[derive(Copy, Clone, Default)]
+struct Quadric {
+    // symmetric 3x3 part, then the linear part and the constant
+    a00: f64,
+    a01: f64,
+    a02: f64,
+    a11: f64,
+    a12: f64,
+    a22: f64,
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    c: f64,
+    weight: f64,
+}
+
+impl Quadric {
+    fn from_triangle(vertices: &[MeshVertex], tri: &[u32]) -> Self {
+        let p0 = cgmath::Point3::from(vertices[tri[0] as usize].position)
+            .cast::<f64>()
+            .unwrap();
+        let p1 = cgmath::Point3::from(vertices[tri[1] as usize].position)
+            .cast::<f64>()
+            .unwrap();
+        let p2 = cgmath::Point3::from(vertices[tri[2] as usize].position)
+            .cast::<f64>()
+            .unwrap();
+        let normal = (p1 - p0).cross(p2 - p0);
+        let length = normal.magnitude();
+        if length == 0.0 {
+            return Self::default();
+        }
+        // weighted by area so that big faces are harder to move away from
+        let weight = length * 0.5;
+        let n = normal / length;
+        let d = -n.dot(p0.to_vec());
+        Self {
+            a00: weight * n.x * n.x,
+            a01: weight * n.x * n.y,
+            a02: weight * n.x * n.z,
+            a11: weight * n.y * n.y,
+            a12: weight * n.y * n.z,
+            a22: weight * n.z * n.z,
+            b0: weight * n.x * d,
+            b1: weight * n.y * d,
+            b2: weight * n.z * d,
+            c: weight * d * d,
+            weight,
+        }
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        self.a00 += other.a00;
+        self.a01 += other.a01;
+        self.a02 += other.a02;
+        self.a11 += other.a11;
+        self.a12 += other.a12;
+        self.a22 += other.a22;
+        self.b0 += other.b0;
+        self.b1 += other.b1;
+        self.b2 += other.b2;
+        self.c += other.c;
+        self.weight += other.weight;
+    }
+
+    // weighted mean of the squared distances to the planes
+    fn error(&self, p: [f32; 3]) -> f64 {
+        if self.weight == 0.0 {
+            return 0.0;
+        }
+        let (x, y, z) = (p[0] as f64, p[1] as f64, p[2] as f64);
+        (self.a00 * x * x
+            + 2.0 * self.a01 * x * y
+            + 2.0 * self.a02 * x * z
+            + self.a11 * y * y
+            + 2.0 * self.a12 * y * z
+            + self.a22 * z * z
+            + 2.0 * (self.b0 * x + self.b1 * y + self.b2 * z)
+            + self.c)
+            / self.weight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh_processing::normals::flat_normals;
+
+    // a flat n x n quad grid in the xy plane, the texcoords are the positions
+    fn grid(n: u32) -> (Vec<MeshVertex>, Vec<u32>) {
+        let mut vertices = vec![];
+        for y in 0..=n {
+            for x in 0..=n {
+                vertices.push(MeshVertex {
+                    position: [x as f32, y as f32, 0.0],
+                    texcoords: [x as f32, y as f32],
+                    ..Default::default()
+                });
+            }
+        }
+        let mut indices = vec![];
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                indices.extend_from_slice(&[i, i + 1, i + n + 2, i, i + n + 2, i + n + 1]);
+            }
+        }
+        (vertices, indices)
+    }
+
+    #[test]
+    fn flat_shaded_grid_simplifies() {
+        let (vertices, indices) = grid(8);
+        // every triangle gets its own vertices
+        let (vertices, indices) = flat_normals(&vertices, &indices);
+        let (lod, _) = simplify(&vertices, &indices, indices.len() / 2, 0.05);
+        assert!(!lod.is_empty());
+        assert!(lod.len() <= indices.len() / 2, "{} indices left", lod.len());
+    }
+
+    #[test]
+    fn seam_collapses_keep_sides_apart() {
+        let (mut vertices, mut indices) = grid(8);
+        // the right half gets its own texcoords, splitting the vertices at x = 4
+        let mut right_copy = HashMap::new();
+        for tri in indices.chunks_exact_mut(3) {
+            if tri.iter().all(|&i| vertices[i as usize].position[0] >= 4.0) {
+                for i in tri {
+                    *i = *right_copy.entry(*i).or_insert_with(|| {
+                        let mut vertex = vertices[*i as usize];
+                        vertex.texcoords[0] += 10.0;
+                        vertices.push(vertex);
+                        vertices.len() as u32 - 1
+                    });
+                }
+            }
+        }
+        let is_right = |i: u32| vertices[i as usize].texcoords[0] >= 10.0;
+
+        let (lod, _) = simplify(&vertices, &indices, indices.len() / 2, 0.05);
+        assert!(lod.len() < indices.len(), "nothing was collapsed");
+        for tri in lod.chunks_exact(3) {
+            assert!(
+                tri.iter().all(|&i| is_right(i)) || tri.iter().all(|&i| !is_right(i)),
+                "triangle {:?} mixes both sides of the seam",
+                tri
+            );
+        }
+    }
+
+    #[test]
+    fn nan_positions_are_left_alone() {
+        let (mut vertices, indices) = grid(8);
+        vertices[10].position = [f32::NAN; 3];
+        let (lod, _) = simplify(&vertices, &indices, indices.len() / 2, 0.05);
+        assert!(lod.len() < indices.len());
+        assert!(lod.contains(&10));
+    }
+}
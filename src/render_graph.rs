@@ -0,0 +1,347 @@
+// GPU work as passes declaring the resources they read and write by name. The passes of a graph
+// are recorded in the order they were added into one encoder and submitted at once. A pass whose
+// writes nothing after it reads is left out, unless it writes an imported resource. Transient
+// textures live only in the graph, the ones whose uses don't overlap share a texture of
+// `TransientTextures`.
+use std::collections::{HashMap, HashSet};
+
+use crate::graphics::GraphicsState;
+use crate::texture::Texture;
+
+// A window sized texture, its contents don't outlive the graph.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TransientTexture {
+    pub format: wgpu::TextureFormat,
+    pub sample_count: u32,
+}
+
+enum Resource<'a> {
+    // outlives the graph, a view is only needed by passes getting it from `GraphTextures`
+    Imported(Option<&'a wgpu::TextureView>),
+    Transient(TransientTexture),
+}
+
+type Record<'a> = Box<dyn FnOnce(&mut wgpu::CommandEncoder, &GraphTextures) + 'a>;
+
+struct Pass<'a> {
+    name: String,
+    reads: Vec<String>,
+    writes: Vec<String>,
+    record: Record<'a>,
+}
+
+pub struct RenderGraph<'a> {
+    label: String,
+    resources: HashMap<String, Resource<'a>>,
+    // by the passes added so far
+    written: HashSet<String>,
+    passes: Vec<Pass<'a>>,
+}
+
+// The textures a pass reads or writes, by name.
+pub struct GraphTextures<'r> {
+    views: HashMap<&'r str, &'r wgpu::TextureView>,
+}
+
+impl<'r> GraphTextures<'r> {
+    pub fn view(&self, name: &str) -> &'r wgpu::TextureView {
+        self.views
+            .get(name)
+            .unwrap_or_else(|| panic!("The pass doesn't use a texture '{}'", name))
+    }
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            resources: HashMap::new(),
+            written: HashSet::new(),
+            passes: vec![],
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.resources.contains_key(name)
+    }
+
+    // A buffer or texture the passes bind themselves, it only orders them.
+    pub fn import(&mut self, name: &str) {
+        self.resources
+            .insert(name.to_string(), Resource::Imported(None));
+    }
+
+    pub fn import_texture(&mut self, name: &str, view: &'a wgpu::TextureView) {
+        self.resources
+            .insert(name.to_string(), Resource::Imported(Some(view)));
+    }
+
+    pub fn create_texture(&mut self, name: &str, texture: TransientTexture) {
+        self.resources
+            .insert(name.to_string(), Resource::Transient(texture));
+    }
+
+    // Transient textures have to be written by an earlier pass before they're read.
+    pub fn add_pass(
+        &mut self,
+        name: &str,
+        reads: &[&str],
+        writes: &[&str],
+        record: impl FnOnce(&mut wgpu::CommandEncoder, &GraphTextures) + 'a,
+    ) {
+        for &read in reads {
+            match self.resources.get(read) {
+                Some(Resource::Imported(_)) => {}
+                Some(Resource::Transient(_)) => assert!(
+                    self.written.contains(read),
+                    "Pass '{}' reads '{}' before any pass writes it",
+                    name,
+                    read
+                ),
+                None => panic!("Pass '{}' reads unknown resource '{}'", name, read),
+            }
+        }
+        for &write in writes {
+            assert!(
+                self.resources.contains_key(write),
+                "Pass '{}' writes unknown resource '{}'",
+                name,
+                write
+            );
+            self.written.insert(write.to_string());
+        }
+        self.passes.push(Pass {
+            name: name.to_string(),
+            reads: reads.iter().map(|read| read.to_string()).collect(),
+            writes: writes.iter().map(|write| write.to_string()).collect(),
+            record: Box::new(record),
+        });
+    }
+
+    // Records the passes that are used and submits them.
+    pub fn execute(self, graphics_state: &GraphicsState) {
+        let kept = self.kept_passes();
+        let slots = self.alias_transients(&kept);
+        {
+            let mut pool = graphics_state.transient_textures.borrow_mut();
+            for &(texture, slot) in slots.values() {
+                pool.reserve(graphics_state, texture, slot + 1);
+            }
+        }
+        let pool = graphics_state.transient_textures.borrow();
+
+        let mut encoder =
+            graphics_state
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some(&format!("{} Encoder", &self.label)),
+                });
+        let resources = &self.resources;
+        for (pass, keep) in self.passes.into_iter().zip(kept) {
+            if !keep {
+                continue;
+            }
+            let Pass {
+                name,
+                reads,
+                writes,
+                record,
+            } = pass;
+            let views = reads
+                .iter()
+                .chain(&writes)
+                .filter_map(|name| {
+                    let view = match &resources[name] {
+                        Resource::Imported(view) => (*view)?,
+                        Resource::Transient(_) => {
+                            let (texture, slot) = slots[name];
+                            &pool.textures[&texture][slot].view
+                        }
+                    };
+                    Some((name.as_str(), view))
+                })
+                .collect();
+            encoder.push_debug_group(&name);
+            record(&mut encoder, &GraphTextures { views });
+            encoder.pop_debug_group();
+        }
+        graphics_state
+            .queue
+            .submit(std::iter::once(encoder.finish()));
+    }
+
+    // Walking back from the last pass, the passes writing an imported resource or something a
+    // kept pass after them reads.
+    fn kept_passes(&self) -> Vec<bool> {
+        let mut needed: HashSet<&str> = HashSet::new();
+        let mut kept = vec![false; self.passes.len()];
+        for (i, pass) in self.passes.iter().enumerate().rev() {
+            kept[i] = pass.writes.iter().any(|name| {
+                needed.contains(name.as_str())
+                    || matches!(self.resources[name], Resource::Imported(_))
+            });
+            if kept[i] {
+                needed.extend(pass.reads.iter().map(String::as_str));
+            }
+        }
+        kept
+    }
+
+    // The pool slot of each transient texture the kept passes use. A slot is reused once the
+    // last pass using its previous texture is done.
+    fn alias_transients(&self, kept: &[bool]) -> HashMap<String, (TransientTexture, usize)> {
+        // name -> (first pass, last pass)
+        let mut uses: HashMap<&String, (usize, usize)> = HashMap::new();
+        for (i, pass) in self.passes.iter().enumerate() {
+            if !kept[i] {
+                continue;
+            }
+            for name in pass.reads.iter().chain(&pass.writes) {
+                if let Resource::Transient(_) = self.resources[name] {
+                    let (_, last) = uses.entry(name).or_insert((i, i));
+                    *last = i;
+                }
+            }
+        }
+        let mut by_first_use: Vec<_> = uses.into_iter().collect();
+        by_first_use.sort_by_key(|&(name, (first, _))| (first, name));
+
+        // the last pass using each slot
+        let mut slot_ends: HashMap<TransientTexture, Vec<usize>> = HashMap::new();
+        let mut slots = HashMap::new();
+        for (name, (first, last)) in by_first_use {
+            let texture = match self.resources[name] {
+                Resource::Transient(texture) => texture,
+                Resource::Imported(_) => unreachable!(),
+            };
+            let ends = slot_ends.entry(texture).or_default();
+            let slot = match ends.iter().position(|&end| end < first) {
+                Some(slot) => slot,
+                None => {
+                    ends.push(last);
+                    ends.len() - 1
+                }
+            };
+            ends[slot] = last;
+            slots.insert(name.clone(), (texture, slot));
+        }
+        slots
+    }
+}
+
+// The textures of transient ones, kept between graphs until the window or the sample count
+// changes.
+pub struct TransientTextures {
+    textures: HashMap<TransientTexture, Vec<Texture>>,
+}
+
+impl TransientTextures {
+    pub fn new() -> Self {
+        Self {
+            textures: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.textures.clear();
+    }
+
+    // Creates the missing textures up to `count`.
+    fn reserve(&mut self, graphics_state: &GraphicsState, texture: TransientTexture, count: usize) {
+        let textures = self.textures.entry(texture).or_default();
+        while textures.len() < count {
+            let label = format!(
+                "Transient {:?} x{} Texture {}",
+                texture.format,
+                texture.sample_count,
+                textures.len()
+            );
+            textures.push(match texture.format {
+                GraphicsState::DEPTH_STENCIL_FORMAT => Texture::depth_stencil_texture(
+                    &graphics_state.device,
+                    &graphics_state.swap_chain_desc,
+                    texture.sample_count,
+                    Some(&label),
+                ),
+                format => Texture::render_target_2d(
+                    &graphics_state.device,
+                    graphics_state.swap_chain_desc.width,
+                    graphics_state.swap_chain_desc.height,
+                    format,
+                    texture.sample_count,
+                    Some(&label),
+                ),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOR: TransientTexture = TransientTexture {
+        format: wgpu::TextureFormat::Rgba16Float,
+        sample_count: 1,
+    };
+
+    fn graph_with(passes: &[(&[&str], &[&str])]) -> RenderGraph<'static> {
+        let mut graph = RenderGraph::new("Test");
+        graph.import("frame");
+        for name in &["a", "b"] {
+            graph.create_texture(name, COLOR);
+        }
+        for (i, &(reads, writes)) in passes.iter().enumerate() {
+            graph.add_pass(&format!("pass {}", i), reads, writes, |_, _| {});
+        }
+        graph
+    }
+
+    fn slots_of(graph: &RenderGraph) -> HashMap<String, usize> {
+        graph
+            .alias_transients(&graph.kept_passes())
+            .into_iter()
+            .map(|(name, (_, slot))| (name, slot))
+            .collect()
+    }
+
+    #[test]
+    fn unread_writes_are_dropped() {
+        let graph = graph_with(&[(&[], &["a"]), (&[], &["b"]), (&["b"], &["frame"])]);
+        assert_eq!(graph.kept_passes(), vec![false, true, true]);
+        assert!(!slots_of(&graph).contains_key("a"));
+    }
+
+    #[test]
+    fn imported_writes_are_kept() {
+        let graph = graph_with(&[(&[], &["frame"]), (&[], &["a"])]);
+        assert_eq!(graph.kept_passes(), vec![true, false]);
+    }
+
+    #[test]
+    fn disjoint_transients_share_a_slot() {
+        let graph = graph_with(&[
+            (&[], &["a"]),
+            (&["a"], &["frame"]),
+            (&[], &["b"]),
+            (&["b"], &["frame"]),
+        ]);
+        let slots = slots_of(&graph);
+        assert_eq!(slots["a"], 0);
+        assert_eq!(slots["b"], 0);
+    }
+
+    #[test]
+    fn overlapping_transients_get_own_slots() {
+        let graph = graph_with(&[(&[], &["a"]), (&[], &["b"]), (&["a", "b"], &["frame"])]);
+        let slots = slots_of(&graph);
+        assert_eq!(slots["a"], 0);
+        assert_eq!(slots["b"], 1);
+
+        // b is written by the pass reading a for the last time
+        let graph = graph_with(&[(&[], &["a"]), (&["a"], &["b"]), (&["b"], &["frame"])]);
+        let slots = slots_of(&graph);
+        assert_eq!(slots["a"], 0);
+        assert_eq!(slots["b"], 1);
+    }
+}
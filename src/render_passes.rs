@@ -0,0 +1,256 @@
+// The list of passes a frame is drawn with. A draw pass selects the sub shader of one tag from the
+// materials, so tags like "DepthOnly" or "ShadowCaster" only need a pass naming them.
+use std::ops::RangeInclusive;
+
+use crate::shader::render_queue;
+use crate::shader::schema::FramePassJson;
+use crate::shader::ShaderParseError;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LightMode {
+    // drawn once with the first light bound, also used by passes that don't light
+    First,
+    // drawn once per light but the first, added on top of a `First` pass
+    Rest,
+    Each,
+}
+
+// How the default passes light, see `default_frame_passes`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Lighting {
+    SinglePass,
+    MultiPass,
+    Clustered,
+    Deferred,
+}
+
+#[derive(Clone, Debug)]
+pub enum FramePass {
+    Draw {
+        tag: String,
+        lights: LightMode,
+        // sub shaders in other queues are left to other passes
+        queues: RangeInclusive<u32>,
+    },
+    // in the pass writing only the frame
+    Skybox,
+    // lights the G-buffer in the pass writing only the frame, see GBUFFER_TARGETS
+    DeferredLighting,
+}
+
+impl FramePass {
+    fn draw(tag: &str, lights: LightMode, queues: RangeInclusive<u32>) -> Self {
+        FramePass::Draw {
+            tag: tag.to_string(),
+            lights,
+            queues,
+        }
+    }
+}
+
+// The opaque queues before the skybox and the transparent ones after. "Forward" sub shaders light
+// with the whole light array in one pass and "ForwardClustered" ones with the lights of their
+// cluster. With multiple passes a "ForwardBase" pass lights with the first light and "ForwardAdd"
+// passes add the others one at a time. Deferred, "GBuffer" sub shaders draw the opaque queues into
+// the G-buffer and the transparent ones are drawn clustered. With a depth pre-pass, "DepthOnly"
+// sub shaders first write the depth of the opaque queues so the passes after it shade each pixel
+// once.
+pub fn default_frame_passes(lighting: Lighting, depth_prepass: bool) -> Vec<FramePass> {
+    let opaque = 0..=render_queue::LAST_OPAQUE;
+    let transparent = render_queue::LAST_OPAQUE + 1..=u32::MAX;
+    let mut passes = vec![];
+    if depth_prepass {
+        passes.push(FramePass::draw(
+            "DepthOnly",
+            LightMode::First,
+            opaque.clone(),
+        ));
+    }
+    let tag = match lighting {
+        Lighting::SinglePass => "Forward",
+        Lighting::Clustered => "ForwardClustered",
+        Lighting::Deferred => {
+            passes.extend(vec![
+                FramePass::draw("GBuffer", LightMode::First, opaque),
+                FramePass::DeferredLighting,
+                FramePass::Skybox,
+                FramePass::draw("ForwardClustered", LightMode::First, transparent),
+            ]);
+            return passes;
+        }
+        Lighting::MultiPass => {
+            passes.extend(vec![
+                FramePass::draw("ForwardBase", LightMode::First, opaque.clone()),
+                FramePass::draw("ForwardAdd", LightMode::Rest, opaque),
+                FramePass::Skybox,
+                FramePass::draw("ForwardBase", LightMode::First, transparent.clone()),
+                FramePass::draw("ForwardAdd", LightMode::Rest, transparent),
+            ]);
+            return passes;
+        }
+    };
+    passes.extend(vec![
+        FramePass::draw(tag, LightMode::First, opaque),
+        FramePass::Skybox,
+        FramePass::draw(tag, LightMode::First, transparent),
+    ]);
+    passes
+}
+
+pub fn frame_passes_from_json(
+    passes: &[FramePassJson],
+) -> Result<Vec<FramePass>, ShaderParseError> {
+    passes
+        .iter()
+        .enumerate()
+        .map(|(i, pass)| {
+            frame_pass_from_json(pass).map_err(|err| err.at(&format!("passes[{}]", i)))
+        })
+        .collect()
+}
+
+fn frame_pass_from_json(pass: &FramePassJson) -> Result<FramePass, ShaderParseError> {
+    match pass.ty.as_deref() {
+        Some(ty @ "skybox") | Some(ty @ "deferred_lighting") => {
+            if pass.tag.is_some() || pass.lights.is_some() || pass.queues.is_some() {
+                return Err(ShaderParseError::new(format!(
+                    "The {} pass has no tag, lights or queues",
+                    ty
+                )));
+            }
+            return Ok(match ty {
+                "skybox" => FramePass::Skybox,
+                _ => FramePass::DeferredLighting,
+            });
+        }
+        Some("draw") | None => {}
+        Some(ty) => {
+            return Err(ShaderParseError::new(format!("Unknown pass type '{}'", ty)).at("type"))
+        }
+    }
+    let tag = pass
+        .tag
+        .as_ref()
+        .ok_or_else(|| ShaderParseError::new("Draw passes need a tag".to_string()))?;
+    let lights = match pass.lights.as_deref() {
+        Some("first") | None => LightMode::First,
+        Some("rest") => LightMode::Rest,
+        Some("each") => LightMode::Each,
+        Some(lights) => {
+            return Err(
+                ShaderParseError::new(format!("Unknown light mode '{}'", lights)).at("lights"),
+            )
+        }
+    };
+    let queues = match &pass.queues {
+        Some((first, last)) => {
+            let first = render_queue::from_str(first).map_err(|err| err.at("queues[0]"))?;
+            let last = render_queue::from_str(last).map_err(|err| err.at("queues[1]"))?;
+            first..=last
+        }
+        None => 0..=u32::MAX,
+    };
+    Ok(FramePass::draw(tag, lights, queues))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<Vec<FramePass>, ShaderParseError> {
+        let passes: Vec<FramePassJson> = serde_json::from_str(json).unwrap();
+        frame_passes_from_json(&passes)
+    }
+
+    // the error message, with its path
+    fn assert_error(json: &str, expected: &str) {
+        let err = parse(json).err().expect("the passes should be rejected");
+        assert_eq!(err.to_string(), format!("Shader parse error {}", expected));
+    }
+
+    #[test]
+    fn valid_passes_parse() {
+        let passes = parse(
+            r#"[
+                { "tag": "DepthOnly", "queues": ["background", "opaque+500"] },
+                { "type": "skybox" },
+                { "type": "draw", "tag": "ForwardAdd", "lights": "rest",
+                  "queues": ["3000", "overlay"] }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(passes.len(), 3);
+        match &passes[0] {
+            FramePass::Draw {
+                tag,
+                lights,
+                queues,
+            } => {
+                assert_eq!(tag, "DepthOnly");
+                assert_eq!(*lights, LightMode::First);
+                assert_eq!(
+                    *queues,
+                    render_queue::BACKGROUND..=render_queue::LAST_OPAQUE
+                );
+            }
+            pass => panic!("{:?} should be a draw pass", pass),
+        }
+        assert!(matches!(passes[1], FramePass::Skybox));
+        match &passes[2] {
+            FramePass::Draw { lights, queues, .. } => {
+                assert_eq!(*lights, LightMode::Rest);
+                assert_eq!(*queues, render_queue::TRANSPARENT..=render_queue::OVERLAY);
+            }
+            pass => panic!("{:?} should be a draw pass", pass),
+        }
+    }
+
+    #[test]
+    fn unknown_pass_type() {
+        assert_error(
+            r#"[{ "type": "skybox" }, { "type": "blit", "tag": "Forward" }]"#,
+            "at 'passes[1].type': Unknown pass type 'blit'",
+        );
+    }
+
+    #[test]
+    fn unknown_light_mode() {
+        assert_error(
+            r#"[{ "tag": "Forward", "lights": "all" }]"#,
+            "at 'passes[0].lights': Unknown light mode 'all'",
+        );
+    }
+
+    #[test]
+    fn draw_pass_without_tag() {
+        assert_error(
+            r#"[{ "type": "draw", "lights": "each" }]"#,
+            "at 'passes[0]': Draw passes need a tag",
+        );
+    }
+
+    #[test]
+    fn skybox_pass_with_tag() {
+        assert_error(
+            r#"[{ "type": "skybox", "tag": "Forward" }]"#,
+            "at 'passes[0]': The skybox pass has no tag, lights or queues",
+        );
+    }
+
+    #[test]
+    fn bad_queues() {
+        assert_error(
+            r#"[{ "tag": "Forward", "queues": ["opaque", "transparent"] },
+                { "tag": "Forward", "queues": ["geometry", "transparent"] }]"#,
+            "at 'passes[1].queues[0]': Unknown render queue 'geometry'",
+        );
+        assert_error(
+            r#"[{ "tag": "Forward", "queues": ["opaque", "transparent+x"] }]"#,
+            "at 'passes[0].queues[1]': Invalid render queue 'transparent+x'",
+        );
+        assert_error(
+            r#"[{ "tag": "Forward", "queues": ["opaque", "background-1001"] }]"#,
+            "at 'passes[0].queues[1]': Invalid render queue 'background-1001'",
+        );
+    }
+}
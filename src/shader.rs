@@ -1,8 +1,17 @@
-use crate::vertex::MeshVertex;
+use crate::shader::compute::ComputeSubShader;
+use crate::shader::reflect::{ImageDim, ReflectedBinding, SpirvType};
+use crate::shader::schema::{ShaderJson, SubShaderJson, TargetJson};
+use crate::vertex::{AttributeFormat, VertexAttribute, VertexLayout};
 use anyhow::*;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::{TryFrom, TryInto};
 
+pub mod compute;
+pub mod reflect;
+pub mod schema;
+pub mod spirv_cache;
+mod wgsl;
+
 pub struct Shader {
     pub name: String,
     pub uniform_properties: Vec<(String, UniformProperty)>,
@@ -10,20 +19,77 @@ pub struct Shader {
     pub uniform_size: usize,
     pub texture_properties: HashMap<String, TextureProperty>,
     pub textures_index: HashMap<String, u32>,
+    pub texture_samplers: HashMap<String, TextureSampler>,
     pub sub_shaders: HashMap<String, SubShader>,
+    pub compute_shaders: HashMap<String, ComputeSubShader>,
+    pub vertex_layout: VertexLayout,
+    pub property_source: PropertySource,
+    // groups of mutually exclusive keywords, materials enable at most one of each group
+    pub keyword_groups: Vec<Vec<String>>,
     pub bind_group_layout: Option<wgpu::BindGroupLayout>,
 }
 
-#[derive(Eq, PartialEq)]
+// Keywords enabled on a material, each one is defined as a macro when compiling the variant.
+pub type Keywords = BTreeSet<String>;
+
+// uniform properties, texture properties and texture indices, as read from set 0
+type ReflectedProperties = (
+    Vec<(String, UniformProperty)>,
+    HashMap<String, TextureProperty>,
+    HashMap<String, u32>,
+);
+
+// Everything a sub shader's render pipeline depends on.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct PipelineKey {
+    pub shader: String,
+    pub tag: String,
+    pub keywords: Keywords,
+    pub vertex_layout: VertexLayout,
+    pub color_format: wgpu::TextureFormat,
+    pub sample_count: u32,
+}
+
+// Where the uniform and texture properties come from.
+pub enum PropertySource {
+    Json,
+    // set 0 of the compiled sub shaders, 2D textures get their default from the map ("white"
+    // if absent)
+    Reflection(HashMap<String, String>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum UniformProperty {
     Float,
     Vec2,
     Vec3,
     Vec4,
+    Int,
+    IVec2,
+    IVec3,
+    IVec4,
+    UInt,
+    UVec2,
+    UVec3,
+    UVec4,
+    // stored as 32 bit 0 / 1
+    Bool,
+    BVec2,
+    BVec3,
+    BVec4,
     Mat3,
     Mat4,
+    // element type, length
+    Array(Box<UniformProperty>, usize),
+    Struct {
+        name: String,
+        members: Vec<(String, UniformProperty)>,
+    },
 }
 
+// default values of 2D textures, see Material::from_shader
+const TEXTURE_2D_DEFAULTS: [&str; 5] = ["white", "black", "normal", "gray", "grey"];
+
 #[derive(Eq, PartialEq)]
 pub enum TextureProperty {
     Texture2D(String),
@@ -31,38 +97,87 @@ pub enum TextureProperty {
     Texture3D,
 }
 
+// Sampler settings of a texture property given in the JSON.
+pub struct TextureSampler {
+    pub desc: wgpu::SamplerDescriptor<'static>,
+    // used instead of the samplers of glTF textures too
+    pub override_gltf: bool,
+}
+
 pub struct SubShader {
     tag: String,
     options: SubShaderOption,
     vs_file: String,
     fs_file: String,
+    vs_entry: String,
+    fs_entry: String,
     shader_definition: HashMap<String, Option<String>>,
-    vs_module: Option<wgpu::ShaderModule>,
-    fs_module: Option<wgpu::ShaderModule>,
+    // headers pulled in by `#include`, known after `build`
+    included_files: Vec<std::path::PathBuf>,
+    // compiled modules per keyword set, only the sets in use are compiled
+    variants: HashMap<Keywords, SubShaderVariant>,
 }
 
-pub struct SubShaderOption {
-    cull_mode: wgpu::CullMode,
-    front_face: wgpu::FrontFace,
+pub struct SubShaderVariant {
+    vs_module: wgpu::ShaderModule,
+    fs_module: wgpu::ShaderModule,
+}
+
+// Name of the swap chain in the targets of a sub shader.
+pub const FRAME_TARGET: &str = "_Frame";
+
+#[derive(Clone)]
+pub struct ColorTarget {
+    pub name: String,
+    // None for the frame
+    pub format: Option<wgpu::TextureFormat>,
     write_mask: wgpu::ColorWrite,
     color_blend: wgpu::BlendState,
     alpha_blend: wgpu::BlendState,
+}
+
+impl ColorTarget {
+    fn frame() -> Self {
+        Self {
+            name: FRAME_TARGET.to_string(),
+            format: None,
+            write_mask: wgpu::ColorWrite::ALL,
+            color_blend: wgpu::BlendState::REPLACE,
+            alpha_blend: wgpu::BlendState::REPLACE,
+        }
+    }
+}
+
+pub struct SubShaderOption {
+    queue: u32,
+    cull_mode: wgpu::CullMode,
+    front_face: wgpu::FrontFace,
+    targets: Vec<ColorTarget>,
     depth_write: bool,
     depth_compare: wgpu::CompareFunction,
     stencil: wgpu::StencilState,
+    polygon_mode: wgpu::PolygonMode,
+    depth_bias: wgpu::DepthBiasState,
+    depth_clamp: bool,
+    sample_count: u32,
+    alpha_to_coverage: bool,
 }
 
 impl Default for SubShaderOption {
     fn default() -> Self {
         Self {
+            queue: render_queue::OPAQUE,
             cull_mode: wgpu::CullMode::Back,
             front_face: wgpu::FrontFace::Ccw,
-            write_mask: wgpu::ColorWrite::ALL,
-            color_blend: wgpu::BlendState::REPLACE,
-            alpha_blend: wgpu::BlendState::REPLACE,
+            targets: vec![ColorTarget::frame()],
             depth_write: true,
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            depth_bias: wgpu::DepthBiasState::default(),
+            depth_clamp: false,
+            sample_count: 1,
+            alpha_to_coverage: false,
         }
     }
 }
@@ -72,7 +187,12 @@ impl Shader {
         name: String,
         uniform_properties: Vec<(String, UniformProperty)>,
         texture_properties: Vec<(String, TextureProperty)>,
+        texture_samplers: HashMap<String, TextureSampler>,
         sub_shaders: HashMap<String, SubShader>,
+        compute_shaders: HashMap<String, ComputeSubShader>,
+        vertex_layout: VertexLayout,
+        property_source: PropertySource,
+        keyword_groups: Vec<Vec<String>>,
     ) -> Self {
         let mut textures_index = HashMap::new();
         for (i, (name, _)) in texture_properties.iter().enumerate() {
@@ -87,13 +207,44 @@ impl Shader {
             uniform_properties,
             texture_properties: texture_properties_hm,
             textures_index,
+            texture_samplers,
             sub_shaders,
+            compute_shaders,
+            vertex_layout,
+            property_source,
+            keyword_groups,
             uniform_size: 0,
             uniform_offsets: HashMap::new(),
             bind_group_layout: None,
         }
     }
 
+    // GLSL files of all sub shaders
+    pub fn source_files(&self) -> Vec<std::path::PathBuf> {
+        self.sub_shaders
+            .values()
+            .flat_map(|sub| vec![sub.vs_file.clone().into(), sub.fs_file.clone().into()])
+            .chain(
+                self.compute_shaders
+                    .values()
+                    .map(ComputeSubShader::source_file),
+            )
+            .collect()
+    }
+
+    // headers included by the GLSL files, empty until built
+    pub fn included_files(&self) -> Vec<std::path::PathBuf> {
+        self.sub_shaders
+            .values()
+            .flat_map(|sub| sub.included_files.clone())
+            .chain(
+                self.compute_shaders
+                    .values()
+                    .flat_map(|compute| compute.included_files.clone()),
+            )
+            .collect()
+    }
+
     pub fn get_uniform_offset(&self, name: &str) -> Option<usize> {
         self.uniform_offsets.get(name).cloned()
     }
@@ -102,8 +253,65 @@ impl Shader {
         self.textures_index.get(name).cloned()
     }
 
+    // The sampler replacing the one of a glTF texture set to the property, if any.
+    pub fn gltf_sampler_override(&self, name: &str) -> Option<&wgpu::SamplerDescriptor<'static>> {
+        self.texture_samplers
+            .get(name)
+            .filter(|sampler| sampler.override_gltf)
+            .map(|sampler| &sampler.desc)
+    }
+
+    // the highest sample count of the sub shaders
+    pub fn sample_count(&self) -> u32 {
+        self.sub_shaders
+            .values()
+            .map(SubShader::sample_count)
+            .max()
+            .unwrap_or(1)
+    }
+
+    // The type at a path accepted by the Material setters, e.g. "lights[1].color".
+    pub fn uniform_type(&self, path: &str) -> Option<&UniformProperty> {
+        let mut ty: Option<&UniformProperty> = None;
+        for segment in path.split('.') {
+            let (name, indices) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+            let members = match ty {
+                None => &self.uniform_properties,
+                Some(UniformProperty::Struct { members, .. }) => members,
+                Some(_) => return None,
+            };
+            let mut current = &members.iter().find(|(member, _)| member == name)?.1;
+            for index in indices.split_terminator(']') {
+                let index: usize = index.strip_prefix('[')?.parse().ok()?;
+                match current {
+                    UniformProperty::Array(element, length) if index < *length => current = element,
+                    _ => return None,
+                }
+            }
+            ty = Some(current);
+        }
+        ty
+    }
+
+    // Compiles the variant without keywords, the properties are reflected from and validated
+    // against it.
     pub fn build(&mut self, device: &wgpu::Device) -> Result<()> {
-        self.build_sub_shaders(device)?;
+        let reflections = self.build_sub_shaders(&Keywords::new(), device)?;
+        if let PropertySource::Reflection(texture_defaults) = &self.property_source {
+            let (uniform_properties, texture_properties, textures_index) =
+                Self::properties_from_reflection(&reflections, texture_defaults)
+                    .with_context(|| format!("Can't reflect properties of '{}'", &self.name))?;
+            self.uniform_properties = uniform_properties;
+            self.texture_properties = texture_properties;
+            self.textures_index = textures_index;
+            for name in self.texture_samplers.keys() {
+                if !self.texture_properties.contains_key(name) {
+                    bail!("Sampler given for unknown texture '{}'", name);
+                }
+            }
+        }
+        self.build_uniform_offsets();
+        self.validate_reflections(&reflections)?;
 
         let mut entries = vec![];
         entries.push(crate::graphics::util::uniform_bind_group_entry(0));
@@ -129,35 +337,231 @@ impl Shader {
             },
         ));
 
-        self.build_uniform_offsets();
+        // compute shaders have no keywords and don't use the material
+        for (tag, compute) in &mut self.compute_shaders {
+            compute
+                .build(device)
+                .with_context(|| format!("Can't build compute shader '{}'", tag))?;
+        }
+        Ok(())
+    }
+
+    // Compiles the sub shaders with the given keywords enabled, unless already done. Call after
+    // `build`.
+    pub fn build_variant(&mut self, keywords: &Keywords, device: &wgpu::Device) -> Result<()> {
+        if let Some(keyword) = keywords.iter().find(|&k| self.keyword_group(k).is_none()) {
+            bail!("Shader '{}' has no keyword '{}'", &self.name, keyword);
+        }
+        for group in &self.keyword_groups {
+            let enabled: Vec<_> = group.iter().filter(|&k| keywords.contains(k)).collect();
+            if enabled.len() > 1 {
+                bail!(
+                    "Keywords {:?} of shader '{}' exclude each other",
+                    enabled,
+                    &self.name
+                );
+            }
+        }
+        let reflections = self.build_sub_shaders(keywords, device)?;
+        self.validate_reflections(&reflections)
+    }
+
+    // The keywords of the set this shader declares, keeping the first one of each group.
+    pub fn supported_keywords(&self, keywords: &Keywords) -> Keywords {
+        let mut supported = Keywords::new();
+        for group in &self.keyword_groups {
+            if let Some(keyword) = group.iter().find(|&k| keywords.contains(k)) {
+                supported.insert(keyword.clone());
+            }
+        }
+        supported
+    }
 
+    fn keyword_group(&self, keyword: &str) -> Option<&Vec<String>> {
+        self.keyword_groups
+            .iter()
+            .find(|group| group.iter().any(|k| k == keyword))
+    }
+
+    fn validate_reflections(&self, reflections: &[(String, Vec<ReflectedBinding>)]) -> Result<()> {
+        for (file, bindings) in reflections {
+            let mismatches = self.material_mismatches(bindings);
+            if !mismatches.is_empty() {
+                bail!(
+                    "Shader '{}' doesn't match '{}':\n  {}",
+                    &self.name,
+                    file,
+                    mismatches.join("\n  ")
+                );
+            }
+        }
         Ok(())
     }
 
+    // Offsets of every uniform, array element (`name[i]`) and struct member (`name.member`).
     fn build_uniform_offsets(&mut self) {
         self.uniform_offsets.clear();
+        let offsets = UniformProperty::member_offsets(&self.uniform_properties);
         let mut total_size = 0;
-        for (name, uniform) in &self.uniform_properties {
-            let align = uniform.align();
-            let diff = total_size % align;
-            if diff != 0 {
-                total_size += align - diff;
+        for ((name, uniform), offset) in self.uniform_properties.iter().zip(offsets) {
+            uniform.insert_offsets(name, offset, &mut self.uniform_offsets);
+            total_size = offset + uniform.size();
+        }
+        self.uniform_size = round_up(total_size, 16);
+    }
+
+    // compiles every sub shader, returns the resources each newly compiled file declares
+    fn build_sub_shaders(
+        &mut self,
+        keywords: &Keywords,
+        device: &wgpu::Device,
+    ) -> Result<Vec<(String, Vec<ReflectedBinding>)>> {
+        let mut reflections = vec![];
+        for sub in self.sub_shaders.values_mut() {
+            reflections.extend(sub.build(device, &self.vertex_layout, keywords)?);
+        }
+        Ok(reflections)
+    }
+
+    // The material block and textures of set 0, merged over all compiled files. Anything the
+    // files disagree on is reported by `material_mismatches` afterwards.
+    fn properties_from_reflection(
+        reflections: &[(String, Vec<ReflectedBinding>)],
+        texture_defaults: &HashMap<String, String>,
+    ) -> Result<ReflectedProperties> {
+        let mut uniform_properties = None;
+        let mut texture_properties = HashMap::new();
+        let mut textures_index = HashMap::new();
+        let set0 = reflections
+            .iter()
+            .flat_map(|(_, bindings)| bindings.iter())
+            .filter(|binding| binding.set == 0);
+        for binding in set0 {
+            match &binding.ty {
+                SpirvType::Struct { members, .. } if binding.binding == 0 => {
+                    if uniform_properties.is_some() {
+                        continue;
+                    }
+                    let mut properties = Vec::with_capacity(members.len());
+                    for member in members {
+                        let ty = UniformProperty::try_from(&member.ty).with_context(|| {
+                            format!("Unsupported type of uniform '{}'", &member.name)
+                        })?;
+                        properties.push((member.name.clone(), ty));
+                    }
+                    uniform_properties = Some(properties);
+                }
+                SpirvType::Image(dim) if binding.binding % 2 == 1 => {
+                    let ty = match dim {
+                        ImageDim::D2 => {
+                            let default = texture_defaults
+                                .get(&binding.name)
+                                .cloned()
+                                .unwrap_or_else(|| "white".to_string());
+                            TextureProperty::Texture2D(default)
+                        }
+                        ImageDim::D3 => TextureProperty::Texture3D,
+                        ImageDim::Cube => TextureProperty::TextureCube,
+                        _ => bail!("Unsupported dimension of texture '{}'", &binding.name),
+                    };
+                    texture_properties.insert(binding.name.clone(), ty);
+                    textures_index.insert(binding.name.clone(), (binding.binding - 1) / 2);
+                }
+                // samplers and everything that breaks the convention is left to the validation
+                _ => {}
             }
-            self.uniform_offsets.insert(name.clone(), total_size);
-            total_size += uniform.size();
         }
-        let diff = total_size % 16;
-        if diff != 0 {
-            total_size += 16 - diff;
+        for name in texture_defaults.keys() {
+            if !texture_properties.contains_key(name) {
+                bail!("Default given for unknown texture '{}'", name);
+            }
         }
-        self.uniform_size = total_size;
+        Ok((
+            uniform_properties.unwrap_or_default(),
+            texture_properties,
+            textures_index,
+        ))
     }
 
-    fn build_sub_shaders(&mut self, device: &wgpu::Device) -> Result<()> {
-        for (_, sub) in &mut self.sub_shaders {
-            sub.build(device)?;
+    // Differences between the material bind group (set 0) described by the JSON and the one a
+    // compiled module declares, one entry per mismatching binding or block member.
+    fn material_mismatches(&self, bindings: &[ReflectedBinding]) -> Vec<String> {
+        let mut mismatches = vec![];
+        for binding in bindings.iter().filter(|binding| binding.set == 0) {
+            if binding.binding == 0 {
+                let members = match &binding.ty {
+                    SpirvType::Struct { members, .. } => members,
+                    ty => {
+                        mismatches.push(format!(
+                            "binding 0:\n    json:  uniform block\n    spirv: {} {}",
+                            ty, &binding.name
+                        ));
+                        continue;
+                    }
+                };
+                // arrays and structs are compared element by element, member by member
+                let mut json_leaves = vec![];
+                for (name, ty) in &self.uniform_properties {
+                    ty.leaves(name, self.uniform_offsets[name], &mut json_leaves);
+                }
+                let mut spirv_leaves = vec![];
+                for member in members {
+                    let offset = member.offset.unwrap_or(0) as usize;
+                    spirv_block_leaves(&member.ty, &member.name, offset, &mut spirv_leaves);
+                }
+                for i in 0..json_leaves.len().max(spirv_leaves.len()) {
+                    let json = json_leaves.get(i).cloned();
+                    let spirv = spirv_leaves.get(i).cloned();
+                    if json != spirv {
+                        mismatches.push(format!(
+                            "{} member {}:\n    json:  {}\n    spirv: {}",
+                            &binding.name,
+                            i,
+                            json.unwrap_or_else(|| "<none>".to_string()),
+                            spirv.unwrap_or_else(|| "<none>".to_string())
+                        ));
+                    }
+                }
+                continue;
+            }
+
+            // textures at index * 2 + 1, their samplers right after
+            let index = (binding.binding - 1) / 2;
+            let texture = self
+                .textures_index
+                .iter()
+                .find(|(_, &i)| i == index)
+                .map(|(name, _)| name);
+            let (json, spirv) = match texture {
+                Some(name) if binding.binding % 2 == 1 => {
+                    let dim = match &self.texture_properties[name] {
+                        TextureProperty::Texture2D(_) => ImageDim::D2,
+                        TextureProperty::Texture3D => ImageDim::D3,
+                        TextureProperty::TextureCube => ImageDim::Cube,
+                    };
+                    (
+                        format!("{} {}", SpirvType::Image(dim), name),
+                        format!("{} {}", &binding.ty, &binding.name),
+                    )
+                }
+                // sampler names aren't part of the convention, only their type is
+                Some(name) => (
+                    format!("{} (for {})", SpirvType::Sampler, name),
+                    format!("{} (for {})", &binding.ty, name),
+                ),
+                None => (
+                    "<none>".to_string(),
+                    format!("{} {}", &binding.ty, &binding.name),
+                ),
+            };
+            if json != spirv {
+                mismatches.push(format!(
+                    "binding {}:\n    json:  {}\n    spirv: {}",
+                    binding.binding, json, spirv
+                ));
+            }
         }
-        Ok(())
+        mismatches
     }
 }
 
@@ -167,6 +571,8 @@ impl SubShader {
         options: SubShaderOption,
         vs_file: String,
         fs_file: String,
+        vs_entry: String,
+        fs_entry: String,
         shader_definition: HashMap<String, Option<String>>,
     ) -> Self {
         Self {
@@ -174,37 +580,105 @@ impl SubShader {
             options,
             vs_file,
             fs_file,
+            vs_entry,
+            fs_entry,
             shader_definition,
-            vs_module: None,
-            fs_module: None,
+            included_files: vec![],
+            variants: HashMap::new(),
         }
     }
 
-    pub fn build(&mut self, device: &wgpu::Device) -> Result<()> {
-        self.vs_module = Some(shader_util::compile_to_module(
-            self.vs_file.as_str(),
-            &self.shader_definition,
-            device,
-        )?);
-        self.fs_module = Some(shader_util::compile_to_module(
-            self.fs_file.as_str(),
-            &self.shader_definition,
-            device,
-        )?);
-        Ok(())
+    // Compiles the keyword variant, returns the resources declared by the vertex and fragment
+    // files (nothing if the variant was compiled before).
+    pub fn build(
+        &mut self,
+        device: &wgpu::Device,
+        vertex_layout: &VertexLayout,
+        keywords: &Keywords,
+    ) -> Result<Vec<(String, Vec<ReflectedBinding>)>> {
+        if self.variants.contains_key(keywords) {
+            return Ok(vec![]);
+        }
+        let mut shader_definition = self.shader_definition.clone();
+        shader_definition.extend(vertex_layout.shader_definitions());
+        for keyword in keywords {
+            shader_definition.insert(keyword.clone(), None);
+        }
+        let (vs_module, vs_bindings, vs_includes) =
+            shader_util::compile_to_module(self.vs_file.as_str(), &shader_definition, device)?;
+        let (fs_module, fs_bindings, fs_includes) =
+            shader_util::compile_to_module(self.fs_file.as_str(), &shader_definition, device)?;
+        for file in vs_includes.into_iter().chain(fs_includes) {
+            if !self.included_files.contains(&file) {
+                self.included_files.push(file);
+            }
+        }
+        self.variants.insert(
+            keywords.clone(),
+            SubShaderVariant {
+                vs_module,
+                fs_module,
+            },
+        );
+        Ok(vec![
+            (self.vs_file.clone(), vs_bindings),
+            (self.fs_file.clone(), fs_bindings),
+        ])
+    }
+
+    // The sample count this sub shader asks the render targets to have.
+    pub fn sample_count(&self) -> u32 {
+        self.options.sample_count
+    }
+
+    // Device features the pipeline can't be created without.
+    pub fn required_features(&self) -> wgpu::Features {
+        let mut features = wgpu::Features::empty();
+        if self.options.polygon_mode != wgpu::PolygonMode::Fill {
+            features |= wgpu::Features::NON_FILL_POLYGON_MODE;
+        }
+        if self.options.depth_clamp {
+            features |= wgpu::Features::DEPTH_CLAMPING;
+        }
+        features
+    }
+
+    // Lower queues are drawn first, see `render_queue`.
+    pub fn queue(&self) -> u32 {
+        self.options.queue
+    }
+
+    // The color attachments the pipeline writes, in fragment output order.
+    pub fn targets(&self) -> &[ColorTarget] {
+        &self.options.targets
     }
 
+    // The keyword variant has to be built.
     pub fn render_pipeline(
         &self,
         shader: &Shader,
+        keywords: &Keywords,
         device: &wgpu::Device,
-        color_format: wgpu::TextureFormat,
+        frame_format: wgpu::TextureFormat,
         depth_stencil_format: wgpu::TextureFormat,
+        sample_count: u32,
         object_bind_group_layout: &wgpu::BindGroupLayout,
         light_bind_group_layout: &wgpu::BindGroupLayout,
         camera_bind_group_layout: &wgpu::BindGroupLayout,
         scene_bind_group_layout: &wgpu::BindGroupLayout,
     ) -> wgpu::RenderPipeline {
+        let variant = &self.variants[keywords];
+        let targets: Vec<_> = self
+            .options
+            .targets
+            .iter()
+            .map(|target| wgpu::ColorTargetState {
+                format: target.format.unwrap_or(frame_format),
+                alpha_blend: target.alpha_blend.clone(),
+                color_blend: target.color_blend.clone(),
+                write_mask: target.write_mask,
+            })
+            .collect();
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some(&format!("{}-{} Pipeline Layout", &shader.name, &self.tag)),
             bind_group_layouts: &[
@@ -217,365 +691,1105 @@ impl SubShader {
             push_constant_ranges: &[],
         });
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some(&format!("{}-{} Render Pipeline", &shader.name, &self.tag)),
+            label: Some(&format!(
+                "{}-{} {:?} Render Pipeline",
+                &shader.name, &self.tag, keywords
+            )),
             layout: Some(&pipeline_layout),
             vertex: wgpu::VertexState {
-                module: &self.vs_module.as_ref().unwrap(),
-                entry_point: "main",
-                buffers: &[MeshVertex::desc()],
+                module: &variant.vs_module,
+                entry_point: &self.vs_entry,
+                buffers: &[shader.vertex_layout.desc()],
             },
             fragment: Some(wgpu::FragmentState {
-                module: &self.fs_module.as_ref().unwrap(),
-                entry_point: "main",
-                targets: &[wgpu::ColorTargetState {
-                    format: color_format,
-                    alpha_blend: self.options.alpha_blend.clone(),
-                    color_blend: self.options.color_blend.clone(),
-                    write_mask: self.options.write_mask,
-                }],
+                module: &variant.fs_module,
+                entry_point: &self.fs_entry,
+                targets: &targets,
             }),
             primitive: wgpu::PrimitiveState {
                 topology: wgpu::PrimitiveTopology::TriangleList,
                 strip_index_format: None,
                 front_face: self.options.front_face,
                 cull_mode: self.options.cull_mode,
-                polygon_mode: wgpu::PolygonMode::Fill,
+                polygon_mode: self.options.polygon_mode,
             },
             depth_stencil: Some(wgpu::DepthStencilState {
                 format: depth_stencil_format,
                 depth_write_enabled: self.options.depth_write,
                 depth_compare: self.options.depth_compare,
                 stencil: self.options.stencil.clone(),
-                bias: wgpu::DepthBiasState::default(),
-                clamp_depth: false,
+                bias: self.options.depth_bias.clone(),
+                clamp_depth: self.options.depth_clamp,
             }),
             multisample: wgpu::MultisampleState {
-                count: 1,
+                count: sample_count,
                 mask: !0,
-                alpha_to_coverage_enabled: false,
+                alpha_to_coverage_enabled: self.options.alpha_to_coverage,
             },
         })
     }
 }
 
+impl std::fmt::Display for UniformProperty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            UniformProperty::Float => "float",
+            UniformProperty::Vec2 => "vec2",
+            UniformProperty::Vec3 => "vec3",
+            UniformProperty::Vec4 => "vec4",
+            UniformProperty::Int => "int",
+            UniformProperty::IVec2 => "ivec2",
+            UniformProperty::IVec3 => "ivec3",
+            UniformProperty::IVec4 => "ivec4",
+            UniformProperty::UInt => "uint",
+            UniformProperty::UVec2 => "uvec2",
+            UniformProperty::UVec3 => "uvec3",
+            UniformProperty::UVec4 => "uvec4",
+            UniformProperty::Bool => "bool",
+            UniformProperty::BVec2 => "bvec2",
+            UniformProperty::BVec3 => "bvec3",
+            UniformProperty::BVec4 => "bvec4",
+            UniformProperty::Mat3 => "mat3",
+            UniformProperty::Mat4 => "mat4",
+            UniformProperty::Array(element, length) => {
+                // the outermost length comes first
+                let mut lengths = format!("[{}]", length);
+                let mut element = element.as_ref();
+                while let UniformProperty::Array(inner, length) = element {
+                    lengths.push_str(&format!("[{}]", length));
+                    element = inner;
+                }
+                return write!(f, "{}{}", element, lengths);
+            }
+            UniformProperty::Struct { name, .. } => name,
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Sizes and alignments follow the std140 rules of uniform blocks.
 impl UniformProperty {
     pub fn size(&self) -> usize {
         match self {
-            UniformProperty::Float => 4,
-            UniformProperty::Vec2 => 8,
-            UniformProperty::Vec3 => 12,
-            UniformProperty::Vec4 => 16,
             UniformProperty::Mat3 => 48,
             UniformProperty::Mat4 => 64,
+            UniformProperty::Array(element, length) => element.array_stride() * length,
+            UniformProperty::Struct { members, .. } => {
+                let offsets = Self::member_offsets(members);
+                let end = members
+                    .last()
+                    .zip(offsets.last())
+                    .map_or(0, |((_, member), offset)| offset + member.size());
+                round_up(end, self.align())
+            }
+            scalar_or_vector => 4 * scalar_or_vector.component_count(),
         }
     }
 
     pub fn align(&self) -> usize {
         match self {
-            UniformProperty::Float => 4,
-            UniformProperty::Vec2 => 8,
-            UniformProperty::Vec3 => 16,
-            UniformProperty::Vec4 => 16,
-            UniformProperty::Mat3 => 16,
-            UniformProperty::Mat4 => 16,
+            UniformProperty::Mat3 | UniformProperty::Mat4 => 16,
+            // arrays and structs are aligned at least like a vec4
+            UniformProperty::Array(element, _) => round_up(element.align(), 16),
+            UniformProperty::Struct { members, .. } => {
+                let align = members.iter().map(|(_, member)| member.align()).max();
+                round_up(align.unwrap_or(1), 16)
+            }
+            scalar_or_vector => match scalar_or_vector.component_count() {
+                1 => 4,
+                2 => 8,
+                _ => 16,
+            },
+        }
+    }
+
+    // distance between two elements of an array of this type
+    pub fn array_stride(&self) -> usize {
+        round_up(self.size(), 16)
+    }
+
+    fn component_count(&self) -> usize {
+        match self {
+            UniformProperty::Vec2 | UniformProperty::IVec2 => 2,
+            UniformProperty::UVec2 | UniformProperty::BVec2 => 2,
+            UniformProperty::Vec3 | UniformProperty::IVec3 => 3,
+            UniformProperty::UVec3 | UniformProperty::BVec3 => 3,
+            UniformProperty::Vec4 | UniformProperty::IVec4 => 4,
+            UniformProperty::UVec4 | UniformProperty::BVec4 => 4,
+            _ => 1,
+        }
+    }
+
+    // offsets of consecutive members, within a block or a struct
+    pub fn member_offsets(members: &[(String, UniformProperty)]) -> Vec<usize> {
+        let mut offsets = Vec::with_capacity(members.len());
+        let mut offset = 0;
+        for (_, member) in members {
+            offset = round_up(offset, member.align());
+            offsets.push(offset);
+            offset += member.size();
+        }
+        offsets
+    }
+
+    fn insert_offsets(&self, path: &str, offset: usize, offsets: &mut HashMap<String, usize>) {
+        offsets.insert(path.to_string(), offset);
+        match self {
+            UniformProperty::Array(element, length) => {
+                for i in 0..*length {
+                    let path = format!("{}[{}]", path, i);
+                    element.insert_offsets(&path, offset + i * element.array_stride(), offsets);
+                }
+            }
+            UniformProperty::Struct { members, .. } => {
+                for ((name, member), member_offset) in
+                    members.iter().zip(Self::member_offsets(members))
+                {
+                    let path = format!("{}.{}", path, name);
+                    member.insert_offsets(&path, offset + member_offset, offsets);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // "type path @ offset" of every scalar, vector and matrix inside, for `material_mismatches`
+    fn leaves(&self, path: &str, offset: usize, leaves: &mut Vec<String>) {
+        match self {
+            UniformProperty::Array(element, length) => {
+                for i in 0..*length {
+                    let path = format!("{}[{}]", path, i);
+                    element.leaves(&path, offset + i * element.array_stride(), leaves);
+                }
+            }
+            UniformProperty::Struct { members, .. } => {
+                for ((name, member), member_offset) in
+                    members.iter().zip(Self::member_offsets(members))
+                {
+                    member.leaves(
+                        &format!("{}.{}", path, name),
+                        offset + member_offset,
+                        leaves,
+                    );
+                }
+            }
+            _ => leaves.push(format!(
+                "{} {} @ {}",
+                block_spelling(&self.to_string()),
+                path,
+                offset
+            )),
+        }
+    }
+
+    // Parses "float", "vec3[4]", "Light[2][3]" (2 arrays of 3, like in GLSL) ... `structs` are
+    // the struct types usable here.
+    fn parse(ty: &str, structs: &[UniformProperty]) -> Result<Self, ShaderParseError> {
+        if let (Some(open), true) = (ty.find('['), ty.ends_with(']')) {
+            let close = open + ty[open..].find(']').unwrap();
+            let length = ty[open + 1..close]
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|&length| length > 0)
+                .ok_or_else(|| {
+                    ShaderParseError::new(format!("Invalid array length in '{}'", ty))
+                })?;
+            let element = format!("{}{}", ty[..open].trim_end(), &ty[close + 1..]);
+            let element = Self::parse(&element, structs)?;
+            return Ok(UniformProperty::Array(Box::new(element), length));
         }
+        let property = match ty {
+            "float" => UniformProperty::Float,
+            "vec2" => UniformProperty::Vec2,
+            "vec3" => UniformProperty::Vec3,
+            "vec4" => UniformProperty::Vec4,
+            "int" => UniformProperty::Int,
+            "ivec2" => UniformProperty::IVec2,
+            "ivec3" => UniformProperty::IVec3,
+            "ivec4" => UniformProperty::IVec4,
+            "uint" => UniformProperty::UInt,
+            "uvec2" => UniformProperty::UVec2,
+            "uvec3" => UniformProperty::UVec3,
+            "uvec4" => UniformProperty::UVec4,
+            "bool" => UniformProperty::Bool,
+            "bvec2" => UniformProperty::BVec2,
+            "bvec3" => UniformProperty::BVec3,
+            "bvec4" => UniformProperty::BVec4,
+            "mat3" => UniformProperty::Mat3,
+            "mat4" => UniformProperty::Mat4,
+            _ => structs
+                .iter()
+                .find(|s| matches!(s, UniformProperty::Struct { name, .. } if name == ty))
+                .cloned()
+                .ok_or_else(|| {
+                    ShaderParseError::new(format!("Unknown uniform property '{}'", ty))
+                })?,
+        };
+        Ok(property)
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|ch: char| ch.is_ascii_alphabetic() || ch == '_')
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+// glslang stores booleans of uniform blocks as uints, so both sides are compared that way
+fn block_spelling(ty: &str) -> String {
+    match ty.strip_prefix('b') {
+        Some(vector) if vector.starts_with("vec") => format!("u{}", vector),
+        _ if ty == "bool" => "uint".to_string(),
+        _ => ty.to_string(),
+    }
+}
+
+// the SPIR-V counterpart of `UniformProperty::leaves`
+fn spirv_block_leaves(ty: &SpirvType, path: &str, offset: usize, leaves: &mut Vec<String>) {
+    match ty {
+        SpirvType::Array(element, Some(length), stride) => {
+            let stride = stride.unwrap_or(0) as usize;
+            for i in 0..*length as usize {
+                let path = format!("{}[{}]", path, i);
+                spirv_block_leaves(element, &path, offset + i * stride, leaves);
+            }
+        }
+        SpirvType::Struct { members, .. } => {
+            for member in members {
+                let path = format!("{}.{}", path, &member.name);
+                let member_offset = offset + member.offset.unwrap_or(0) as usize;
+                spirv_block_leaves(&member.ty, &path, member_offset, leaves);
+            }
+        }
+        _ => leaves.push(format!(
+            "{} {} @ {}",
+            block_spelling(&ty.to_string()),
+            path,
+            offset
+        )),
     }
 }
 
 #[derive(Debug)]
 pub struct ShaderParseError {
+    file: Option<String>,
+    path: String,
     parse_error: String,
 }
 
+impl ShaderParseError {
+    pub fn new(parse_error: String) -> Self {
+        Self {
+            file: None,
+            path: String::new(),
+            parse_error,
+        }
+    }
+
+    // prepends a JSON path segment, errors get these while unwinding out of nested values
+    pub fn at(mut self, path: &str) -> Self {
+        if !self.path.is_empty() && !self.path.starts_with('[') && !path.is_empty() {
+            self.path.insert(0, '.');
+        }
+        self.path.insert_str(0, path);
+        self
+    }
+
+    pub fn in_file(mut self, file: &str) -> Self {
+        if self.file.is_none() {
+            self.file = Some(file.to_string());
+        }
+        self
+    }
+}
+
 impl std::fmt::Display for ShaderParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Shader parse error: {}", &self.parse_error)
+        write!(f, "Shader parse error")?;
+        if let Some(file) = &self.file {
+            write!(f, " in '{}'", file)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, " at '{}'", &self.path)?;
+        }
+        write!(f, ": {}", &self.parse_error)
     }
 }
 
 impl std::error::Error for ShaderParseError {}
 
-impl TryFrom<&serde_json::Value> for Shader {
+impl TryFrom<&ShaderJson> for Shader {
     type Error = ShaderParseError;
 
-    fn try_from(value: &serde_json::Value) -> Result<Self, Self::Error> {
-        if let Some(path) = value.as_str() {
-            let json_file = std::fs::File::open(path);
-            if json_file.is_err() {
-                return Err(ShaderParseError {
-                    parse_error: format!("Can't open shader json file '{}'", path),
-                });
-            }
-            let json_reader = std::io::BufReader::new(json_file.unwrap());
-            let json_value = serde_json::from_reader(json_reader);
-            if json_value.is_err() {
-                return Err(ShaderParseError {
-                    parse_error: format!("Can't parse shader json file '{}'", path),
-                });
-            }
-            return Shader::try_from(&json_value.unwrap());
-        }
-
-        let name = value["name"].as_str().unwrap().to_string();
-
-        let uniform_properties_arr = value["uniform_properties"].as_array().unwrap();
-        let mut uniform_properties = Vec::with_capacity(uniform_properties_arr.len());
-        for prop in uniform_properties_arr {
-            let prop = prop.as_array().unwrap();
-            let name = prop[1].as_str().unwrap().to_string();
-            let ty: UniformProperty = prop[0].as_str().unwrap().to_string().try_into()?;
-            uniform_properties.push((name, ty));
-        }
-
-        let texture_properties_arr = value["texture_properties"].as_array().unwrap();
-        let mut texture_properties = Vec::with_capacity(texture_properties_arr.len());
-        for prop in texture_properties_arr {
-            let prop = prop.as_array().unwrap();
-            let name = prop[1].as_str().unwrap().to_string();
-            let mut ty: TextureProperty = prop[0].as_str().unwrap().to_string().try_into()?;
+    fn try_from(value: &ShaderJson) -> Result<Self, Self::Error> {
+        // a struct can use the ones declared before it
+        let mut structs: Vec<UniformProperty> = Vec::with_capacity(value.structs.len());
+        for (i, struct_json) in value.structs.iter().enumerate() {
+            let path = format!("structs[{}]", i);
+            let name = &struct_json.name;
+            if UniformProperty::parse(name, &structs).is_ok() || !is_identifier(name) {
+                return Err(ShaderParseError::new(format!(
+                    "Invalid or duplicate struct name '{}'",
+                    name
+                ))
+                .at(&path));
+            }
+            if struct_json.members.is_empty() {
+                return Err(ShaderParseError::new("Struct without members".to_string()).at(&path));
+            }
+            let mut members = Vec::with_capacity(struct_json.members.len());
+            for (j, (ty, member)) in struct_json.members.iter().enumerate() {
+                let ty = UniformProperty::parse(ty, &structs)
+                    .map_err(|err| err.at(&format!("members[{}]", j)).at(&path))?;
+                members.push((member.clone(), ty));
+            }
+            structs.push(UniformProperty::Struct {
+                name: name.clone(),
+                members,
+            });
+        }
+
+        let mut uniform_properties = Vec::with_capacity(value.uniform_properties.len());
+        for (i, (ty, name)) in value.uniform_properties.iter().enumerate() {
+            let ty = UniformProperty::parse(ty, &structs)
+                .map_err(|err| err.at(&format!("uniform_properties[{}]", i)))?;
+            uniform_properties.push((name.clone(), ty));
+        }
+
+        let mut texture_properties = Vec::with_capacity(value.texture_properties.len());
+        for (i, prop) in value.texture_properties.iter().enumerate() {
+            let path = format!("texture_properties[{}]", i);
+            let mut ty: TextureProperty = prop
+                .0
+                .clone()
+                .try_into()
+                .map_err(|err: ShaderParseError| err.at(&path))?;
             if let TextureProperty::Texture2D(default) = &mut ty {
-                *default = prop[2].as_str().unwrap().to_string();
-                if !["white", "black", "normal", "gray", "grey"].contains(&default.as_str()) {
-                    return Err(ShaderParseError {
-                        parse_error: format!("Unknown texture2D default value '{}'", default),
-                    });
+                *default = prop.2.clone().ok_or_else(|| {
+                    ShaderParseError::new("2D texture property needs a default value".to_string())
+                        .at(&path)
+                })?;
+                if !TEXTURE_2D_DEFAULTS.contains(&default.as_str()) {
+                    return Err(ShaderParseError::new(format!(
+                        "Unknown texture2D default value '{}'",
+                        default
+                    ))
+                    .at(&path));
                 }
             }
-            texture_properties.push((name, ty));
+            texture_properties.push((prop.1.clone(), ty));
         }
 
-        let sub_shaders_arr = value["subshaders"].as_array().unwrap();
-        let mut sub_shaders = HashMap::new();
-        for sub in sub_shaders_arr {
-            let tag = sub["tag"].as_str().unwrap().to_string();
-            let vs_file = sub["vs"].as_str().unwrap();
-            let fs_file = sub["fs"].as_str().unwrap();
-            let mut shader_definition = HashMap::new();
-            if let Some(definition) = sub.get("definition") {
-                let definition = definition.as_object().unwrap();
-                for (k, v) in definition {
-                    if let Some(v) = v.as_str() {
-                        shader_definition.insert(k.to_string(), Some(v.to_string()));
-                    } else {
-                        shader_definition.insert(k.to_string(), None);
-                    }
+        // shaders that don't declare their vertex attributes get every attribute as floats
+        let vertex_layout = if let Some(attributes_arr) = &value.vertex_attributes {
+            let mut attributes = Vec::with_capacity(attributes_arr.len());
+            for (i, (format, attribute)) in attributes_arr.iter().enumerate() {
+                let path = format!("vertex_attributes[{}]", i);
+                let format: AttributeFormat = format
+                    .clone()
+                    .try_into()
+                    .map_err(|err: ShaderParseError| err.at(&path))?;
+                let attribute: VertexAttribute = attribute
+                    .clone()
+                    .try_into()
+                    .map_err(|err: ShaderParseError| err.at(&path))?;
+                attributes.push((attribute, format));
+            }
+            VertexLayout::new(attributes)
+                .map_err(|err| ShaderParseError::new(err).at("vertex_attributes"))?
+        } else {
+            VertexLayout::default()
+        };
+
+        let mut declared_keywords = Keywords::new();
+        for (i, group) in value.keywords.iter().enumerate() {
+            for (j, keyword) in group.iter().enumerate() {
+                let error = if !is_identifier(keyword) {
+                    Some(format!("Keyword '{}' isn't a valid macro name", keyword))
+                } else if !declared_keywords.insert(keyword.clone()) {
+                    Some(format!("Duplicated keyword '{}'", keyword))
+                } else {
+                    None
+                };
+                if let Some(error) = error {
+                    let path = format!("keywords[{}][{}]", i, j);
+                    return Err(ShaderParseError::new(error).at(&path));
+                }
+            }
+        }
+
+        let mut texture_samplers = HashMap::new();
+        let mut lod_bias_definition = HashMap::new();
+        for (name, sampler) in &value.samplers {
+            let path = format!("samplers.{}", name);
+            let known = value.reflect_properties
+                || texture_properties
+                    .iter()
+                    .any(|(texture, _)| texture == name);
+            if !known {
+                return Err(
+                    ShaderParseError::new(format!("Unknown texture property '{}'", name)).at(&path),
+                );
+            }
+            let desc =
+                shader_option_util::sampler_from_json(sampler, shader_option_util::base_sampler())
+                    .map_err(|err| err.at(&path))?;
+            if let Some(lod_bias) = sampler.lod_bias {
+                if !is_identifier(name) {
+                    return Err(ShaderParseError::new(format!(
+                        "'{}' can't be used in a macro name for the LOD bias",
+                        name
+                    ))
+                    .at(&path));
                 }
+                // Debug keeps the decimal point, "1.0" instead of "1"
+                let value = format!("{:?}", lod_bias);
+                lod_bias_definition.insert(format!("{}_LOD_BIAS", name), Some(value));
+            }
+            let override_gltf = sampler.override_gltf;
+            texture_samplers.insert(
+                name.clone(),
+                TextureSampler {
+                    desc,
+                    override_gltf,
+                },
+            );
+        }
+
+        let mut sub_shaders = HashMap::new();
+        for (i, sub) in value.subshaders.iter().enumerate() {
+            let mut shader_definition = lod_bias_definition.clone();
+            for (k, v) in &sub.definition {
+                shader_definition.insert(k.to_string(), v.as_str().map(|v| v.to_string()));
             }
-            let option = sub.try_into()?;
+            let option = SubShaderOption::try_from(sub)
+                .map_err(|err| err.at(&format!("subshaders[{}]", i)))?;
+            let default_entry = |file: &str, wgsl_entry: &str| {
+                if file.ends_with(".wgsl") {
+                    wgsl_entry.to_string()
+                } else {
+                    "main".to_string()
+                }
+            };
             let sub_shader = SubShader::new(
-                tag.clone(),
+                sub.tag.clone(),
                 option,
-                vs_file.to_string(),
-                fs_file.to_string(),
+                sub.vs.clone(),
+                sub.fs.clone(),
+                sub.vs_entry
+                    .clone()
+                    .unwrap_or_else(|| default_entry(&sub.vs, "vs_main")),
+                sub.fs_entry
+                    .clone()
+                    .unwrap_or_else(|| default_entry(&sub.fs, "fs_main")),
                 shader_definition,
             );
-            sub_shaders.insert(tag, sub_shader);
+            if sub_shaders.insert(sub.tag.clone(), sub_shader).is_some() {
+                return Err(ShaderParseError::new(format!(
+                    "Duplicated sub shader tag '{}'",
+                    &sub.tag
+                ))
+                .at(&format!("subshaders[{}].tag", i)));
+            }
+        }
+
+        let mut compute_shaders = HashMap::new();
+        for (i, compute) in value.compute.iter().enumerate() {
+            let path = format!("compute[{}]", i);
+            let compute_shader =
+                ComputeSubShader::try_from(compute).map_err(|err| err.at(&path))?;
+            let duplicated = sub_shaders.contains_key(&compute.tag)
+                || compute_shaders
+                    .insert(compute.tag.clone(), compute_shader)
+                    .is_some();
+            if duplicated {
+                return Err(ShaderParseError::new(format!(
+                    "Duplicated sub shader tag '{}'",
+                    &compute.tag
+                ))
+                .at(&format!("{}.tag", path)));
+            }
         }
 
+        let property_source = if value.reflect_properties {
+            if !uniform_properties.is_empty() || !texture_properties.is_empty() {
+                return Err(ShaderParseError::new(
+                    "Properties are reflected, remove 'uniform_properties' and \
+                     'texture_properties'"
+                        .to_string(),
+                )
+                .at("reflect_properties"));
+            }
+            for (name, default) in &value.texture_defaults {
+                if !TEXTURE_2D_DEFAULTS.contains(&default.as_str()) {
+                    return Err(ShaderParseError::new(format!(
+                        "Unknown texture2D default value '{}'",
+                        default
+                    ))
+                    .at(&format!("texture_defaults.{}", name)));
+                }
+            }
+            PropertySource::Reflection(value.texture_defaults.clone())
+        } else {
+            if !value.texture_defaults.is_empty() {
+                return Err(ShaderParseError::new(
+                    "Only used with 'reflect_properties', give defaults in 'texture_properties'"
+                        .to_string(),
+                )
+                .at("texture_defaults"));
+            }
+            PropertySource::Json
+        };
+
         Ok(Shader::new(
-            name,
+            value.name.clone(),
             uniform_properties,
             texture_properties,
+            texture_samplers,
             sub_shaders,
+            compute_shaders,
+            vertex_layout,
+            property_source,
+            value.keywords.clone(),
         ))
     }
 }
 
-impl TryFrom<&serde_json::Value> for SubShaderOption {
+impl ColorTarget {
+    // Write mask and blend default to the ones of the sub shader, given as `frame`.
+    fn parse(value: &TargetJson, frame: &ColorTarget) -> Result<Self, ShaderParseError> {
+        let format = if value.name == FRAME_TARGET {
+            if value.format.is_some() {
+                return Err(ShaderParseError::new(
+                    "The frame has the swap chain format".to_string(),
+                )
+                .at("format"));
+            }
+            None
+        } else if is_identifier(&value.name) {
+            let format = value.format.as_ref().ok_or_else(|| {
+                ShaderParseError::new(format!("Target '{}' needs a format", value.name))
+            })?;
+            Some(
+                shader_option_util::texture_format_from_str(format)
+                    .map_err(|err| err.at("format"))?,
+            )
+        } else {
+            return Err(
+                ShaderParseError::new(format!("Invalid target name '{}'", value.name)).at("name"),
+            );
+        };
+        let mut target = Self {
+            name: value.name.clone(),
+            format,
+            ..frame.clone()
+        };
+        if let Some(write_mask) = &value.write_mask {
+            target.write_mask = shader_option_util::write_mask_from_strs(write_mask)
+                .map_err(|err| err.at("write_mask"))?;
+        }
+        if let Some(blend) = &value.blend {
+            let (color_blend, alpha_blend) =
+                shader_option_util::blend_from_json(blend).map_err(|err| err.at("blend"))?;
+            target.color_blend = color_blend;
+            target.alpha_blend = alpha_blend;
+        }
+        Ok(target)
+    }
+}
+
+impl TryFrom<&SubShaderJson> for SubShaderOption {
     type Error = ShaderParseError;
 
-    fn try_from(value: &serde_json::Value) -> Result<Self, Self::Error> {
+    fn try_from(value: &SubShaderJson) -> Result<Self, Self::Error> {
         let mut option = Self::default();
-        if let Some(cull) = value.get("cull") {
-            let cull = cull.as_str().unwrap();
-            match cull {
+        if let Some(queue) = &value.queue {
+            option.queue = render_queue::from_str(queue).map_err(|err| err.at("queue"))?;
+        }
+        if let Some(cull) = &value.cull {
+            match cull.as_str() {
                 "front" => option.cull_mode = wgpu::CullMode::Front,
                 "back" => option.cull_mode = wgpu::CullMode::Back,
                 "none" => option.cull_mode = wgpu::CullMode::None,
                 _ => {
-                    return Err(ShaderParseError {
-                        parse_error: format!("Unknown cull value: '{}'", cull),
-                    })
+                    return Err(
+                        ShaderParseError::new(format!("Unknown cull value: '{}'", cull)).at("cull"),
+                    )
                 }
             }
         }
-        if let Some(front) = value.get("front_face") {
-            let front = front.as_str().unwrap();
-            match front {
+        if let Some(front) = &value.front_face {
+            match front.as_str() {
                 "ccw" => option.front_face = wgpu::FrontFace::Ccw,
                 "cw" => option.front_face = wgpu::FrontFace::Cw,
                 _ => {
-                    return Err(ShaderParseError {
-                        parse_error: format!("Unknown front face value: '{}'", front),
-                    })
+                    return Err(ShaderParseError::new(format!(
+                        "Unknown front face value: '{}'",
+                        front
+                    ))
+                    .at("front_face"))
                 }
             }
         }
-        if let Some(write_mask) = value.get("write_mask") {
-            let mut mask = wgpu::ColorWrite::from_bits(0).unwrap();
-            let write_mask = write_mask.as_array().unwrap();
-            for ch in write_mask {
-                let ch = ch.as_str().unwrap();
-                match ch {
-                    "R" => mask.insert(wgpu::ColorWrite::RED),
-                    "G" => mask.insert(wgpu::ColorWrite::GREEN),
-                    "B" => mask.insert(wgpu::ColorWrite::BLUE),
-                    "A" => mask.insert(wgpu::ColorWrite::ALPHA),
-                    _ => {
-                        return Err(ShaderParseError {
-                            parse_error: format!("Unknown color write mask value: '{}'", ch),
-                        })
+        let mut frame = ColorTarget::frame();
+        if let Some(write_mask) = &value.write_mask {
+            frame.write_mask = shader_option_util::write_mask_from_strs(write_mask)
+                .map_err(|err| err.at("write_mask"))?;
+        }
+        if let Some(blend) = &value.blend {
+            let (color_blend, alpha_blend) =
+                shader_option_util::blend_from_json(blend).map_err(|err| err.at("blend"))?;
+            frame.color_blend = color_blend;
+            frame.alpha_blend = alpha_blend;
+        }
+        option.targets = match &value.targets {
+            Some(targets) => {
+                let mut color_targets: Vec<ColorTarget> = vec![];
+                for (i, target) in targets.iter().enumerate() {
+                    let target = ColorTarget::parse(target, &frame)
+                        .map_err(|err| err.at(&format!("[{}]", i)).at("targets"))?;
+                    if color_targets.iter().any(|other| other.name == target.name) {
+                        return Err(ShaderParseError::new(format!(
+                            "Target '{}' is written twice",
+                            target.name
+                        ))
+                        .at(&format!("targets[{}]", i)));
                     }
-                };
+                    color_targets.push(target);
+                }
+                color_targets
             }
-            option.write_mask = mask;
+            None => vec![frame],
+        };
+        if let Some(depth_write) = value.depth_write {
+            option.depth_write = depth_write;
+        }
+        if let Some(depth_cmp) = &value.depth_compare {
+            option.depth_compare = shader_option_util::compare_func_from_str(depth_cmp)
+                .map_err(|err| err.at("depth_compare"))?;
         }
-        if let Some(blend) = value.get("blend") {
-            let mut color_blend = wgpu::BlendState::default();
-            let mut alpha_blend = wgpu::BlendState::default();
-            if let Some(op) = blend.get("op") {
-                let op = op.as_str().unwrap();
-                color_blend.operation = shader_option_util::blend_op_from_str(op)?;
+        if let Some(stencil) = &value.stencil {
+            let mut stencil_state = wgpu::StencilState::default();
+            if let Some(read_mask) = stencil.read_mask {
+                stencil_state.read_mask = read_mask;
+            }
+            if let Some(write_mask) = stencil.write_mask {
+                stencil_state.write_mask = write_mask;
             }
-            if let Some(src) = blend.get("src") {
-                let src = src.as_str().unwrap();
-                color_blend.src_factor = shader_option_util::blend_factor_from_str(src)?;
+            if let Some(front_state) = &stencil.front {
+                stencil_state.front = shader_option_util::stencil_face_state_from_json(front_state)
+                    .map_err(|err| err.at("stencil.front"))?;
             }
-            if let Some(dst) = blend.get("dst") {
-                let dst = dst.as_str().unwrap();
-                color_blend.dst_factor = shader_option_util::blend_factor_from_str(dst)?;
+            if let Some(back_state) = &stencil.back {
+                stencil_state.back = shader_option_util::stencil_face_state_from_json(back_state)
+                    .map_err(|err| err.at("stencil.back"))?;
             }
-            if let Some(op) = blend.get("op_alpha") {
-                let op = op.as_str().unwrap();
-                alpha_blend.operation = shader_option_util::blend_op_from_str(op)?;
+            option.stencil = stencil_state;
+        }
+        if let Some(polygon_mode) = &value.polygon_mode {
+            option.polygon_mode = match polygon_mode.as_str() {
+                "fill" => wgpu::PolygonMode::Fill,
+                "line" => wgpu::PolygonMode::Line,
+                "point" => wgpu::PolygonMode::Point,
+                _ => {
+                    return Err(ShaderParseError::new(format!(
+                        "Unknown polygon mode: '{}'",
+                        polygon_mode
+                    ))
+                    .at("polygon_mode"))
+                }
+            };
+        }
+        if let Some(depth_bias) = &value.depth_bias {
+            if let Some(constant) = depth_bias.constant {
+                option.depth_bias.constant = constant;
             }
-            if let Some(src) = blend.get("src_alpha") {
-                let src = src.as_str().unwrap();
-                alpha_blend.src_factor = shader_option_util::blend_factor_from_str(src)?;
+            if let Some(slope_scale) = depth_bias.slope_scale {
+                option.depth_bias.slope_scale = slope_scale;
             }
-            if let Some(dst) = blend.get("dst_alpha") {
-                let dst = dst.as_str().unwrap();
-                alpha_blend.dst_factor = shader_option_util::blend_factor_from_str(dst)?;
+            if let Some(clamp) = depth_bias.clamp {
+                option.depth_bias.clamp = clamp;
             }
-            option.color_blend = color_blend;
-            option.alpha_blend = alpha_blend;
         }
-        if let Some(depth_write) = value.get("depth_write") {
-            let depth_write = depth_write.as_bool().unwrap();
-            option.depth_write = depth_write;
+        if let Some(depth_clamp) = value.depth_clamp {
+            option.depth_clamp = depth_clamp;
         }
-        if let Some(depth_cmp) = value.get("depth_compare") {
-            let depth_cmp = depth_cmp.as_str().unwrap();
-            option.depth_compare = shader_option_util::compare_func_from_str(depth_cmp)?;
+        if let Some(sample_count) = value.sample_count {
+            // the counts every wgpu backend supports
+            if sample_count != 1 && sample_count != 4 {
+                return Err(ShaderParseError::new(format!(
+                    "Sample count must be 1 or 4, not {}",
+                    sample_count
+                ))
+                .at("sample_count"));
+            }
+            option.sample_count = sample_count;
         }
-        if let Some(stencil) = value.get("stencil") {
-            let mut stencil_state = wgpu::StencilState::default();
-            if let Some(read_mask) = stencil.get("read_mask") {
-                stencil_state.read_mask = read_mask.as_u64().unwrap() as u32;
+        if let Some(alpha_to_coverage) = value.alpha_to_coverage {
+            option.alpha_to_coverage = alpha_to_coverage;
+        }
+        Ok(option)
+    }
+}
+
+impl TryFrom<&SpirvType> for UniformProperty {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &SpirvType) -> Result<Self, Self::Error> {
+        match value {
+            SpirvType::Float => Ok(UniformProperty::Float),
+            SpirvType::Int { signed: true } => Ok(UniformProperty::Int),
+            SpirvType::Int { signed: false } => Ok(UniformProperty::UInt),
+            SpirvType::Bool => Ok(UniformProperty::Bool),
+            SpirvType::Vector(component, count) => {
+                let property = match (component.as_ref(), count) {
+                    (SpirvType::Float, 2) => UniformProperty::Vec2,
+                    (SpirvType::Float, 3) => UniformProperty::Vec3,
+                    (SpirvType::Float, 4) => UniformProperty::Vec4,
+                    (SpirvType::Int { signed: true }, 2) => UniformProperty::IVec2,
+                    (SpirvType::Int { signed: true }, 3) => UniformProperty::IVec3,
+                    (SpirvType::Int { signed: true }, 4) => UniformProperty::IVec4,
+                    (SpirvType::Int { signed: false }, 2) => UniformProperty::UVec2,
+                    (SpirvType::Int { signed: false }, 3) => UniformProperty::UVec3,
+                    (SpirvType::Int { signed: false }, 4) => UniformProperty::UVec4,
+                    (SpirvType::Bool, 2) => UniformProperty::BVec2,
+                    (SpirvType::Bool, 3) => UniformProperty::BVec3,
+                    (SpirvType::Bool, 4) => UniformProperty::BVec4,
+                    _ => bail!("'{}' has no matching uniform property", value),
+                };
+                Ok(property)
             }
-            if let Some(write_mask) = stencil.get("write_mask") {
-                stencil_state.write_mask = write_mask.as_u64().unwrap() as u32;
+            SpirvType::Array(element, Some(length), _) => Ok(UniformProperty::Array(
+                Box::new(UniformProperty::try_from(element.as_ref())?),
+                *length as usize,
+            )),
+            SpirvType::Struct { name, members } => {
+                let mut properties = Vec::with_capacity(members.len());
+                for member in members {
+                    let ty = UniformProperty::try_from(&member.ty)
+                        .with_context(|| format!("In member '{}' of '{}'", &member.name, name))?;
+                    properties.push((member.name.clone(), ty));
+                }
+                Ok(UniformProperty::Struct {
+                    name: name.clone(),
+                    members: properties,
+                })
             }
-            if let Some(front_state) = stencil.get("front") {
-                stencil_state.front =
-                    shader_option_util::stencil_face_state_from_json(front_state)?;
+            SpirvType::Matrix(column, 3)
+                if **column == SpirvType::Vector(Box::new(SpirvType::Float), 3) =>
+            {
+                Ok(UniformProperty::Mat3)
             }
-            if let Some(back_state) = stencil.get("back") {
-                stencil_state.back = shader_option_util::stencil_face_state_from_json(back_state)?;
+            SpirvType::Matrix(column, 4)
+                if **column == SpirvType::Vector(Box::new(SpirvType::Float), 4) =>
+            {
+                Ok(UniformProperty::Mat4)
             }
-            option.stencil = stencil_state;
+            _ => bail!("'{}' has no matching uniform property", value),
         }
-        Ok(option)
     }
 }
 
-impl TryFrom<String> for UniformProperty {
+impl TryFrom<String> for TextureProperty {
     type Error = ShaderParseError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         match value.as_str() {
-            "float" => Ok(UniformProperty::Float),
-            "vec2" => Ok(UniformProperty::Vec2),
-            "vec3" => Ok(UniformProperty::Vec3),
-            "vec4" => Ok(UniformProperty::Vec4),
-            "mat3" => Ok(UniformProperty::Mat3),
-            "mat4" => Ok(UniformProperty::Mat4),
-            _ => Err(ShaderParseError {
-                parse_error: format!("Unknown uniform property '{}'", value),
-            }),
+            "2D" => Ok(TextureProperty::Texture2D("".to_string())),
+            "3D" => Ok(TextureProperty::Texture3D),
+            "Cube" => Ok(TextureProperty::TextureCube),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown texture property '{}'",
+                value
+            ))),
         }
     }
 }
 
-impl TryFrom<String> for TextureProperty {
+impl TryFrom<String> for VertexAttribute {
     type Error = ShaderParseError;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         match value.as_str() {
-            "2D" => Ok(TextureProperty::Texture2D("".to_string())),
-            "3D" => Ok(TextureProperty::Texture3D),
-            "Cube" => Ok(TextureProperty::TextureCube),
-            _ => Err(ShaderParseError {
-                parse_error: format!("Unknown texture property '{}'", value),
-            }),
+            "position" => Ok(VertexAttribute::Position),
+            "texcoords" => Ok(VertexAttribute::Texcoords),
+            "normal" => Ok(VertexAttribute::Normal),
+            "tangent" => Ok(VertexAttribute::Tangent),
+            "color" => Ok(VertexAttribute::Color),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown vertex attribute '{}'",
+                value
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for AttributeFormat {
+    type Error = ShaderParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "float" => Ok(AttributeFormat::Float),
+            "half" => Ok(AttributeFormat::Half),
+            "oct" => Ok(AttributeFormat::Octahedral),
+            "unorm8" => Ok(AttributeFormat::Unorm8),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown vertex attribute format '{}'",
+                value
+            ))),
         }
     }
 }
 
 mod shader_util {
+    use crate::shader::reflect::{self, ReflectedBinding};
+    use crate::shader::{spirv_cache, wgsl};
     use anyhow::*;
+    use std::cell::RefCell;
     use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
 
+    // searched by `#include <...>`, and by `#include "..."` when the file isn't found next to the
+    // including file (shaderc retries those as `<...>`); the engine headers live in `engine/`
+    pub const INCLUDE_DIRS: [&str; 1] = ["res/shaders"];
+
+    // Uses the SPIR-V cache if the source and its includes are unchanged. Also returns the
+    // resources the module declares and the files it included.
     pub fn compile_to_module<P: AsRef<std::path::Path>>(
         path: P,
         definition: &HashMap<String, Option<String>>,
         device: &wgpu::Device,
-    ) -> Result<wgpu::ShaderModule> {
+    ) -> Result<(wgpu::ShaderModule, Vec<ReflectedBinding>, Vec<PathBuf>)> {
         let path_buf = path.as_ref().to_path_buf();
-        let shader_source = std::fs::read_to_string(path)?;
         let orig_extension = path_buf
             .extension()
             .context("No extension")?
             .to_str()
             .context("Invalid extension")?;
-        let spv_path = path_buf.with_extension(format!("{}.spv", orig_extension));
         let shader_kind = match orig_extension {
             "vert" => Some(shaderc::ShaderKind::Vertex),
             "frag" => Some(shaderc::ShaderKind::Fragment),
             "comp" => Some(shaderc::ShaderKind::Compute),
-            _ => None,
-        }
-        .context("Unknown shader kind")?;
+            // a WGSL file holds all stages
+            "wgsl" => None,
+            _ => bail!("Unknown shader kind"),
+        };
+
+        let (spirv, included_files) = match spirv_cache::load(&path_buf, definition) {
+            Some(cached) => (cached.spirv, cached.included_files),
+            None => {
+                let (spirv, included_files) = match shader_kind {
+                    Some(shader_kind) => compile_to_spirv(&path_buf, shader_kind, definition)?,
+                    None => wgsl::compile_to_spirv(&path_buf, definition)?,
+                };
+                if let Err(err) = spirv_cache::store(&path_buf, definition, &spirv, &included_files)
+                {
+                    eprintln!("Can't cache '{}': {:?}", path_buf.display(), err);
+                }
+                (spirv, included_files)
+            }
+        };
+
+        let words: Vec<u32> = spirv
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let bindings = reflect::reflect(&words)?;
+
+        let module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: path_buf.to_str(),
+            source: wgpu::util::make_spirv(&spirv),
+            flags: Default::default(),
+        });
+        Ok((module, bindings, included_files))
+    }
+
+    fn compile_to_spirv(
+        path: &Path,
+        shader_kind: shaderc::ShaderKind,
+        definition: &HashMap<String, Option<String>>,
+    ) -> Result<(Vec<u8>, Vec<PathBuf>)> {
+        let shader_source = std::fs::read_to_string(path)?;
         let mut compiler = shaderc::Compiler::new().context("Can't get compiler")?;
 
+        let included_files = RefCell::new(vec![]);
         let mut compile_options =
             shaderc::CompileOptions::new().context("Can't get compile options object")?;
         for (key, value) in definition {
             compile_options
                 .add_macro_definition(key.as_str(), value.as_ref().map(|str| str.as_str()));
         }
+        compile_options.set_include_callback(|requested, include_type, requesting, _depth| {
+            let resolved = resolve_include(requested, include_type, requesting)?;
+            included_files.borrow_mut().push(resolved.clone());
+            let content = std::fs::read_to_string(&resolved).map_err(|err| {
+                format!("Can't read include file '{}': {}", resolved.display(), err)
+            })?;
+            Ok(shaderc::ResolvedInclude {
+                resolved_name: resolved.display().to_string(),
+                content,
+            })
+        });
 
         let compiler_result = compiler.compile_into_spirv(
             &shader_source,
             shader_kind,
-            path_buf.to_str().unwrap(),
+            path.to_str().unwrap(),
             "main",
             Some(&compile_options),
         )?;
+        let spirv = compiler_result.as_binary_u8().to_vec();
+        drop(compile_options);
+        Ok((spirv, included_files.into_inner()))
+    }
 
-        std::fs::write(spv_path, compiler_result.as_binary_u8())?;
+    pub fn resolve_include(
+        requested: &str,
+        include_type: shaderc::IncludeType,
+        requesting: &str,
+    ) -> Result<PathBuf, String> {
+        let dirs: Vec<&Path> = match include_type {
+            shaderc::IncludeType::Relative => Path::new(requesting).parent().into_iter().collect(),
+            shaderc::IncludeType::Standard => INCLUDE_DIRS.iter().map(Path::new).collect(),
+        };
+        dirs.into_iter()
+            .map(|dir| dir.join(requested))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| {
+                format!(
+                    "Can't find include file '{}' (requested by '{}')",
+                    requested, requesting
+                )
+            })
+    }
+}
 
-        Ok(device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-            label: path_buf.to_str(),
-            source: wgpu::util::make_spirv(compiler_result.as_binary_u8()),
-            flags: Default::default(),
-        }))
+// Draw order of sub shaders, as in Unity. Queues up to `LAST_OPAQUE` are sorted to change state
+// as rarely as possible, the ones above are drawn back to front.
+pub mod render_queue {
+    use crate::shader::ShaderParseError;
+    use std::convert::TryFrom;
+
+    pub const BACKGROUND: u32 = 1000;
+    pub const OPAQUE: u32 = 2000;
+    pub const ALPHA_TEST: u32 = 2450;
+    pub const LAST_OPAQUE: u32 = 2500;
+    pub const TRANSPARENT: u32 = 3000;
+    pub const OVERLAY: u32 = 4000;
+
+    // a name, optionally with an offset ("transparent+10", "opaque-1"), or a number
+    pub fn from_str(str: &str) -> Result<u32, ShaderParseError> {
+        if let Ok(queue) = str.parse::<u32>() {
+            return Ok(queue);
+        }
+        let split = str.find(['+', '-']).unwrap_or(str.len());
+        let (name, offset) = str.split_at(split);
+        let queue = match name {
+            "background" => BACKGROUND,
+            "opaque" => OPAQUE,
+            "alpha_test" => ALPHA_TEST,
+            "transparent" => TRANSPARENT,
+            "overlay" => OVERLAY,
+            _ => {
+                return Err(ShaderParseError::new(format!(
+                    "Unknown render queue '{}'",
+                    name
+                )))
+            }
+        };
+        if offset.is_empty() {
+            return Ok(queue);
+        }
+        offset
+            .parse::<i64>()
+            .ok()
+            .and_then(|offset| u32::try_from(queue as i64 + offset).ok())
+            .ok_or_else(|| ShaderParseError::new(format!("Invalid render queue '{}'", str)))
     }
 }
 
-mod shader_option_util {
+pub(crate) mod shader_option_util {
+    use crate::shader::schema::{BlendJson, SamplerJson, StencilFaceJson};
     use crate::shader::ShaderParseError;
 
+    pub fn write_mask_from_strs(
+        write_mask: &[String],
+    ) -> Result<wgpu::ColorWrite, ShaderParseError> {
+        let mut mask = wgpu::ColorWrite::empty();
+        for (i, ch) in write_mask.iter().enumerate() {
+            match ch.as_str() {
+                "R" => mask.insert(wgpu::ColorWrite::RED),
+                "G" => mask.insert(wgpu::ColorWrite::GREEN),
+                "B" => mask.insert(wgpu::ColorWrite::BLUE),
+                "A" => mask.insert(wgpu::ColorWrite::ALPHA),
+                _ => {
+                    return Err(ShaderParseError::new(format!(
+                        "Unknown color write mask value: '{}'",
+                        ch
+                    ))
+                    .at(&format!("[{}]", i)))
+                }
+            };
+        }
+        Ok(mask)
+    }
+
+    // (color, alpha), unset factors and operations keep the wgpu defaults
+    pub fn blend_from_json(
+        blend: &BlendJson,
+    ) -> Result<(wgpu::BlendState, wgpu::BlendState), ShaderParseError> {
+        let at = |key: &'static str| move |err: ShaderParseError| err.at(key);
+        let mut color_blend = wgpu::BlendState::default();
+        let mut alpha_blend = wgpu::BlendState::default();
+        if let Some(op) = &blend.op {
+            color_blend.operation = blend_op_from_str(op).map_err(at("op"))?;
+        }
+        if let Some(src) = &blend.src {
+            color_blend.src_factor = blend_factor_from_str(src).map_err(at("src"))?;
+        }
+        if let Some(dst) = &blend.dst {
+            color_blend.dst_factor = blend_factor_from_str(dst).map_err(at("dst"))?;
+        }
+        if let Some(op) = &blend.op_alpha {
+            alpha_blend.operation = blend_op_from_str(op).map_err(at("op_alpha"))?;
+        }
+        if let Some(src) = &blend.src_alpha {
+            alpha_blend.src_factor = blend_factor_from_str(src).map_err(at("src_alpha"))?;
+        }
+        if let Some(dst) = &blend.dst_alpha {
+            alpha_blend.dst_factor = blend_factor_from_str(dst).map_err(at("dst_alpha"))?;
+        }
+        Ok((color_blend, alpha_blend))
+    }
+
+    // formats render targets can have
+    pub fn texture_format_from_str(str: &str) -> Result<wgpu::TextureFormat, ShaderParseError> {
+        match str {
+            "r8unorm" => Ok(wgpu::TextureFormat::R8Unorm),
+            "rg8unorm" => Ok(wgpu::TextureFormat::Rg8Unorm),
+            "rgba8unorm" => Ok(wgpu::TextureFormat::Rgba8Unorm),
+            "rgba8unorm_srgb" => Ok(wgpu::TextureFormat::Rgba8UnormSrgb),
+            "rgb10a2unorm" => Ok(wgpu::TextureFormat::Rgb10a2Unorm),
+            "r16float" => Ok(wgpu::TextureFormat::R16Float),
+            "rg16float" => Ok(wgpu::TextureFormat::Rg16Float),
+            "rgba16float" => Ok(wgpu::TextureFormat::Rgba16Float),
+            "r32float" => Ok(wgpu::TextureFormat::R32Float),
+            "rg32float" => Ok(wgpu::TextureFormat::Rg32Float),
+            "rgba32float" => Ok(wgpu::TextureFormat::Rgba32Float),
+            "r32uint" => Ok(wgpu::TextureFormat::R32Uint),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown texture format '{}'",
+                str
+            ))),
+        }
+    }
+
     pub fn blend_factor_from_str(str: &str) -> Result<wgpu::BlendFactor, ShaderParseError> {
         match str {
             "one" => Ok(wgpu::BlendFactor::One),
@@ -588,9 +1802,10 @@ mod shader_option_util {
             "dst_color" => Ok(wgpu::BlendFactor::DstColor),
             "one_minus_dst_alpha" => Ok(wgpu::BlendFactor::OneMinusDstAlpha),
             "one_minus_dst_color" => Ok(wgpu::BlendFactor::OneMinusDstColor),
-            _ => Err(ShaderParseError {
-                parse_error: format!("Unknown blend factor '{}'", str),
-            }),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown blend factor '{}'",
+                str
+            ))),
         }
     }
 
@@ -601,9 +1816,10 @@ mod shader_option_util {
             "min" => Ok(wgpu::BlendOperation::Min),
             "sub" => Ok(wgpu::BlendOperation::Subtract),
             "rsub" => Ok(wgpu::BlendOperation::ReverseSubtract),
-            _ => Err(ShaderParseError {
-                parse_error: format!("Unknown blend operation '{}'", str),
-            }),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown blend operation '{}'",
+                str
+            ))),
         }
     }
 
@@ -617,9 +1833,10 @@ mod shader_option_util {
             "lequal" => Ok(wgpu::CompareFunction::LessEqual),
             "greater" => Ok(wgpu::CompareFunction::Greater),
             "gequal" => Ok(wgpu::CompareFunction::GreaterEqual),
-            _ => Err(ShaderParseError {
-                parse_error: format!("Unknown compare function '{}'", str),
-            }),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown compare function '{}'",
+                str
+            ))),
         }
     }
 
@@ -633,32 +1850,327 @@ mod shader_option_util {
             "inc_wrap" => Ok(wgpu::StencilOperation::IncrementWrap),
             "dec_clamp" => Ok(wgpu::StencilOperation::DecrementClamp),
             "dec_wrap" => Ok(wgpu::StencilOperation::DecrementWrap),
-            _ => Err(ShaderParseError {
-                parse_error: format!("Unknown stencil operation '{}'", str),
-            }),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown stencil operation '{}'",
+                str
+            ))),
         }
     }
 
     pub fn stencil_face_state_from_json(
-        value: &serde_json::Value,
+        value: &StencilFaceJson,
     ) -> Result<wgpu::StencilFaceState, ShaderParseError> {
         let mut state = wgpu::StencilFaceState::default();
-        if let Some(cmp) = value.get("compare") {
-            let cmp = cmp.as_str().unwrap();
-            state.compare = compare_func_from_str(cmp)?;
+        if let Some(cmp) = &value.compare {
+            state.compare = compare_func_from_str(cmp).map_err(|err| err.at("compare"))?;
         }
-        if let Some(pass) = value.get("pass") {
-            let pass = pass.as_str().unwrap();
-            state.pass_op = stencil_op_from_str(pass)?;
+        if let Some(pass) = &value.pass {
+            state.pass_op = stencil_op_from_str(pass).map_err(|err| err.at("pass"))?;
         }
-        if let Some(fail) = value.get("fail") {
-            let fail = fail.as_str().unwrap();
-            state.pass_op = stencil_op_from_str(fail)?;
+        if let Some(fail) = &value.fail {
+            state.fail_op = stencil_op_from_str(fail).map_err(|err| err.at("fail"))?;
         }
-        if let Some(depth_fail) = value.get("depth_fail") {
-            let depth_fail = depth_fail.as_str().unwrap();
-            state.pass_op = stencil_op_from_str(depth_fail)?;
+        if let Some(depth_fail) = &value.depth_fail {
+            state.depth_fail_op =
+                stencil_op_from_str(depth_fail).map_err(|err| err.at("depth_fail"))?;
         }
         Ok(state)
     }
+
+    pub fn address_mode_from_str(str: &str) -> Result<wgpu::AddressMode, ShaderParseError> {
+        match str {
+            "clamp" => Ok(wgpu::AddressMode::ClampToEdge),
+            "repeat" => Ok(wgpu::AddressMode::Repeat),
+            "mirror" => Ok(wgpu::AddressMode::MirrorRepeat),
+            // needs ADDRESS_MODE_CLAMP_TO_BORDER and a border color, neither is supported
+            "border" => Err(ShaderParseError::new(
+                "Address mode 'border' isn't supported, use 'clamp', 'repeat' or 'mirror'"
+                    .to_string(),
+            )),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown address mode '{}'",
+                str
+            ))),
+        }
+    }
+
+    pub fn filter_mode_from_str(str: &str) -> Result<wgpu::FilterMode, ShaderParseError> {
+        match str {
+            "nearest" => Ok(wgpu::FilterMode::Nearest),
+            "linear" => Ok(wgpu::FilterMode::Linear),
+            _ => Err(ShaderParseError::new(format!(
+                "Unknown filter mode '{}'",
+                str
+            ))),
+        }
+    }
+
+    // Linear filtering and repeat, for textures without sampler settings.
+    pub fn base_sampler() -> wgpu::SamplerDescriptor<'static> {
+        wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        }
+    }
+
+    // `base` gives the settings the JSON leaves out.
+    pub fn sampler_from_json(
+        value: &SamplerJson,
+        base: wgpu::SamplerDescriptor<'static>,
+    ) -> Result<wgpu::SamplerDescriptor<'static>, ShaderParseError> {
+        let mut desc = base;
+        if let Some(mode) = &value.address_mode {
+            let mode = address_mode_from_str(mode).map_err(|err| err.at("address_mode"))?;
+            desc.address_mode_u = mode;
+            desc.address_mode_v = mode;
+            desc.address_mode_w = mode;
+        }
+        if let Some(mode) = &value.address_mode_u {
+            desc.address_mode_u =
+                address_mode_from_str(mode).map_err(|err| err.at("address_mode_u"))?;
+        }
+        if let Some(mode) = &value.address_mode_v {
+            desc.address_mode_v =
+                address_mode_from_str(mode).map_err(|err| err.at("address_mode_v"))?;
+        }
+        if let Some(mode) = &value.address_mode_w {
+            desc.address_mode_w =
+                address_mode_from_str(mode).map_err(|err| err.at("address_mode_w"))?;
+        }
+        if let Some(filter) = &value.filter {
+            let filter = filter_mode_from_str(filter).map_err(|err| err.at("filter"))?;
+            desc.mag_filter = filter;
+            desc.min_filter = filter;
+            desc.mipmap_filter = filter;
+        }
+        if let Some(filter) = &value.mag_filter {
+            desc.mag_filter = filter_mode_from_str(filter).map_err(|err| err.at("mag_filter"))?;
+        }
+        if let Some(filter) = &value.min_filter {
+            desc.min_filter = filter_mode_from_str(filter).map_err(|err| err.at("min_filter"))?;
+        }
+        if let Some(filter) = &value.mipmap_filter {
+            desc.mipmap_filter =
+                filter_mode_from_str(filter).map_err(|err| err.at("mipmap_filter"))?;
+        }
+        if let Some(anisotropy) = value.anisotropy {
+            if ![1, 2, 4, 8, 16].contains(&anisotropy) {
+                return Err(ShaderParseError::new(format!(
+                    "Anisotropy must be 1, 2, 4, 8 or 16, not {}",
+                    anisotropy
+                ))
+                .at("anisotropy"));
+            }
+            // 1 is the same as no anisotropic filtering
+            desc.anisotropy_clamp = std::num::NonZeroU8::new(anisotropy).filter(|&a| a.get() > 1);
+        }
+        if let Some(lod_min_clamp) = value.lod_min_clamp {
+            desc.lod_min_clamp = lod_min_clamp;
+        }
+        if let Some(lod_max_clamp) = value.lod_max_clamp {
+            desc.lod_max_clamp = lod_max_clamp;
+        }
+        Ok(desc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::schema::SamplerJson;
+
+    fn structure(
+        name: &str,
+        members: &[(&str, &str)],
+        structs: &[UniformProperty],
+    ) -> UniformProperty {
+        UniformProperty::Struct {
+            name: name.to_string(),
+            members: members
+                .iter()
+                .map(|&(ty, name)| {
+                    (
+                        name.to_string(),
+                        UniformProperty::parse(ty, structs).unwrap(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    // the offsets and size of a material block with these [type, name] properties
+    fn block(properties: &[(&str, &str)], structs: &[UniformProperty]) -> Shader {
+        let uniform_properties = properties
+            .iter()
+            .map(|&(ty, name)| {
+                (
+                    name.to_string(),
+                    UniformProperty::parse(ty, structs).unwrap(),
+                )
+            })
+            .collect();
+        let mut shader = Shader::new(
+            "test".to_string(),
+            uniform_properties,
+            vec![],
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            VertexLayout::default(),
+            PropertySource::Json,
+            vec![],
+        );
+        shader.build_uniform_offsets();
+        shader
+    }
+
+    fn assert_offsets(shader: &Shader, offsets: &[(&str, usize)], size: usize) {
+        for &(name, offset) in offsets {
+            assert_eq!(
+                shader.uniform_offsets.get(name),
+                Some(&offset),
+                "offset of {}",
+                name
+            );
+        }
+        assert_eq!(shader.uniform_size, size);
+    }
+
+    #[test]
+    fn vec3_then_float() {
+        let shader = block(&[("vec3", "a"), ("float", "b")], &[]);
+        assert_offsets(&shader, &[("a", 0), ("b", 12)], 16);
+    }
+
+    #[test]
+    fn float_array() {
+        let array = UniformProperty::parse("float[4]", &[]).unwrap();
+        assert_eq!(array.align(), 16);
+        assert_eq!(array.size(), 64);
+        assert_eq!(UniformProperty::Float.array_stride(), 16);
+
+        let shader = block(&[("float", "x"), ("float[4]", "a"), ("float", "y")], &[]);
+        assert_offsets(
+            &shader,
+            &[("x", 0), ("a", 16), ("a[0]", 16), ("a[2]", 48), ("y", 80)],
+            96,
+        );
+    }
+
+    #[test]
+    fn mat3() {
+        assert_eq!(UniformProperty::Mat3.size(), 48);
+        assert_eq!(UniformProperty::Mat3.align(), 16);
+        assert_eq!(UniformProperty::Mat3.array_stride(), 48);
+
+        let shader = block(&[("float", "f"), ("mat3", "m"), ("float", "g")], &[]);
+        assert_offsets(&shader, &[("f", 0), ("m", 16), ("g", 64)], 80);
+    }
+
+    #[test]
+    fn nested_structs() {
+        let inner = structure("Inner", &[("vec2", "a"), ("float", "b")], &[]);
+        assert_eq!(inner.size(), 16);
+        assert_eq!(inner.align(), 16);
+        let outer = structure(
+            "Outer",
+            &[("float", "x"), ("Inner", "inner"), ("vec3", "v")],
+            std::slice::from_ref(&inner),
+        );
+        assert_eq!(outer.size(), 48);
+
+        let shader = block(&[("Outer", "o"), ("float", "after")], &[inner, outer]);
+        assert_offsets(
+            &shader,
+            &[
+                ("o", 0),
+                ("o.x", 0),
+                ("o.inner", 16),
+                ("o.inner.a", 16),
+                ("o.inner.b", 24),
+                ("o.v", 32),
+                ("after", 48),
+            ],
+            64,
+        );
+    }
+
+    #[test]
+    fn array_of_arrays_of_structs() {
+        let light = structure(
+            "Light",
+            &[
+                ("vec3", "position"),
+                ("float", "intensity"),
+                ("vec4", "color"),
+            ],
+            &[],
+        );
+        assert_eq!(light.size(), 32);
+        let lights = UniformProperty::parse("Light[2][3]", std::slice::from_ref(&light)).unwrap();
+        // 2 arrays of 3 lights
+        match &lights {
+            UniformProperty::Array(inner, 2) => assert_eq!(inner.array_stride(), 96),
+            _ => panic!("Light[2][3] should be an array of 2"),
+        }
+        assert_eq!(lights.size(), 192);
+
+        let shader = block(&[("float", "count"), ("Light[2][3]", "lights")], &[light]);
+        assert_offsets(
+            &shader,
+            &[
+                ("count", 0),
+                ("lights", 16),
+                ("lights[0][1].intensity", 60),
+                ("lights[1]", 112),
+                ("lights[1][2].color", 192),
+            ],
+            208,
+        );
+        assert!(!shader.uniform_offsets.contains_key("lights[2]"));
+        assert!(!shader.uniform_offsets.contains_key("lights[1][3]"));
+    }
+
+    #[test]
+    fn bools() {
+        // 32 bits per component, aligned like the float vectors
+        let shader = block(
+            &[
+                ("bool", "a"),
+                ("bvec2", "b"),
+                ("bvec3", "c"),
+                ("bool", "d"),
+                ("bvec4", "e"),
+            ],
+            &[],
+        );
+        assert_offsets(
+            &shader,
+            &[("a", 0), ("b", 8), ("c", 16), ("d", 28), ("e", 32)],
+            48,
+        );
+    }
+
+    #[test]
+    fn border_address_mode_is_rejected() {
+        let sampler: SamplerJson =
+            serde_json::from_str(r#"{ "filter": "nearest", "address_mode_v": "border" }"#).unwrap();
+        let err =
+            shader_option_util::sampler_from_json(&sampler, shader_option_util::base_sampler())
+                .err()
+                .expect("'border' should be rejected");
+        assert_eq!(err.path, "address_mode_v");
+
+        let sampler: SamplerJson = serde_json::from_str(r#"{ "address_mode": "mirror" }"#).unwrap();
+        let desc =
+            shader_option_util::sampler_from_json(&sampler, shader_option_util::base_sampler())
+                .unwrap();
+        assert_eq!(desc.address_mode_u, wgpu::AddressMode::MirrorRepeat);
+        assert_eq!(desc.address_mode_w, wgpu::AddressMode::MirrorRepeat);
+    }
 }
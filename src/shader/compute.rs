@@ -0,0 +1,238 @@
+// Compute sub shaders. Their only bind group (set 0) binds engine buffers and textures by name,
+// as declared in the JSON, so the pipeline layout is known before anything is compiled. The
+// compiled module is checked against the declaration like render sub shaders are against the
+// material properties.
+use anyhow::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use crate::shader::reflect::{ImageDim, ReflectedBinding, SpirvType};
+use crate::shader::schema::{ComputeJson, ComputeResourceJson};
+use crate::shader::{shader_util, ShaderParseError};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ComputeResourceKind {
+    UniformBuffer,
+    StorageBuffer { read_only: bool },
+    // 2D, sampled or fetched
+    Texture,
+    Sampler,
+}
+
+pub struct ComputeResource {
+    pub binding: u32,
+    pub kind: ComputeResourceKind,
+    // the engine buffer or texture bound
+    pub name: String,
+}
+
+pub struct ComputeSubShader {
+    pub tag: String,
+    pub resources: Vec<ComputeResource>,
+    cs_file: String,
+    cs_entry: String,
+    shader_definition: HashMap<String, Option<String>>,
+    // headers pulled in by `#include`, known after `build`
+    pub(crate) included_files: Vec<PathBuf>,
+    module: Option<wgpu::ShaderModule>,
+    bind_group_layout: Option<wgpu::BindGroupLayout>,
+}
+
+impl ComputeSubShader {
+    pub fn source_file(&self) -> PathBuf {
+        self.cs_file.clone().into()
+    }
+
+    pub fn entry_point(&self) -> &str {
+        &self.cs_entry
+    }
+
+    // Both are None until built.
+    pub fn module(&self) -> Option<&wgpu::ShaderModule> {
+        self.module.as_ref()
+    }
+
+    pub fn bind_group_layout(&self) -> Option<&wgpu::BindGroupLayout> {
+        self.bind_group_layout.as_ref()
+    }
+
+    pub fn build(&mut self, device: &wgpu::Device) -> Result<()> {
+        let (module, bindings, included_files) =
+            shader_util::compile_to_module(self.cs_file.as_str(), &self.shader_definition, device)?;
+        self.validate(&bindings)
+            .with_context(|| format!("'{}' doesn't match its resources", &self.cs_file))?;
+
+        let entries: Vec<_> = self
+            .resources
+            .iter()
+            .map(|resource| wgpu::BindGroupLayoutEntry {
+                binding: resource.binding,
+                visibility: wgpu::ShaderStage::COMPUTE,
+                ty: match resource.kind {
+                    ComputeResourceKind::UniformBuffer => wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    ComputeResourceKind::StorageBuffer { read_only } => wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    ComputeResourceKind::Texture => wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    ComputeResourceKind::Sampler => wgpu::BindingType::Sampler {
+                        filtering: true,
+                        comparison: false,
+                    },
+                },
+                count: None,
+            })
+            .collect();
+        self.bind_group_layout = Some(device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                label: Some(&format!("{} Compute Bind Group Layout", &self.tag)),
+                entries: &entries,
+            },
+        ));
+        self.module = Some(module);
+        self.included_files = included_files;
+        Ok(())
+    }
+
+    // Every binding of the module has to be declared with a matching type, declared ones the
+    // module doesn't use are fine.
+    fn validate(&self, bindings: &[ReflectedBinding]) -> Result<()> {
+        for binding in bindings {
+            if binding.set != 0 {
+                bail!(
+                    "'{}' is in set {}, compute shaders only have set 0",
+                    binding.name,
+                    binding.set
+                );
+            }
+            let resource = self
+                .resources
+                .iter()
+                .find(|resource| resource.binding == binding.binding)
+                .with_context(|| {
+                    format!(
+                        "'{}' at binding {} isn't declared",
+                        binding.name, binding.binding
+                    )
+                })?;
+            let matches = match (&binding.ty, resource.kind) {
+                (SpirvType::Struct { .. }, ComputeResourceKind::UniformBuffer)
+                | (SpirvType::Struct { .. }, ComputeResourceKind::StorageBuffer { .. })
+                | (SpirvType::Image(ImageDim::D2), ComputeResourceKind::Texture)
+                | (SpirvType::Sampler, ComputeResourceKind::Sampler) => true,
+                (SpirvType::SampledImage(_), _) => bail!(
+                    "'{}' is a combined image sampler, declare the texture and sampler apart",
+                    binding.name
+                ),
+                _ => false,
+            };
+            if !matches {
+                bail!(
+                    "'{}' at binding {} is a {}, declared as {:?}",
+                    binding.name,
+                    binding.binding,
+                    binding.ty,
+                    resource.kind
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&ComputeJson> for ComputeSubShader {
+    type Error = ShaderParseError;
+
+    fn try_from(value: &ComputeJson) -> Result<Self, Self::Error> {
+        let mut resources: Vec<ComputeResource> = Vec::with_capacity(value.resources.len());
+        for (i, resource) in value.resources.iter().enumerate() {
+            let path = format!("resources[{}]", i);
+            let resource = ComputeResource::try_from(resource).map_err(|err| err.at(&path))?;
+            if resources
+                .iter()
+                .any(|other| other.binding == resource.binding)
+            {
+                return Err(ShaderParseError::new(format!(
+                    "Binding {} is declared twice",
+                    resource.binding
+                ))
+                .at(&path));
+            }
+            resources.push(resource);
+        }
+        let shader_definition = value
+            .definition
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.as_str().map(|v| v.to_string())))
+            .collect();
+        let cs_entry = match &value.cs_entry {
+            Some(entry) => entry.clone(),
+            None if value.cs.ends_with(".wgsl") => "cs_main".to_string(),
+            None => "main".to_string(),
+        };
+        Ok(Self {
+            tag: value.tag.clone(),
+            resources,
+            cs_file: value.cs.clone(),
+            cs_entry,
+            shader_definition,
+            included_files: vec![],
+            module: None,
+            bind_group_layout: None,
+        })
+    }
+}
+
+impl TryFrom<&ComputeResourceJson> for ComputeResource {
+    type Error = ShaderParseError;
+
+    fn try_from(value: &ComputeResourceJson) -> Result<Self, Self::Error> {
+        let access = value.access.as_deref();
+        let unknown_access = || {
+            ShaderParseError::new(format!(
+                "Unknown access '{}' for a {}",
+                access.unwrap_or_default(),
+                value.ty
+            ))
+            .at("access")
+        };
+        let kind = match value.ty.as_str() {
+            "uniform_buffer" | "texture" | "sampler" if access.is_some() => {
+                return Err(
+                    ShaderParseError::new(format!("A {} has no access", value.ty)).at("access"),
+                )
+            }
+            "uniform_buffer" => ComputeResourceKind::UniformBuffer,
+            "texture" => ComputeResourceKind::Texture,
+            "sampler" => ComputeResourceKind::Sampler,
+            "storage_buffer" => ComputeResourceKind::StorageBuffer {
+                read_only: match access {
+                    Some("read") => true,
+                    Some("read_write") | None => false,
+                    Some(_) => return Err(unknown_access()),
+                },
+            },
+            _ => {
+                return Err(
+                    ShaderParseError::new(format!("Unknown resource type '{}'", value.ty))
+                        .at("type"),
+                )
+            }
+        };
+        Ok(Self {
+            binding: value.binding,
+            kind,
+            name: value.name.clone(),
+        })
+    }
+}
@@ -0,0 +1,417 @@
+// Minimal SPIR-V reflection: just enough to list the resources a module declares, with block
+// member names, offsets and types.
+use anyhow::*;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SpirvType {
+    Bool,
+    Int {
+        signed: bool,
+    },
+    Float,
+    Vector(Box<SpirvType>, u32),
+    // column type, column count
+    Matrix(Box<SpirvType>, u32),
+    // element type, length (None for runtime arrays), stride (only decorated in blocks)
+    Array(Box<SpirvType>, Option<u32>, Option<u32>),
+    Struct {
+        name: String,
+        members: Vec<StructMember>,
+    },
+    Image(ImageDim),
+    SampledImage(ImageDim),
+    Sampler,
+    Unknown,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ImageDim {
+    D1,
+    D2,
+    D3,
+    Cube,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructMember {
+    pub name: String,
+    pub offset: Option<u32>,
+    pub ty: SpirvType,
+}
+
+// A uniform/storage buffer, texture or sampler variable.
+#[derive(Clone, Debug)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub name: String,
+    pub ty: SpirvType,
+}
+
+impl std::fmt::Display for SpirvType {
+    // GLSL spelling, so mismatches read like the shader source
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpirvType::Bool => write!(f, "bool"),
+            SpirvType::Int { signed: true } => write!(f, "int"),
+            SpirvType::Int { signed: false } => write!(f, "uint"),
+            SpirvType::Float => write!(f, "float"),
+            SpirvType::Vector(component, count) => {
+                let prefix = match component.as_ref() {
+                    SpirvType::Bool => "b",
+                    SpirvType::Int { signed: true } => "i",
+                    SpirvType::Int { signed: false } => "u",
+                    _ => "",
+                };
+                write!(f, "{}vec{}", prefix, count)
+            }
+            SpirvType::Matrix(column, count) => match column.as_ref() {
+                SpirvType::Vector(_, rows) if rows == count => write!(f, "mat{}", count),
+                SpirvType::Vector(_, rows) => write!(f, "mat{}x{}", count, rows),
+                _ => write!(f, "mat?"),
+            },
+            SpirvType::Array(element, Some(length), _) => write!(f, "{}[{}]", element, length),
+            SpirvType::Array(element, None, _) => write!(f, "{}[]", element),
+            SpirvType::Struct { name, .. } => write!(f, "{}", name),
+            SpirvType::Image(dim) => write!(f, "texture{}", dim.suffix()),
+            SpirvType::SampledImage(dim) => write!(f, "sampler{}", dim.suffix()),
+            SpirvType::Sampler => write!(f, "sampler"),
+            SpirvType::Unknown => write!(f, "<unknown>"),
+        }
+    }
+}
+
+impl ImageDim {
+    fn suffix(&self) -> &'static str {
+        match self {
+            ImageDim::D1 => "1D",
+            ImageDim::D2 => "2D",
+            ImageDim::D3 => "3D",
+            ImageDim::Cube => "Cube",
+            ImageDim::Other => "?",
+        }
+    }
+}
+
+mod op {
+    pub const NAME: u32 = 5;
+    pub const MEMBER_NAME: u32 = 6;
+    pub const TYPE_BOOL: u32 = 20;
+    pub const TYPE_INT: u32 = 21;
+    pub const TYPE_FLOAT: u32 = 22;
+    pub const TYPE_VECTOR: u32 = 23;
+    pub const TYPE_MATRIX: u32 = 24;
+    pub const TYPE_IMAGE: u32 = 25;
+    pub const TYPE_SAMPLER: u32 = 26;
+    pub const TYPE_SAMPLED_IMAGE: u32 = 27;
+    pub const TYPE_ARRAY: u32 = 28;
+    pub const TYPE_RUNTIME_ARRAY: u32 = 29;
+    pub const TYPE_STRUCT: u32 = 30;
+    pub const TYPE_POINTER: u32 = 32;
+    pub const CONSTANT: u32 = 43;
+    pub const VARIABLE: u32 = 59;
+    pub const DECORATE: u32 = 71;
+    pub const MEMBER_DECORATE: u32 = 72;
+
+    // Operand words `reflect` reads of each instruction, the module may come from a corrupt
+    // cache entry.
+    pub fn min_operands(opcode: u32) -> usize {
+        match opcode {
+            TYPE_BOOL | TYPE_SAMPLER | TYPE_STRUCT => 1,
+            NAME | TYPE_FLOAT | TYPE_SAMPLED_IMAGE | TYPE_RUNTIME_ARRAY | DECORATE => 2,
+            MEMBER_NAME | TYPE_INT | TYPE_VECTOR | TYPE_MATRIX | TYPE_ARRAY | TYPE_POINTER
+            | CONSTANT | VARIABLE | MEMBER_DECORATE => 3,
+            TYPE_IMAGE => 8,
+            _ => 0,
+        }
+    }
+}
+
+mod decoration {
+    pub const ARRAY_STRIDE: u32 = 6;
+    pub const OFFSET: u32 = 35;
+    pub const BINDING: u32 = 33;
+    pub const DESCRIPTOR_SET: u32 = 34;
+}
+
+mod storage_class {
+    pub const UNIFORM_CONSTANT: u32 = 0;
+    pub const UNIFORM: u32 = 2;
+    pub const STORAGE_BUFFER: u32 = 12;
+}
+
+const MAGIC: u32 = 0x0723_0203;
+
+enum RawType {
+    Bool,
+    Int(bool),
+    Float,
+    Vector(u32, u32),
+    Matrix(u32, u32),
+    Array(u32, u32),
+    RuntimeArray(u32),
+    Struct(Vec<u32>),
+    Image(ImageDim),
+    SampledImage(u32),
+    Sampler,
+    Pointer(u32),
+}
+
+// Lists every descriptor bound variable of the module, sorted by (set, binding).
+pub fn reflect(words: &[u32]) -> Result<Vec<ReflectedBinding>> {
+    if words.len() < 5 || words[0] != MAGIC {
+        bail!("Not a SPIR-V module");
+    }
+
+    let mut names = HashMap::new();
+    let mut member_names = HashMap::new();
+    let mut member_offsets = HashMap::new();
+    let mut bindings = HashMap::new();
+    let mut sets = HashMap::new();
+    let mut array_strides = HashMap::new();
+    let mut types = HashMap::new();
+    let mut constants = HashMap::new();
+    let mut variables = vec![];
+
+    let mut pos = 5;
+    while pos < words.len() {
+        let word_count = (words[pos] >> 16) as usize;
+        let opcode = words[pos] & 0xffff;
+        if word_count == 0 || pos + word_count > words.len() {
+            bail!("Malformed SPIR-V instruction at word {}", pos);
+        }
+        let args = &words[pos + 1..pos + word_count];
+        if args.len() < op::min_operands(opcode) {
+            bail!("Malformed SPIR-V instruction at word {}", pos);
+        }
+        match opcode {
+            op::NAME => {
+                names.insert(args[0], literal_string(&args[1..]));
+            }
+            op::MEMBER_NAME => {
+                member_names.insert((args[0], args[1]), literal_string(&args[2..]));
+            }
+            op::DECORATE if args.len() >= 3 => match args[1] {
+                decoration::BINDING => {
+                    bindings.insert(args[0], args[2]);
+                }
+                decoration::DESCRIPTOR_SET => {
+                    sets.insert(args[0], args[2]);
+                }
+                decoration::ARRAY_STRIDE => {
+                    array_strides.insert(args[0], args[2]);
+                }
+                _ => {}
+            },
+            op::MEMBER_DECORATE if args.len() >= 4 && args[2] == decoration::OFFSET => {
+                member_offsets.insert((args[0], args[1]), args[3]);
+            }
+            op::TYPE_BOOL => {
+                types.insert(args[0], RawType::Bool);
+            }
+            op::TYPE_INT => {
+                types.insert(args[0], RawType::Int(args[2] != 0));
+            }
+            op::TYPE_FLOAT => {
+                types.insert(args[0], RawType::Float);
+            }
+            op::TYPE_VECTOR => {
+                types.insert(args[0], RawType::Vector(args[1], args[2]));
+            }
+            op::TYPE_MATRIX => {
+                types.insert(args[0], RawType::Matrix(args[1], args[2]));
+            }
+            op::TYPE_IMAGE => {
+                let dim = match args[2] {
+                    0 => ImageDim::D1,
+                    1 => ImageDim::D2,
+                    2 => ImageDim::D3,
+                    3 => ImageDim::Cube,
+                    _ => ImageDim::Other,
+                };
+                types.insert(args[0], RawType::Image(dim));
+            }
+            op::TYPE_SAMPLER => {
+                types.insert(args[0], RawType::Sampler);
+            }
+            op::TYPE_SAMPLED_IMAGE => {
+                types.insert(args[0], RawType::SampledImage(args[1]));
+            }
+            op::TYPE_ARRAY => {
+                types.insert(args[0], RawType::Array(args[1], args[2]));
+            }
+            op::TYPE_RUNTIME_ARRAY => {
+                types.insert(args[0], RawType::RuntimeArray(args[1]));
+            }
+            op::TYPE_STRUCT => {
+                types.insert(args[0], RawType::Struct(args[1..].to_vec()));
+            }
+            op::TYPE_POINTER => {
+                types.insert(args[0], RawType::Pointer(args[2]));
+            }
+            op::CONSTANT => {
+                constants.insert(args[1], args[2]);
+            }
+            op::VARIABLE => {
+                let storage = args[2];
+                if storage == storage_class::UNIFORM_CONSTANT
+                    || storage == storage_class::UNIFORM
+                    || storage == storage_class::STORAGE_BUFFER
+                {
+                    variables.push((args[1], args[0]));
+                }
+            }
+            _ => {}
+        }
+        pos += word_count;
+    }
+
+    let resolver = TypeResolver {
+        names: &names,
+        member_names: &member_names,
+        member_offsets: &member_offsets,
+        array_strides: &array_strides,
+        types: &types,
+        constants: &constants,
+    };
+    let mut result: Vec<ReflectedBinding> = variables
+        .into_iter()
+        .filter_map(|(id, pointer_type)| {
+            let binding = *bindings.get(&id)?;
+            let set = sets.get(&id).cloned().unwrap_or(0);
+            let ty = match types.get(&pointer_type) {
+                Some(RawType::Pointer(pointee)) => resolver.resolve(*pointee),
+                _ => SpirvType::Unknown,
+            };
+            // blocks are usually declared without an instance name
+            let name = match names.get(&id) {
+                Some(name) if !name.is_empty() => name.clone(),
+                _ => ty.to_string(),
+            };
+            Some(ReflectedBinding {
+                set,
+                binding,
+                name,
+                ty,
+            })
+        })
+        .collect();
+    result.sort_by_key(|b| (b.set, b.binding));
+    Ok(result)
+}
+
+struct TypeResolver<'a> {
+    names: &'a HashMap<u32, String>,
+    member_names: &'a HashMap<(u32, u32), String>,
+    member_offsets: &'a HashMap<(u32, u32), u32>,
+    array_strides: &'a HashMap<u32, u32>,
+    types: &'a HashMap<u32, RawType>,
+    constants: &'a HashMap<u32, u32>,
+}
+
+impl TypeResolver<'_> {
+    fn resolve(&self, id: u32) -> SpirvType {
+        match self.types.get(&id) {
+            Some(RawType::Bool) => SpirvType::Bool,
+            Some(RawType::Int(signed)) => SpirvType::Int { signed: *signed },
+            Some(RawType::Float) => SpirvType::Float,
+            Some(RawType::Vector(component, count)) => {
+                SpirvType::Vector(Box::new(self.resolve(*component)), *count)
+            }
+            Some(RawType::Matrix(column, count)) => {
+                SpirvType::Matrix(Box::new(self.resolve(*column)), *count)
+            }
+            Some(RawType::Array(element, length)) => SpirvType::Array(
+                Box::new(self.resolve(*element)),
+                self.constants.get(length).cloned(),
+                self.array_strides.get(&id).cloned(),
+            ),
+            Some(RawType::RuntimeArray(element)) => SpirvType::Array(
+                Box::new(self.resolve(*element)),
+                None,
+                self.array_strides.get(&id).cloned(),
+            ),
+            Some(RawType::Struct(member_types)) => SpirvType::Struct {
+                name: self.names.get(&id).cloned().unwrap_or_default(),
+                members: member_types
+                    .iter()
+                    .enumerate()
+                    .map(|(i, ty)| StructMember {
+                        name: self
+                            .member_names
+                            .get(&(id, i as u32))
+                            .cloned()
+                            .unwrap_or_default(),
+                        offset: self.member_offsets.get(&(id, i as u32)).cloned(),
+                        ty: self.resolve(*ty),
+                    })
+                    .collect(),
+            },
+            Some(RawType::Image(dim)) => SpirvType::Image(*dim),
+            Some(RawType::SampledImage(image)) => match self.types.get(image) {
+                Some(RawType::Image(dim)) => SpirvType::SampledImage(*dim),
+                _ => SpirvType::Unknown,
+            },
+            Some(RawType::Sampler) => SpirvType::Sampler,
+            Some(RawType::Pointer(pointee)) => self.resolve(*pointee),
+            None => SpirvType::Unknown,
+        }
+    }
+}
+
+fn literal_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .take_while(|&byte| byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(instructions: &[&[u32]]) -> Vec<u32> {
+        let mut words = vec![MAGIC, 0x0001_0000, 0, 16, 0];
+        for instruction in instructions {
+            words.push(((instruction.len() as u32) << 16) | instruction[0]);
+            words.extend_from_slice(&instruction[1..]);
+        }
+        words
+    }
+
+    #[test]
+    fn reflects_a_binding() {
+        let words = module(&[
+            &[op::NAME, 4, u32::from_le_bytes(*b"tex\0")],
+            &[op::DECORATE, 4, decoration::DESCRIPTOR_SET, 1],
+            &[op::DECORATE, 4, decoration::BINDING, 2],
+            &[op::TYPE_FLOAT, 1, 32],
+            &[op::TYPE_IMAGE, 2, 1, 1, 0, 0, 0, 1, 0],
+            &[op::TYPE_POINTER, 3, storage_class::UNIFORM_CONSTANT, 2],
+            &[op::VARIABLE, 3, 4, storage_class::UNIFORM_CONSTANT],
+        ]);
+        let bindings = reflect(&words).unwrap();
+        assert_eq!(bindings.len(), 1);
+        assert_eq!((bindings[0].set, bindings[0].binding), (1, 2));
+        assert_eq!(bindings[0].name, "tex");
+        assert_eq!(bindings[0].ty, SpirvType::Image(ImageDim::D2));
+    }
+
+    #[test]
+    fn truncated_instructions_are_errors() {
+        let truncated: [&[u32]; 5] = [
+            &[op::NAME, 4],
+            &[op::TYPE_INT, 1, 32],
+            &[op::TYPE_VECTOR, 2, 1],
+            &[op::TYPE_IMAGE, 2, 1, 1, 0, 0, 0, 1],
+            &[op::VARIABLE, 3, 4],
+        ];
+        for instruction in truncated.iter() {
+            assert!(reflect(&module(&[*instruction])).is_err());
+        }
+    }
+}
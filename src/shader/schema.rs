@@ -0,0 +1,539 @@
+// Serde definitions of the shader JSON format. Values that map onto engine or wgpu enums stay
+// strings here and are converted in `shader.rs`, where the error can still name the JSON path.
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::shader::ShaderParseError;
+
+// The file passed to `Engine::load_shaders`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadersFile {
+    pub shaders: Vec<ShaderEntry>,
+    #[serde(default)]
+    pub materials: Vec<MaterialFileEntry>,
+    // how the frame is drawn, the forward base and add passes when missing
+    pub passes: Option<Vec<FramePassJson>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FramePassJson {
+    // "draw" (default), "skybox" or "deferred_lighting"
+    #[serde(rename = "type")]
+    pub ty: Option<String>,
+    // the sub shaders drawn, required by draw passes
+    pub tag: Option<String>,
+    // "first" (default), "rest" or "each"
+    pub lights: Option<String>,
+    // [first, last] queue drawn, all of them when missing
+    pub queues: Option<(String, String)>,
+}
+
+// Shaders and materials are either inlined or a path to their own json file.
+pub enum Entry<T> {
+    File(String),
+    Inline(T),
+}
+
+pub type ShaderEntry = Entry<ShaderJson>;
+pub type MaterialFileEntry = Entry<MaterialEntry>;
+
+// Values not given come from the parent, or from the shader defaults (and glTF, for materials
+// a glTF file uses) without one.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialEntry {
+    pub name: String,
+    // required unless there is a parent
+    pub shader: Option<String>,
+    // a material defined earlier this one starts from
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    // keywords of the parent to turn off
+    #[serde(default)]
+    pub disabled_keywords: Vec<String>,
+    // uniform path -> value, e.g. "base_color": [1, 0, 0, 1], "lights[0]": { "color": [...] }.
+    // Matrices are arrays of columns, booleans are true / false.
+    #[serde(default)]
+    pub uniforms: HashMap<String, serde_json::Value>,
+    // texture property -> image file
+    #[serde(default)]
+    pub textures: HashMap<String, MaterialTextureJson>,
+}
+
+// Either just the path, or the path with how to load and sample it.
+#[derive(Clone)]
+pub enum MaterialTextureJson {
+    Path(String),
+    Detailed(Box<MaterialTextureDetails>),
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialTextureDetails {
+    pub path: String,
+    // "srgb" (default) or "linear", normal and data maps are linear
+    pub color_space: Option<String>,
+    pub sampler: Option<SamplerJson>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SamplerJson {
+    // sets u, v and w at once
+    pub address_mode: Option<String>,
+    pub address_mode_u: Option<String>,
+    pub address_mode_v: Option<String>,
+    pub address_mode_w: Option<String>,
+    // sets mag, min and mipmap filters at once
+    pub filter: Option<String>,
+    pub mag_filter: Option<String>,
+    pub min_filter: Option<String>,
+    pub mipmap_filter: Option<String>,
+    // 1, 2, 4, 8 or 16
+    pub anisotropy: Option<u8>,
+    pub lod_min_clamp: Option<f32>,
+    pub lod_max_clamp: Option<f32>,
+    // Shader JSON only. wgpu samplers have no LOD bias, the shader gets it as the macro
+    // `<texture>_LOD_BIAS` to pass to `texture()`.
+    pub lod_bias: Option<f32>,
+    // Shader JSON only, also replace the samplers glTF files come with.
+    #[serde(default)]
+    pub override_gltf: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShaderJson {
+    pub name: String,
+    // take the properties from set 0 of the compiled sub shaders instead of the lists below
+    #[serde(default)]
+    pub reflect_properties: bool,
+    // texture name -> default, for reflected 2D textures
+    #[serde(default)]
+    pub texture_defaults: HashMap<String, String>,
+    // struct types the uniform properties can use, e.g. "Light" or "Light[4]"
+    #[serde(default)]
+    pub structs: Vec<StructJson>,
+    // [type, name], arrays are written "vec4[8]"
+    #[serde(default)]
+    pub uniform_properties: Vec<(String, String)>,
+    #[serde(default)]
+    pub texture_properties: Vec<TexturePropertyJson>,
+    // texture property -> sampler of its default texture and of textures set without one
+    #[serde(default)]
+    pub samplers: HashMap<String, SamplerJson>,
+    // [format, attribute]
+    pub vertex_attributes: Option<Vec<(String, String)>>,
+    // groups of mutually exclusive keywords, e.g. [["NORMAL_MAP"], ["ALPHA_TEST", "ALPHA_BLEND"]]
+    #[serde(default)]
+    pub keywords: Vec<Vec<String>>,
+    #[serde(default)]
+    pub subshaders: Vec<SubShaderJson>,
+    // dispatched by `Engine::schedule_compute_pass`, a shader can have only these
+    #[serde(default)]
+    pub compute: Vec<ComputeJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComputeJson {
+    pub tag: String,
+    pub cs: String,
+    // defaults to "main" for GLSL, "cs_main" for WGSL
+    pub cs_entry: Option<String>,
+    // macro name -> value, anything but a string defines the macro without a value
+    #[serde(default)]
+    pub definition: HashMap<String, serde_json::Value>,
+    // everything set 0 binds, compute shaders use no other set
+    #[serde(default)]
+    pub resources: Vec<ComputeResourceJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ComputeResourceJson {
+    pub binding: u32,
+    // "uniform_buffer", "storage_buffer", "texture" or "sampler"
+    #[serde(rename = "type")]
+    pub ty: String,
+    // The engine buffer or texture bound. Buffers are created with
+    // `Engine::create_storage_buffer`, textures are render targets, samplers are the ones of a
+    // texture.
+    pub name: String,
+    // storage buffers: "read" or "read_write" (default)
+    pub access: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StructJson {
+    pub name: String,
+    // [type, name]
+    pub members: Vec<(String, String)>,
+}
+
+// [type, name, default], the default is only used by 2D textures
+#[derive(Deserialize)]
+pub struct TexturePropertyJson(pub String, pub String, #[serde(default)] pub Option<String>);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubShaderJson {
+    pub tag: String,
+    pub vs: String,
+    pub fs: String,
+    // default to "main" for GLSL, "vs_main" / "fs_main" for WGSL, where vs and fs are usually
+    // the same file
+    pub vs_entry: Option<String>,
+    pub fs_entry: Option<String>,
+    // macro name -> value, anything but a string defines the macro without a value
+    #[serde(default)]
+    pub definition: HashMap<String, serde_json::Value>,
+    pub cull: Option<String>,
+    pub front_face: Option<String>,
+    // used by the targets not giving their own
+    pub write_mask: Option<Vec<String>>,
+    pub blend: Option<BlendJson>,
+    // color attachments in fragment output order, only the frame when missing
+    pub targets: Option<Vec<TargetJson>>,
+    // "background", "opaque" (default), "alpha_test", "transparent" or "overlay", optionally
+    // offset ("transparent+10"), or a number
+    pub queue: Option<String>,
+    pub depth_write: Option<bool>,
+    pub depth_compare: Option<String>,
+    pub stencil: Option<StencilJson>,
+    // "fill", "line" or "point"
+    pub polygon_mode: Option<String>,
+    pub depth_bias: Option<DepthBiasJson>,
+    pub depth_clamp: Option<bool>,
+    // 1 or 4, the engine renders with the highest count any sub shader asks for. Deferred
+    // shading needs 1.
+    pub sample_count: Option<u32>,
+    pub alpha_to_coverage: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetJson {
+    // "_Frame" for the swap chain, other names are render targets the engine creates
+    pub name: String,
+    // required by render targets, the frame has the swap chain format
+    pub format: Option<String>,
+    pub write_mask: Option<Vec<String>>,
+    pub blend: Option<BlendJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DepthBiasJson {
+    pub constant: Option<i32>,
+    pub slope_scale: Option<f32>,
+    pub clamp: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlendJson {
+    pub op: Option<String>,
+    pub src: Option<String>,
+    pub dst: Option<String>,
+    pub op_alpha: Option<String>,
+    pub src_alpha: Option<String>,
+    pub dst_alpha: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StencilJson {
+    pub read_mask: Option<u32>,
+    pub write_mask: Option<u32>,
+    pub front: Option<StencilFaceJson>,
+    pub back: Option<StencilFaceJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StencilFaceJson {
+    pub compare: Option<String>,
+    pub pass: Option<String>,
+    pub fail: Option<String>,
+    pub depth_fail: Option<String>,
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Entry<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor<T>(std::marker::PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> serde::de::Visitor<'de> for EntryVisitor<T> {
+            type Value = Entry<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "an object or a path to a json file")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Entry<T>, E> {
+                Ok(Entry::File(value.to_string()))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Entry<T>, A::Error> {
+                // keep deserializing from the same stream so errors keep their position
+                let value = T::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(Entry::Inline(value))
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor(std::marker::PhantomData))
+    }
+}
+
+impl<'de> Deserialize<'de> for MaterialTextureJson {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TextureVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for TextureVisitor {
+            type Value = MaterialTextureJson;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a path to an image or an object with one")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(MaterialTextureJson::Path(value.to_string()))
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                map: A,
+            ) -> Result<Self::Value, A::Error> {
+                // like `Entry`, so unknown fields are reported where they are
+                let details = MaterialTextureDetails::deserialize(
+                    serde::de::value::MapAccessDeserializer::new(map),
+                )?;
+                Ok(MaterialTextureJson::Detailed(Box::new(details)))
+            }
+        }
+
+        deserializer.deserialize_any(TextureVisitor)
+    }
+}
+
+pub fn from_file<T: DeserializeOwned, P: AsRef<std::path::Path>>(
+    path: P,
+) -> Result<T, ShaderParseError> {
+    let file_name = path.as_ref().display().to_string();
+    let text = std::fs::read_to_string(&path).map_err(|err| {
+        ShaderParseError::new(format!("Can't open json file: {}", err)).in_file(&file_name)
+    })?;
+    serde_json::from_str(&text).map_err(|err| {
+        let path = json_path_at(&text, err.line(), err.column());
+        ShaderParseError::new(err.to_string())
+            .at(&path)
+            .in_file(&file_name)
+    })
+}
+
+enum PathFrame {
+    Object { key: Option<String>, in_value: bool },
+    Array { index: usize },
+}
+
+// Replays the json up to the position serde_json reports an error at (1-based line, byte column)
+// and returns the path of the value being read there, e.g. `shaders[0].subshaders[1].blend.op`.
+fn json_path_at(text: &str, line: usize, column: usize) -> String {
+    let mut frames: Vec<PathFrame> = vec![];
+    let mut bytes = text.bytes();
+    let (mut curr_line, mut curr_column) = (1, 0);
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'\n' {
+            curr_line += 1;
+            curr_column = 0;
+        } else {
+            curr_column += 1;
+        }
+        if (curr_line, curr_column) >= (line, column) && byte != b'"' {
+            // errors reported on a closing bracket (missing fields, wrong lengths) are about
+            // the whole object or array
+            if byte == b'}' || byte == b']' {
+                frames.pop();
+            }
+            break;
+        }
+        match byte {
+            b'{' => frames.push(PathFrame::Object {
+                key: None,
+                in_value: false,
+            }),
+            b'[' => frames.push(PathFrame::Array { index: 0 }),
+            b'}' | b']' => {
+                frames.pop();
+            }
+            b':' => {
+                if let Some(PathFrame::Object { in_value, .. }) = frames.last_mut() {
+                    *in_value = true;
+                }
+            }
+            b',' => match frames.last_mut() {
+                Some(PathFrame::Object { key, in_value }) => {
+                    *key = None;
+                    *in_value = false;
+                }
+                Some(PathFrame::Array { index }) => *index += 1,
+                None => {}
+            },
+            b'"' => {
+                // strings are read as a whole, so an error on a key still reports that key
+                let mut string = vec![];
+                while let Some(byte) = bytes.next() {
+                    curr_column += 1;
+                    match byte {
+                        b'"' => break,
+                        b'\\' => {
+                            bytes.next();
+                            curr_column += 1;
+                        }
+                        _ => string.push(byte),
+                    }
+                }
+                if let Some(PathFrame::Object {
+                    key,
+                    in_value: false,
+                }) = frames.last_mut()
+                {
+                    *key = Some(String::from_utf8_lossy(&string).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut path = String::new();
+    for frame in &frames {
+        match frame {
+            PathFrame::Object { key: Some(key), .. } => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+            }
+            PathFrame::Object { key: None, .. } => {}
+            PathFrame::Array { index } => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::Shader;
+    use std::convert::TryFrom;
+
+    // writes the json to a file of its own and parses it
+    fn parse<T: DeserializeOwned>(name: &str, json: &str) -> (String, Result<T, ShaderParseError>) {
+        let path = std::env::temp_dir().join(format!("schema_test_{}.json", name));
+        std::fs::write(&path, json).unwrap();
+        let file_name = path.display().to_string();
+        (file_name, from_file(&path))
+    }
+
+    fn assert_error_at<T: DeserializeOwned>(name: &str, json: &str, path: &str) {
+        let (file_name, result) = parse::<T>(name, json);
+        let err = result.err().expect("the json should not parse");
+        assert_eq!(err.file.as_deref(), Some(file_name.as_str()), "{}", err);
+        assert_eq!(err.path, path, "{}", err);
+    }
+
+    const SHADER: &str = r#"{
+    "name": "test",
+    "uniform_properties": [["vec4", "base_color"], ["float", "roughness"]],
+    "subshaders": [
+        { "tag": "Forward", "vs": "a.vert", "fs": "a.frag" },
+        { "tag": "Shadow", "vs": "b.vert", "fs": "b.frag", "cull": "front" }
+    ]
+}"#;
+
+    #[test]
+    fn valid_shader_parses() {
+        let (_, result) = parse::<ShaderJson>("valid", SHADER);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn unknown_field() {
+        let json = SHADER.replace(r#""cull": "front""#, r#""culling": "front""#);
+        assert_error_at::<ShaderJson>("unknown_field", &json, "subshaders[1].culling");
+    }
+
+    #[test]
+    fn wrong_type() {
+        let json = SHADER.replace(r#""name": "test""#, r#""name": 3"#);
+        assert_error_at::<ShaderJson>("wrong_type", &json, "name");
+    }
+
+    #[test]
+    fn error_in_nested_array() {
+        let json = SHADER.replace(r#"["float", "roughness"]"#, r#"["float", 0.5]"#);
+        assert_error_at::<ShaderJson>("nested_array", &json, "uniform_properties[1][1]");
+    }
+
+    #[test]
+    fn missing_field_of_nested_object() {
+        let json = SHADER.replace(r#""fs": "b.frag", "#, "");
+        assert_error_at::<ShaderJson>("missing_field", &json, "subshaders[1]");
+    }
+
+    #[test]
+    fn error_in_inlined_shader() {
+        let json = format!(
+            r#"{{ "shaders": ["other.json", {}] }}"#,
+            SHADER.replace(r#""vs": "b.vert""#, r#""vs": ["b.vert"]"#)
+        );
+        assert_error_at::<ShadersFile>("inlined", &json, "shaders[1].subshaders[1].vs");
+    }
+
+    #[test]
+    fn unknown_material_texture_field() {
+        let json = r#"{ "name": "m", "textures": { "albedo": { "path": "a.png" } } }"#;
+        assert!(parse::<MaterialEntry>("texture", json).1.is_ok());
+        let json = json.replace(r#""path""#, r#""colour_space": "linear", "path""#);
+        assert_error_at::<MaterialEntry>("texture_field", &json, "textures.albedo.colour_space");
+    }
+
+    #[test]
+    fn bad_enum_string() {
+        // enum strings are checked when converting, like `Engine::load_shader_entry` does
+        let json = SHADER.replace(r#""cull": "front""#, r#""cull": "sideways""#);
+        let (file_name, result) = parse::<ShaderJson>("bad_enum", &json);
+        let err = Shader::try_from(&result.unwrap())
+            .err()
+            .expect("the cull mode should be rejected")
+            .in_file(&file_name);
+        assert_eq!(err.file.as_deref(), Some(file_name.as_str()));
+        assert_eq!(err.path, "subshaders[1].cull");
+    }
+
+    #[test]
+    fn paths_are_prepended() {
+        let err = ShaderParseError::new("error".to_string())
+            .at("[1]")
+            .at("targets")
+            .at("subshaders[0]")
+            .in_file("a.json")
+            .in_file("b.json");
+        assert_eq!(err.path, "subshaders[0].targets[1]");
+        assert_eq!(err.file.as_deref(), Some("a.json"));
+    }
+
+    #[test]
+    fn path_of_position() {
+        let json = "{\n  \"a\": [1, {\"b\": [true, 2]}],\n  \"c\": {}\n}";
+        // the `2`, then the `}` of "c"
+        assert_eq!(json_path_at(json, 2, 25), "a[1].b[1]");
+        assert_eq!(json_path_at(json, 3, 9), "c");
+    }
+}
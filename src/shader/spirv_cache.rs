@@ -0,0 +1,151 @@
+// On-disk cache of compiled SPIR-V, so unchanged shaders skip shaderc on the next launch.
+//
+// Every (file, macro definitions) combination has one entry: `<key>.spv` and `<key>.deps`, the
+// latter holding a hash of the source and of every header it included when it was compiled,
+// followed by the paths of those files. An entry is only used while that hash still matches the
+// files on disk.
+use anyhow::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const CACHE_DIR: &str = ".shader_cache";
+
+pub struct CachedModule {
+    pub spirv: Vec<u8>,
+    pub included_files: Vec<PathBuf>,
+}
+
+pub fn load(path: &Path, definition: &HashMap<String, Option<String>>) -> Option<CachedModule> {
+    let entry = entry_path(path, definition);
+    let (hash, files) = read_deps(&entry.with_extension("deps"))?;
+    if files.first().map(PathBuf::as_path) != Some(path) || content_hash(&files)? != hash {
+        return None;
+    }
+    let spirv = std::fs::read(entry.with_extension("spv")).ok()?;
+    Some(CachedModule {
+        spirv,
+        included_files: files[1..].to_vec(),
+    })
+}
+
+// Replaces the entry of this combination, older versions are overwritten.
+pub fn store(
+    path: &Path,
+    definition: &HashMap<String, Option<String>>,
+    spirv: &[u8],
+    included_files: &[PathBuf],
+) -> Result<()> {
+    let entry = entry_path(path, definition);
+    let mut files = vec![path.to_path_buf()];
+    files.extend_from_slice(included_files);
+    let hash = content_hash(&files).context("Shader source changed while compiling")?;
+
+    let mut deps = format!("{:032x}\n", hash);
+    for file in &files {
+        deps.push_str(file.to_str().context("Non UTF-8 shader path")?);
+        deps.push('\n');
+    }
+    std::fs::create_dir_all(CACHE_DIR)?;
+    std::fs::write(entry.with_extension("spv"), spirv)?;
+    // written last, an entry without it is never used
+    std::fs::write(entry.with_extension("deps"), deps)?;
+    Ok(())
+}
+
+// Removes entries whose sources changed or no longer exist, returns how many were removed.
+pub fn evict_stale() -> usize {
+    let dir = match std::fs::read_dir(CACHE_DIR) {
+        Ok(dir) => dir,
+        Err(_) => return 0,
+    };
+    let mut evicted = 0;
+    for file in dir.filter_map(|file| file.ok()).map(|file| file.path()) {
+        if file.extension() != Some("spv".as_ref()) {
+            continue;
+        }
+        let deps = file.with_extension("deps");
+        let fresh = read_deps(&deps)
+            .and_then(|(hash, files)| Some(content_hash(&files)? == hash))
+            .unwrap_or(false);
+        if !fresh {
+            let _ = std::fs::remove_file(&deps);
+            if std::fs::remove_file(&file).is_ok() {
+                evicted += 1;
+            }
+        }
+    }
+    evicted
+}
+
+pub fn clear() -> Result<()> {
+    if Path::new(CACHE_DIR).exists() {
+        std::fs::remove_dir_all(CACHE_DIR)
+            .with_context(|| format!("Can't remove shader cache '{}'", CACHE_DIR))?;
+    }
+    Ok(())
+}
+
+fn entry_path(path: &Path, definition: &HashMap<String, Option<String>>) -> PathBuf {
+    let mut macros: Vec<_> = definition.iter().collect();
+    macros.sort();
+    let mut hasher = Fnv128::new();
+    hasher.write(path.to_string_lossy().as_bytes());
+    for (key, value) in macros {
+        hasher.write(key.as_bytes());
+        // keeps `A` and `A=""` apart
+        match value {
+            Some(value) => {
+                hasher.write(b"=");
+                hasher.write(value.as_bytes());
+            }
+            None => hasher.write(b";"),
+        }
+    }
+    Path::new(CACHE_DIR).join(format!("{:032x}", hasher.finish()))
+}
+
+fn read_deps(path: &Path) -> Option<(u128, Vec<PathBuf>)> {
+    let deps = std::fs::read_to_string(path).ok()?;
+    let mut lines = deps.lines();
+    let hash = u128::from_str_radix(lines.next()?, 16).ok()?;
+    let files: Vec<PathBuf> = lines.map(PathBuf::from).collect();
+    if files.is_empty() {
+        return None;
+    }
+    Some((hash, files))
+}
+
+// None if any of the files can't be read
+fn content_hash(files: &[PathBuf]) -> Option<u128> {
+    let mut hasher = Fnv128::new();
+    for file in files {
+        let content = std::fs::read(file).ok()?;
+        hasher.write(file.to_string_lossy().as_bytes());
+        hasher.write(&(content.len() as u64).to_le_bytes());
+        hasher.write(&content);
+    }
+    Some(hasher.finish())
+}
+
+// FNV-1a, the std hasher isn't guaranteed to give the same result across Rust versions
+struct Fnv128(u128);
+
+impl Fnv128 {
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u128;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn finish(&self) -> u128 {
+        self.0
+    }
+}
@@ -0,0 +1,179 @@
+// WGSL sub shaders. WGSL has no preprocessor, so the same macro definitions GLSL files get are
+// applied by a small one here (`#define`, `#undef`, `#ifdef`, `#ifndef`, `#else`, `#endif`,
+// `#include`), then naga turns the result into SPIR-V, which goes through the same reflection and
+// validation as compiled GLSL.
+use anyhow::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::shader::shader_util;
+
+// Returns the SPIR-V (holding every entry point of the file) and the files it included.
+pub fn compile_to_spirv(
+    path: &Path,
+    definition: &HashMap<String, Option<String>>,
+) -> Result<(Vec<u8>, Vec<PathBuf>)> {
+    let mut preprocessor = Preprocessor {
+        macros: definition.clone(),
+        included_files: vec![],
+    };
+    let source = preprocessor.process_file(path, 0)?;
+
+    let module = naga::front::wgsl::parse_str(&source)
+        .map_err(|err| anyhow!("{}", err))
+        .with_context(|| format!("Can't parse '{}'", path.display()))?;
+    naga::proc::Validator::new()
+        .validate(&module)
+        .with_context(|| format!("Invalid shader '{}'", path.display()))?;
+
+    use naga::back::spv::Capability;
+    let capabilities = [
+        Capability::Shader,
+        Capability::Matrix,
+        Capability::Sampled1D,
+        Capability::Image1D,
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    // debug info carries the names reflection reports
+    let words =
+        naga::back::spv::write_vec(&module, naga::back::spv::WriterFlags::DEBUG, capabilities)?;
+    let spirv = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .collect();
+    Ok((spirv, preprocessor.included_files))
+}
+
+struct Preprocessor {
+    // name -> value, None for macros defined without one
+    macros: HashMap<String, Option<String>>,
+    included_files: Vec<PathBuf>,
+}
+
+struct Condition {
+    active: bool,
+    parent_active: bool,
+    in_else: bool,
+}
+
+impl Preprocessor {
+    const MAX_INCLUDE_DEPTH: usize = 32;
+
+    // Lines removed by directives are kept empty, so naga's line numbers still point into the
+    // file (as long as nothing was included above).
+    fn process_file(&mut self, path: &Path, depth: usize) -> Result<String> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Can't read '{}'", path.display()))?;
+        let mut output = String::with_capacity(source.len());
+        let mut conditions: Vec<Condition> = vec![];
+
+        for (i, line) in source.lines().enumerate() {
+            let active = conditions.last().is_none_or(|c| c.active);
+            let error_at = |msg: String| anyhow!("{}:{}: {}", path.display(), i + 1, msg);
+            let trimmed = line.trim_start();
+            if !trimmed.starts_with('#') {
+                if active {
+                    output.push_str(&self.substitute(line));
+                }
+                output.push('\n');
+                continue;
+            }
+
+            let mut parts = trimmed[1..].trim().splitn(2, char::is_whitespace);
+            let directive = parts.next().unwrap_or("");
+            let argument = parts.next().unwrap_or("").trim();
+            match directive {
+                "ifdef" | "ifndef" => {
+                    let defined = self.macros.contains_key(argument);
+                    conditions.push(Condition {
+                        active: active && (defined == (directive == "ifdef")),
+                        parent_active: active,
+                        in_else: false,
+                    });
+                }
+                "else" => match conditions.last_mut() {
+                    Some(condition) if !condition.in_else => {
+                        condition.active = condition.parent_active && !condition.active;
+                        condition.in_else = true;
+                    }
+                    _ => return Err(error_at("#else without #ifdef".to_string())),
+                },
+                "endif" => {
+                    if conditions.pop().is_none() {
+                        return Err(error_at("#endif without #ifdef".to_string()));
+                    }
+                }
+                _ if !active => {}
+                "define" => {
+                    let mut parts = argument.splitn(2, char::is_whitespace);
+                    let name = parts.next().unwrap_or("");
+                    if name.is_empty() {
+                        return Err(error_at("#define without a name".to_string()));
+                    }
+                    let value = parts.next().map(|value| value.trim().to_string());
+                    self.macros.insert(name.to_string(), value);
+                }
+                "undef" => {
+                    self.macros.remove(argument);
+                }
+                "include" => {
+                    if depth >= Self::MAX_INCLUDE_DEPTH {
+                        return Err(error_at("#include nested too deeply".to_string()));
+                    }
+                    let included = self.resolve_include(argument, path).map_err(error_at)?;
+                    self.included_files.push(included.clone());
+                    output.push_str(&self.process_file(&included, depth + 1)?);
+                }
+                _ => return Err(error_at(format!("Unknown directive '#{}'", directive))),
+            }
+            output.push('\n');
+        }
+
+        if !conditions.is_empty() {
+            bail!("{}: missing #endif", path.display());
+        }
+        Ok(output)
+    }
+
+    // `"file"` is looked up next to the including file first, like in GLSL
+    fn resolve_include(&self, argument: &str, requesting: &Path) -> Result<PathBuf, String> {
+        let requesting = requesting.to_string_lossy();
+        if let Some(name) = argument.strip_prefix('"').and_then(|a| a.strip_suffix('"')) {
+            shader_util::resolve_include(name, shaderc::IncludeType::Relative, &requesting).or_else(
+                |_| shader_util::resolve_include(name, shaderc::IncludeType::Standard, &requesting),
+            )
+        } else if let Some(name) = argument.strip_prefix('<').and_then(|a| a.strip_suffix('>')) {
+            shader_util::resolve_include(name, shaderc::IncludeType::Standard, &requesting)
+        } else {
+            Err(format!("Malformed #include {}", argument))
+        }
+    }
+
+    // replaces identifiers naming macros with a value
+    fn substitute(&self, line: &str) -> String {
+        let mut output = String::with_capacity(line.len());
+        let mut chars = line.char_indices().peekable();
+        while let Some((start, ch)) = chars.next() {
+            if !(ch.is_ascii_alphabetic() || ch == '_') {
+                output.push(ch);
+                continue;
+            }
+            let mut end = start + ch.len_utf8();
+            while let Some(&(i, ch)) = chars.peek() {
+                if !(ch.is_ascii_alphanumeric() || ch == '_') {
+                    break;
+                }
+                end = i + ch.len_utf8();
+                chars.next();
+            }
+            let identifier = &line[start..end];
+            match self.macros.get(identifier) {
+                Some(Some(value)) => output.push_str(value),
+                _ => output.push_str(identifier),
+            }
+        }
+        output
+    }
+}
@@ -13,6 +13,7 @@ impl Texture {
     pub fn depth_stencil_texture(
         device: &wgpu::Device,
         swap_chain_desc: &wgpu::SwapChainDescriptor,
+        sample_count: u32,
         label: Option<&str>,
     ) -> Self {
         let size = wgpu::Extent3d {
@@ -26,7 +27,7 @@ impl Texture {
             label,
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension,
             format,
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
@@ -55,6 +56,55 @@ impl Texture {
         }
     }
 
+    // A color texture to render into, multisampled ones are resolved instead of sampled.
+    pub fn render_target_2d(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        label: Option<&str>,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth: 1,
+        };
+        let dimension = wgpu::TextureDimension::D2;
+        let usage = if sample_count > 1 {
+            wgpu::TextureUsage::RENDER_ATTACHMENT
+        } else {
+            wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size,
+            mip_level_count: 1,
+            sample_count,
+            dimension,
+            format,
+            usage,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+            size,
+            dimension,
+            format,
+        }
+    }
+
     pub fn from_bytes_2d(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -8,47 +8,6 @@ pub struct MeshVertex {
     pub color: [f32; 4],
 }
 
-impl MeshVertex {
-    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
-        wgpu::VertexBufferLayout {
-            array_stride: std::mem::size_of::<MeshVertex>() as wgpu::BufferAddress,
-            step_mode: wgpu::InputStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    // position
-                    format: wgpu::VertexFormat::Float3,
-                    offset: 0,
-                    shader_location: 0,
-                },
-                wgpu::VertexAttribute {
-                    // texcoord
-                    format: wgpu::VertexFormat::Float2,
-                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
-                },
-                wgpu::VertexAttribute {
-                    // normal
-                    format: wgpu::VertexFormat::Float3,
-                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
-                    shader_location: 2,
-                },
-                wgpu::VertexAttribute {
-                    // tangent
-                    format: wgpu::VertexFormat::Float4,
-                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 3,
-                },
-                wgpu::VertexAttribute {
-                    // color
-                    format: wgpu::VertexFormat::Float4,
-                    offset: std::mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
-                    shader_location: 4,
-                },
-            ],
-        }
-    }
-}
-
 impl Default for MeshVertex {
     fn default() -> Self {
         Self {
@@ -60,3 +19,367 @@ impl Default for MeshVertex {
         }
     }
 }
+
+// Which vertex attributes a shader consumes and how they are stored. Every attribute has a fixed
+// shader location, absent ones are simply left out of the buffer.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct VertexLayout {
+    attributes: Vec<(VertexAttribute, AttributeFormat)>,
+    wgpu_attributes: Vec<wgpu::VertexAttribute>,
+    stride: usize,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum VertexAttribute {
+    Position,
+    Texcoords,
+    Normal,
+    Tangent,
+    Color,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum AttributeFormat {
+    Float,
+    // 16-bit floats, for texcoords, tangents and colors
+    Half,
+    // octahedral encoded 2x snorm16, for normals
+    Octahedral,
+    // 4x unorm8, for colors
+    Unorm8,
+}
+
+impl VertexAttribute {
+    pub fn shader_location(&self) -> u32 {
+        match self {
+            VertexAttribute::Position => 0,
+            VertexAttribute::Texcoords => 1,
+            VertexAttribute::Normal => 2,
+            VertexAttribute::Tangent => 3,
+            VertexAttribute::Color => 4,
+        }
+    }
+
+    fn components(&self) -> usize {
+        match self {
+            VertexAttribute::Position => 3,
+            VertexAttribute::Texcoords => 2,
+            VertexAttribute::Normal => 3,
+            VertexAttribute::Tangent => 4,
+            VertexAttribute::Color => 4,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            VertexAttribute::Position => "POSITION",
+            VertexAttribute::Texcoords => "TEXCOORDS",
+            VertexAttribute::Normal => "NORMAL",
+            VertexAttribute::Tangent => "TANGENT",
+            VertexAttribute::Color => "COLOR",
+        }
+    }
+
+    fn read(&self, vertex: &MeshVertex) -> [f32; 4] {
+        match self {
+            VertexAttribute::Position => [
+                vertex.position[0],
+                vertex.position[1],
+                vertex.position[2],
+                0.0,
+            ],
+            VertexAttribute::Texcoords => [vertex.texcoords[0], vertex.texcoords[1], 0.0, 0.0],
+            VertexAttribute::Normal => [vertex.normal[0], vertex.normal[1], vertex.normal[2], 0.0],
+            VertexAttribute::Tangent => vertex.tangent,
+            VertexAttribute::Color => vertex.color,
+        }
+    }
+}
+
+impl AttributeFormat {
+    fn wgpu_format(&self, components: usize) -> Option<wgpu::VertexFormat> {
+        match (self, components) {
+            (AttributeFormat::Float, 2) => Some(wgpu::VertexFormat::Float2),
+            (AttributeFormat::Float, 3) => Some(wgpu::VertexFormat::Float3),
+            (AttributeFormat::Float, 4) => Some(wgpu::VertexFormat::Float4),
+            (AttributeFormat::Half, 2) => Some(wgpu::VertexFormat::Half2),
+            (AttributeFormat::Half, 4) => Some(wgpu::VertexFormat::Half4),
+            (AttributeFormat::Octahedral, 3) => Some(wgpu::VertexFormat::Short2Norm),
+            (AttributeFormat::Unorm8, 4) => Some(wgpu::VertexFormat::Uchar4Norm),
+            _ => None,
+        }
+    }
+}
+
+impl VertexLayout {
+    pub fn new(attributes: Vec<(VertexAttribute, AttributeFormat)>) -> Result<Self, String> {
+        let mut wgpu_attributes = Vec::with_capacity(attributes.len());
+        let mut offset = 0;
+        for (attribute, format) in &attributes {
+            if wgpu_attributes
+                .iter()
+                .any(|a: &wgpu::VertexAttribute| a.shader_location == attribute.shader_location())
+            {
+                return Err(format!(
+                    "Vertex attribute {:?} is declared twice",
+                    attribute
+                ));
+            }
+            let wgpu_format = format.wgpu_format(attribute.components()).ok_or_else(|| {
+                format!(
+                    "Vertex attribute {:?} can't be stored as {:?}",
+                    attribute, format
+                )
+            })?;
+            wgpu_attributes.push(wgpu::VertexAttribute {
+                format: wgpu_format,
+                offset: offset as wgpu::BufferAddress,
+                shader_location: attribute.shader_location(),
+            });
+            offset += wgpu_format.size() as usize;
+        }
+        Ok(Self {
+            attributes,
+            wgpu_attributes,
+            stride: offset,
+        })
+    }
+
+    pub fn desc(&self) -> wgpu::VertexBufferLayout<'_> {
+        wgpu::VertexBufferLayout {
+            array_stride: self.stride as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &self.wgpu_attributes,
+        }
+    }
+
+    // VERTEX_<ATTRIBUTE> for every attribute present, plus VERTEX_NORMAL_OCT for packed normals
+    pub fn shader_definitions(&self) -> Vec<(String, Option<String>)> {
+        let mut definitions = vec![];
+        for (attribute, format) in &self.attributes {
+            definitions.push((format!("VERTEX_{}", attribute.name()), None));
+            if *format == AttributeFormat::Octahedral {
+                definitions.push((format!("VERTEX_{}_OCT", attribute.name()), None));
+            }
+        }
+        definitions
+    }
+
+    pub fn pack(&self, vertices: &[MeshVertex]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(vertices.len() * self.stride);
+        for vertex in vertices {
+            for (attribute, format) in &self.attributes {
+                let value = attribute.read(vertex);
+                let value = &value[..attribute.components()];
+                match format {
+                    AttributeFormat::Float => {
+                        for v in value {
+                            bytes.extend_from_slice(&v.to_le_bytes());
+                        }
+                    }
+                    AttributeFormat::Half => {
+                        for &v in value {
+                            bytes.extend_from_slice(&quantize::f32_to_f16(v).to_le_bytes());
+                        }
+                    }
+                    AttributeFormat::Octahedral => {
+                        for v in &quantize::octahedral_encode([value[0], value[1], value[2]]) {
+                            bytes.extend_from_slice(&v.to_le_bytes());
+                        }
+                    }
+                    AttributeFormat::Unorm8 => {
+                        for &v in value {
+                            bytes.push((v.clamp(0.0, 1.0) * 255.0).round() as u8);
+                        }
+                    }
+                }
+            }
+        }
+        bytes
+    }
+}
+
+impl Default for VertexLayout {
+    // the same layout as MeshVertex, for shaders that don't declare their attributes
+    fn default() -> Self {
+        Self::new(vec![
+            (VertexAttribute::Position, AttributeFormat::Float),
+            (VertexAttribute::Texcoords, AttributeFormat::Float),
+            (VertexAttribute::Normal, AttributeFormat::Float),
+            (VertexAttribute::Tangent, AttributeFormat::Float),
+            (VertexAttribute::Color, AttributeFormat::Float),
+        ])
+        .unwrap()
+    }
+}
+
+pub mod quantize {
+    pub fn f32_to_f16(value: f32) -> u16 {
+        let bits = value.to_bits();
+        let sign = ((bits >> 16) & 0x8000) as u16;
+        let exponent = ((bits >> 23) & 0xff) as i32;
+        let mantissa = bits & 0x007f_ffff;
+
+        if exponent == 0xff {
+            // inf or nan
+            let nan_bit = if mantissa != 0 { 0x0200 } else { 0 };
+            return sign | 0x7c00 | nan_bit;
+        }
+        let half_exponent = exponent - 127 + 15;
+        if half_exponent >= 0x1f {
+            return sign | 0x7c00;
+        }
+        if half_exponent <= 0 {
+            if half_exponent < -10 {
+                return sign;
+            }
+            // subnormal, round to nearest
+            let mantissa = mantissa | 0x0080_0000;
+            let shift = (14 - half_exponent) as u32;
+            let half_mantissa = mantissa >> shift;
+            let round = (mantissa >> (shift - 1)) & 1;
+            return sign | (half_mantissa + round) as u16;
+        }
+        let half = sign as u32 | ((half_exponent as u32) << 10) | (mantissa >> 13);
+        // round to nearest, a carry into the exponent is still the right value
+        let round = (mantissa >> 12) & 1;
+        (half + round) as u16
+    }
+
+    pub fn octahedral_encode(normal: [f32; 3]) -> [i16; 2] {
+        let l1 = normal[0].abs() + normal[1].abs() + normal[2].abs();
+        if l1 == 0.0 {
+            return [0, 0];
+        }
+        let (mut x, mut y) = (normal[0] / l1, normal[1] / l1);
+        if normal[2] < 0.0 {
+            let sign_x = if x >= 0.0 { 1.0 } else { -1.0 };
+            let sign_y = if y >= 0.0 { 1.0 } else { -1.0 };
+            let (old_x, old_y) = (x, y);
+            x = (1.0 - old_y.abs()) * sign_x;
+            y = (1.0 - old_x.abs()) * sign_y;
+        }
+        [snorm16(x), snorm16(y)]
+    }
+
+    fn snorm16(value: f32) -> i16 {
+        (value.clamp(-1.0, 1.0) * 32767.0).round() as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::quantize::*;
+
+    // the usual decoding of the two snorm16 components, shaders reading `oct` normals do the same
+    fn octahedral_decode(encoded: [i16; 2]) -> [f32; 3] {
+        let (x, y) = (encoded[0] as f32 / 32767.0, encoded[1] as f32 / 32767.0);
+        let z = 1.0 - x.abs() - y.abs();
+        let (x, y) = if z < 0.0 {
+            ((1.0 - y.abs()) * x.signum(), (1.0 - x.abs()) * y.signum())
+        } else {
+            (x, y)
+        };
+        let length = (x * x + y * y + z * z).sqrt();
+        [x / length, y / length, z / length]
+    }
+
+    fn assert_round_trip(normal: [f32; 3]) {
+        let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+        let normal = [normal[0] / length, normal[1] / length, normal[2] / length];
+        let decoded = octahedral_decode(octahedral_encode(normal));
+        for (d, n) in decoded.iter().zip(&normal) {
+            assert!(
+                (d - n).abs() < 1e-3,
+                "{:?} decoded to {:?}",
+                normal,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn f16_exact_values() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(1.5), 0x3e00);
+        assert_eq!(f32_to_f16(100.0), 0x5640);
+        // largest half and smallest normal half
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(2f32.powi(-14)), 0x0400);
+    }
+
+    #[test]
+    fn f16_rounds_to_nearest() {
+        // 1 + 2^-10 is the next half after 1
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-12)), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 3.0 * 2f32.powi(-12)), 0x3c01);
+        // all mantissa bits set and rounding up carries into the exponent
+        assert_eq!(f32_to_f16(2.0 - 2f32.powi(-12)), 0x4000);
+        assert_eq!(f32_to_f16(-(2.0 - 2f32.powi(-12))), 0xc000);
+    }
+
+    #[test]
+    fn f16_subnormals() {
+        // half exponent 0, the largest subnormals
+        assert_eq!(f32_to_f16(2f32.powi(-15)), 0x0200);
+        assert_eq!(f32_to_f16(3.0 * 2f32.powi(-16)), 0x0300);
+        // rounds up into the smallest normal
+        assert_eq!(f32_to_f16(2f32.powi(-14) - 2f32.powi(-26)), 0x0400);
+        // the smallest subnormal is 2^-24
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(-2f32.powi(-24)), 0x8001);
+        // half exponent -10, rounds up to the smallest subnormal
+        assert_eq!(f32_to_f16(1.5 * 2f32.powi(-25)), 0x0001);
+        // below that flushes to zero
+        assert_eq!(f32_to_f16(1.5 * 2f32.powi(-26)), 0x0000);
+        assert_eq!(f32_to_f16(-1.5 * 2f32.powi(-26)), 0x8000);
+    }
+
+    #[test]
+    fn f16_overflow_and_nan() {
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f32_to_f16(65536.0), 0x7c00);
+        assert_eq!(f32_to_f16(-1e10), 0xfc00);
+        // rounds past the largest half
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+
+        let nan = f32_to_f16(f32::NAN);
+        assert_eq!(nan & 0x7c00, 0x7c00);
+        assert_ne!(nan & 0x03ff, 0);
+    }
+
+    #[test]
+    fn octahedral_axes() {
+        for &axis in &[
+            [1.0, 0.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0],
+        ] {
+            assert_round_trip(axis);
+        }
+    }
+
+    #[test]
+    fn octahedral_round_trip() {
+        // the negative z ones get folded over the diagonals
+        for &normal in &[
+            [0.3, 0.4, -0.8],
+            [-0.6, 0.2, -0.5],
+            [0.1, -0.9, -0.2],
+            [-0.5, -0.5, -0.7],
+            [0.7, 0.0, -0.7],
+            [1.0, 1.0, 1.0],
+            [-0.2, 0.6, 0.3],
+        ] {
+            assert_round_trip(normal);
+        }
+    }
+}